@@ -1,18 +1,77 @@
-use client::BufferedClient;
-use client::Client;
 use redflareproxy::ClientTokenValue;
 use backend::write_to_stream;
-use redflareproxy::{ADMIN_LISTENER};
+use redflareproxy::{ADMIN_LISTENER, FIRST_ADMIN_CLIENT_INDEX, MAX_ADMIN_CLIENTS};
 use redflareproxy::{ClientToken};
 use config::{AdminConfig};
-use bufreader::BufReader;
 
 use mio::*;
-use mio::tcp::{TcpListener};
+use mio::tcp::{TcpListener, TcpStream};
 use hashbrown::HashMap;
+use std::io::{Read, Write};
+
+// Admin requests are small. A client sending more than this without completing a request is disconnected.
+const MAX_REQUEST_SIZE: usize = 65536;
+
+/*
+    A reply to an admin command, written out in RESP so that redis-cli and client libraries can read it.
+    Errors are prefixed with ERR, as redis does.
+*/
+#[derive(Debug, PartialEq)]
+pub enum AdminReply {
+    Status(String),
+    Bulk(String),
+    Integer(i64),
+    Array(Vec<AdminReply>),
+    Error(String),
+    Nil,
+}
+impl AdminReply {
+    pub fn encode(&self, response: &mut Vec<u8>) {
+        match *self {
+            AdminReply::Status(ref status) => {
+                response.push(b'+');
+                response.extend_from_slice(single_line(status).as_bytes());
+                response.extend_from_slice(b"\r\n");
+            }
+            AdminReply::Bulk(ref text) => {
+                response.extend_from_slice(format!("${}\r\n", text.len()).as_bytes());
+                response.extend_from_slice(text.as_bytes());
+                response.extend_from_slice(b"\r\n");
+            }
+            AdminReply::Integer(value) => {
+                response.extend_from_slice(format!(":{}\r\n", value).as_bytes());
+            }
+            AdminReply::Array(ref replies) => {
+                response.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies.iter() {
+                    reply.encode(response);
+                }
+            }
+            AdminReply::Error(ref message) => {
+                response.extend_from_slice(b"-ERR ");
+                response.extend_from_slice(single_line(message).as_bytes());
+                response.extend_from_slice(b"\r\n");
+            }
+            AdminReply::Nil => {
+                response.extend_from_slice(b"$-1\r\n");
+            }
+        }
+    }
+}
+
+// Status and error replies can't span lines.
+fn single_line(text: &str) -> String {
+    return text.trim().replace("\r\n", " ").replace('\n', " ").replace('\r', " ");
+}
+
+pub struct AdminClient {
+    stream: TcpStream,
+    // What has been read of the client's requests, which may end with a partial request.
+    request: Vec<u8>,
+}
 
 pub struct AdminPort {
-    pub client_sockets: HashMap<ClientTokenValue, BufferedClient>,
+    pub client_sockets: HashMap<ClientTokenValue, AdminClient>,
     pub socket: TcpListener,
     pub config: AdminConfig,
 }
@@ -51,18 +110,27 @@ impl AdminPort {
         }
     }
 
-    pub fn accept_client_connection(&mut self, next_admin_token: usize, poll: &mut Poll) {
+    // Accepts connections while there are free tokens, between FIRST_ADMIN_CLIENT_INDEX and MAX_ADMIN_CLIENTS after it.
+    pub fn accept_client_connection(&mut self, poll: &mut Poll) {
         loop {
             match self.socket.accept() {
                 Ok((s, _)) => {
-                    let token = Token(next_admin_token);
-                    match poll.register(&s, token, Ready::readable(), PollOpt::edge()) {
+                    let token = match (FIRST_ADMIN_CLIENT_INDEX..FIRST_ADMIN_CLIENT_INDEX + MAX_ADMIN_CLIENTS).find(|t| !self.client_sockets.contains_key(t)) {
+                        Some(token) => Token(token),
+                        None => {
+                            warn!("Too many admin connections. Dropping the new one.");
+                            continue;
+                        }
+                    };
+                    // Writable events let MONITOR output that didn't fit in the socket's buffer go out once it does.
+                    match poll.register(&s, token, Ready::readable() | Ready::writable(), PollOpt::edge()) {
                         Ok(_) => {}
                         Err(error) => {
                             error!("Failed to register admin client socket to poll. Reason: {:?}", error);
+                            continue;
                         }
                     };
-                    self.client_sockets.insert(token.0, BufReader::new(Client::new(s)));
+                    self.client_sockets.insert(token.0, AdminClient { stream: s, request: Vec::new() });
                 }
                 Err(error) => {
                     if error.kind() == std::io::ErrorKind::WouldBlock {
@@ -75,10 +143,109 @@ impl AdminPort {
         }
     }
 
-    pub fn write_to_client(&mut self, client_token: ClientToken, message: String) {
+    /*
+        Reads what is available from an admin client, without waiting for more. Returns the arguments of each request
+        that has been read in full, the first argument being the command. Closed connections and protocol errors
+        disconnect the client, after the requests before them are returned.
+    */
+    pub fn read_requests(&mut self, client_token: ClientToken) -> Vec<Vec<String>> {
+        let mut requests = Vec::new();
+        let mut disconnect = false;
         match self.client_sockets.get_mut(&client_token.0) {
             Some(client) => {
-                match write_to_stream(&mut client.get_mut().stream, &message.into_bytes()[..]) {
+                let mut buf = [0; 1024];
+                loop {
+                    match client.stream.read(&mut buf) {
+                        Ok(0) => {
+                            disconnect = true;
+                            break;
+                        }
+                        Ok(len) => client.request.extend_from_slice(&buf[..len]),
+                        Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(error) => {
+                            debug!("Unable to read from admin client. Received error: {}", error);
+                            disconnect = true;
+                            break;
+                        }
+                    }
+                }
+                let mut index = 0;
+                loop {
+                    match parse_request(&client.request[index..]) {
+                        Ok(Some((args, len))) => {
+                            index += len;
+                            if args.len() > 0 {
+                                requests.push(args);
+                            }
+                        }
+                        Ok(None) => {
+                            if client.request.len() - index > MAX_REQUEST_SIZE {
+                                debug!("Admin request is too large. Disconnecting the client.");
+                                disconnect = true;
+                            }
+                            break;
+                        }
+                        Err(message) => {
+                            let mut response = Vec::new();
+                            AdminReply::Error(format!("Protocol error: {}", message)).encode(&mut response);
+                            let _ = write_to_stream(&mut client.stream, &response);
+                            disconnect = true;
+                            break;
+                        }
+                    }
+                }
+                client.request.drain(..index);
+            }
+            None => {
+                error!("AdminClient {:?} triggered an event, but it is no longer stored.", client_token);
+            }
+        }
+        if disconnect {
+            self.client_sockets.remove(&client_token.0);
+        }
+        return requests;
+    }
+
+    /*
+        Writes as much of the output as the client takes without waiting, for MONITOR, which must not hold up the
+        proxy's clients. Returns how much was written, or None once the client is gone.
+    */
+    pub fn write_available(&mut self, client_token: ClientToken, output: &[u8]) -> Option<usize> {
+        let mut written = 0;
+        let failed = match self.client_sockets.get_mut(&client_token.0) {
+            Some(client) => {
+                loop {
+                    if written == output.len() {
+                        break false;
+                    }
+                    match client.stream.write(&output[written..]) {
+                        Ok(0) => break true,
+                        Ok(len) => written += len,
+                        Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => break false,
+                        Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(error) => {
+                            debug!("Unable to write to admin client. Received error: {}", error);
+                            break true;
+                        }
+                    }
+                }
+            }
+            None => return None,
+        };
+        if failed {
+            self.client_sockets.remove(&client_token.0);
+            return None;
+        }
+        return Some(written);
+    }
+
+    pub fn write_to_client(&mut self, client_token: ClientToken, reply: AdminReply) {
+        match self.client_sockets.get_mut(&client_token.0) {
+            Some(client) => {
+                let mut response = Vec::new();
+                reply.encode(&mut response);
+                match write_to_stream(&mut client.stream, &response) {
                     Ok(_) => { return; }
                     Err(err) => {
                         debug!("Unable to write to admin client. Received error: {}", err);
@@ -93,3 +260,85 @@ impl AdminPort {
         self.client_sockets.remove(&client_token.0);
     }
 }
+
+/*
+    Parses the request at the start of the bytes, returning its arguments and length once it has arrived in full.
+    Requests are arrays of bulk strings, as sent by redis-cli and client libraries, or inline commands split on
+    whitespace, as typed into telnet.
+*/
+fn parse_request(bytes: &[u8]) -> Result<Option<(Vec<String>, usize)>, String> {
+    let mut index = 0;
+    let header = match read_line(bytes, &mut index) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if header.get(0) != Some(&b'*') {
+        let args = String::from_utf8_lossy(header).split_whitespace().map(|arg| arg.to_owned()).collect();
+        return Ok(Some((args, index)));
+    }
+    let num_args = try!(parse_length(&header[1..]));
+    let mut args = Vec::new();
+    for _ in 0..num_args {
+        let line = match read_line(bytes, &mut index) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.get(0) != Some(&b'$') {
+            return Err(format!("expected '$', got '{}'", String::from_utf8_lossy(&line[..std::cmp::min(line.len(), 1)])));
+        }
+        let len = try!(parse_length(&line[1..]));
+        match bytes.get(index..index + len + 2) {
+            Some(arg) => args.push(String::from_utf8_lossy(&arg[..len]).into_owned()),
+            None => return Ok(None),
+        }
+        index += len + 2;
+    }
+    return Ok(Some((args, index)));
+}
+
+// Returns the line at the index without its line ending, moving the index past it. None if the line isn't complete.
+fn read_line<'a>(bytes: &'a [u8], index: &mut usize) -> Option<&'a [u8]> {
+    let start = *index;
+    match bytes[start..].iter().position(|byte| *byte == b'\n') {
+        Some(end) => {
+            *index = start + end + 1;
+            let line = &bytes[start..start + end];
+            return Some(if line.last() == Some(&b'\r') { &line[..line.len() - 1] } else { line });
+        }
+        None => return None,
+    }
+}
+
+fn parse_length(bytes: &[u8]) -> Result<usize, String> {
+    match std::str::from_utf8(bytes).ok().and_then(|len| len.parse::<usize>().ok()) {
+        Some(len) if len <= MAX_REQUEST_SIZE => return Ok(len),
+        _ => return Err(format!("invalid length '{}'", String::from_utf8_lossy(bytes))),
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    assert_eq!(parse_request(b"*2\r\n$5\r\nSTATS\r\n$5\r\npool1\r\n"), Ok(Some((vec!["STATS".to_owned(), "pool1".to_owned()], 26))));
+    assert_eq!(parse_request(b"STATS pool1\r\n*1\r\n"), Ok(Some((vec!["STATS".to_owned(), "pool1".to_owned()], 13))));
+    assert_eq!(parse_request(b"\r\n"), Ok(Some((Vec::new(), 2))));
+    // Partial requests wait for the rest.
+    assert_eq!(parse_request(b""), Ok(None));
+    assert_eq!(parse_request(b"*2\r\n$5\r\nSTATS\r\n$5\r\npoo"), Ok(None));
+    assert_eq!(parse_request(b"*2\r\n$5\r\nSTATS\r\n$"), Ok(None));
+    assert_eq!(parse_request(b"PING"), Ok(None));
+    assert_eq!(parse_request(b"*1\r\n:5\r\n"), Err("expected '$', got ':'".to_owned()));
+    assert_eq!(parse_request(b"*x\r\n"), Err("invalid length 'x'".to_owned()));
+}
+
+#[test]
+fn test_encode_reply() {
+    let mut response = Vec::new();
+    AdminReply::Array(vec![
+        AdminReply::Status("OK".to_owned()),
+        AdminReply::Bulk("a\nb".to_owned()),
+        AdminReply::Integer(3),
+        AdminReply::Nil,
+        AdminReply::Error("Bad\nconfig".to_owned()),
+    ]).encode(&mut response);
+    assert_eq!(&response[..], &b"*5\r\n+OK\r\n$3\r\na\nb\r\n:3\r\n$-1\r\n-ERR Bad config\r\n"[..]);
+}
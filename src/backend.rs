@@ -1,5 +1,5 @@
 use client::BufferedClient;
-use stats::Stats;
+use stats::{Stats, BackendStats};
 use redflareproxy::ClientTokenValue;
 use redisprotocol::WriteError;
 use redflareproxy::PoolTokenValue;
@@ -7,23 +7,31 @@ use std::net::SocketAddr;
 use hashbrown::HashMap;
 use redflareproxy::ClientToken;
 use redflareproxy::BackendToken;
-use client::Client;
+use client::{Client, FallbackState};
 use bufreader::BufReader;
-use redflareproxy::{NULL_TOKEN};
+use redflareproxy::{NULL_TOKEN, MIRROR_TOKEN, MIGRATION_READ_ID, MIGRATION_FALLBACK_ID, STRIP_KEY_PREFIX_ID, REPLICATED_WRITE_ID};
+use redflareproxy::{get_timer_token, RETRY_TIMER, REQUEST_TIMER, HEALTH_CHECK_TIMER};
 use config::BackendConfig;
 use mio::*;
 use mio_more::timer::{Timer, Builder};
 use mio::tcp::{TcpStream};
 use std::collections::{VecDeque};
 use std::string::String;
-use std::io::{Read, Write, BufRead};
+use std::io::{Write, BufRead};
 use std::time::Duration;
 use std::time::Instant;
 use std::cell::RefCell;
 use std::rc::Rc;
 use cluster_backend::{ClusterBackend};
+use sentinel_backend::{SentinelBackend};
+use replicated_backend::{ReplicatedBackend};
+use config::ReadBalance;
 use redisprotocol::extract_redis_command;
 use redisprotocol::RedisError;
+use redisprotocol::{is_nil_response, strip_key_prefix, get_command_name};
+use healthcheck::HealthCheck;
+use backoff::RetryBackoff;
+use circuitbreaker::{CircuitBreaker, CircuitState};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BackendStatus {
@@ -34,14 +42,30 @@ pub enum BackendStatus {
     LOADING,
 }
 
+/*
+    Whether a backend takes new requests, as set through the admin BACKEND command. Draining and disabled backends are
+    left out of the pool's sharding, whether or not auto_eject_hosts is set.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendMode {
+    ENABLED,
+    // Takes no new requests, but keeps its connection so that the requests already sent can finish.
+    DRAINING,
+    // Takes no new requests, and stays disconnected until enabled again.
+    DISABLED,
+}
+
 pub enum BackendEnum {
     Single(SingleBackend),
     Cluster(ClusterBackend),
+    Sentinel(SentinelBackend),
+    Replicated(ReplicatedBackend),
 }
 
 pub struct Backend {
     pub weight: usize,
     pub single: BackendEnum,
+    pub mode: BackendMode,
 }
 impl Backend {
     pub fn new(
@@ -52,13 +76,47 @@ impl Backend {
         next_cluster_token_value: &mut usize,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_backoff: RetryBackoff,
         pool_token: PoolTokenValue,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
     ) -> (Backend, Vec<Token>) {
         let weight = config.weight;
         let (backend, all_backend_tokens) = match config.use_cluster {
+            false if config.use_sentinel => {
+                let (backend, tokens) = SentinelBackend::new(
+                    config,
+                    token,
+                    cluster_backends,
+                    poll_registry,
+                    next_cluster_token_value,
+                    timeout,
+                    failure_limit,
+                    retry_backoff,
+                    pool_token,
+                    num_backends,
+                    cached_backend_shards,
+                );
+                (BackendEnum::Sentinel(backend), tokens)
+            }
+            false if config.replicas.len() > 0 => {
+                let host = config.host.unwrap().clone();
+                let (backend, tokens) = ReplicatedBackend::new(
+                    config,
+                    host,
+                    token,
+                    cluster_backends,
+                    poll_registry,
+                    next_cluster_token_value,
+                    timeout,
+                    failure_limit,
+                    retry_backoff,
+                    pool_token,
+                    num_backends,
+                    cached_backend_shards,
+                );
+                (BackendEnum::Replicated(backend), tokens)
+            }
             false => {
                 // The config should be validated to have a host when not using cluster. See load_config.
                 let host = config.host.unwrap().clone();
@@ -69,7 +127,7 @@ impl Backend {
                     poll_registry,
                     timeout,
                     failure_limit,
-                    retry_timeout,
+                    retry_backoff,
                     pool_token,
                     num_backends,
                     cached_backend_shards,
@@ -85,7 +143,7 @@ impl Backend {
                     next_cluster_token_value,
                     timeout,
                     failure_limit,
-                    retry_timeout,
+                    retry_backoff,
                     pool_token,
                     num_backends,
                     cached_backend_shards,
@@ -96,6 +154,7 @@ impl Backend {
         (Backend {
             single: backend,
             weight: weight,
+            mode: BackendMode::ENABLED,
         }, all_backend_tokens)
     }
 
@@ -103,6 +162,55 @@ impl Backend {
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.reregister_token(new_token, new_num_backends),
             BackendEnum::Cluster(ref mut backend) => backend.reregister_token(new_token, cluster_backends, new_num_backends),
+            BackendEnum::Sentinel(ref mut backend) => backend.reregister_token(new_token, cluster_backends, new_num_backends),
+            BackendEnum::Replicated(ref mut backend) => backend.reregister_token(new_token, cluster_backends, new_num_backends),
+        }
+    }
+
+    // Health checks are not run against cluster backends, which rely on their own slot discovery. Sentinel backends
+    // only check their master.
+    pub fn enable_health_check(&mut self, health_check: HealthCheck, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => backend.enable_health_check(health_check),
+            BackendEnum::Cluster(_) => {}
+            BackendEnum::Sentinel(ref mut backend) => backend.get_master().enable_health_check(health_check),
+            BackendEnum::Replicated(ref mut backend) => backend.enable_health_check(health_check, cluster_backends),
+        }
+    }
+
+    pub fn handle_health_check(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => backend.handle_health_check(clients, completed_clients, stats),
+            BackendEnum::Cluster(_) => {}
+            BackendEnum::Sentinel(ref mut backend) => backend.get_master().handle_health_check(clients, completed_clients, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.get_master().handle_health_check(clients, completed_clients, stats),
+        }
+    }
+
+    // Like health checks, circuit breakers are not used for cluster backends.
+    pub fn enable_circuit_breaker(&mut self, circuit_breaker: CircuitBreaker, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => backend.enable_circuit_breaker(circuit_breaker),
+            BackendEnum::Cluster(_) => {}
+            BackendEnum::Sentinel(ref mut backend) => backend.get_master().enable_circuit_breaker(circuit_breaker),
+            BackendEnum::Replicated(ref mut backend) => backend.enable_circuit_breaker(circuit_breaker, cluster_backends),
+        }
+    }
+
+    pub fn handle_retry_timeout(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>, stats: &mut Stats) {
+        if self.mode == BackendMode::DISABLED {
+            return;
+        }
+        match self.single {
+            BackendEnum::Single(ref mut backend) => backend.handle_retry_timeout(stats),
+            BackendEnum::Cluster(ref mut backend) => backend.init_connection(cluster_backends),
+            BackendEnum::Sentinel(ref mut backend) => backend.handle_retry_timeout(stats),
+            BackendEnum::Replicated(ref mut backend) => backend.get_master().handle_retry_timeout(stats),
         }
     }
 
@@ -110,13 +218,132 @@ impl Backend {
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.change_pool_token(new_token_value),
             BackendEnum::Cluster(ref mut backend) => backend.change_pool_token(new_token_value),
+            BackendEnum::Sentinel(ref mut backend) => backend.change_pool_token(new_token_value),
+            BackendEnum::Replicated(ref mut backend) => backend.change_pool_token(new_token_value),
         }
     }
 
     pub fn is_available(&self) -> bool {
+        if self.mode != BackendMode::ENABLED {
+            return false;
+        }
         match self.single {
             BackendEnum::Single(ref backend) => backend.is_available(),
             BackendEnum::Cluster(ref backend) => backend.is_available(),
+            BackendEnum::Sentinel(ref backend) => backend.is_available(),
+            BackendEnum::Replicated(ref backend) => backend.is_available(),
+        }
+    }
+
+    /*
+        Number of requests sent to this backend that are waiting for a reply. For backends with replicas, only requests
+        to the master are counted. Cluster backends spread their requests over their own hosts, so they report none.
+    */
+    pub fn outstanding_requests(&self) -> usize {
+        match self.single {
+            BackendEnum::Single(ref backend) => backend.queue.len(),
+            BackendEnum::Cluster(_) => 0,
+            BackendEnum::Sentinel(ref backend) => backend.outstanding_requests(),
+            BackendEnum::Replicated(ref backend) => backend.outstanding_requests(),
+        }
+    }
+
+    // Describes the reconnect backoff state of each host connection of this backend.
+    pub fn get_backoff_info(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<String> {
+        return self.describe_hosts(cluster_backends, &|role, backend| {
+            match role {
+                "replica" | "sentinel" => format!("{} {}", role, backend.get_backoff_info()),
+                _ => backend.get_backoff_info(),
+            }
+        });
+    }
+
+    /*
+        The address of the backend's master, for MONITOR. Reads of a replicated backend may go to one of its replicas
+        instead, and cluster backends pick one of their nodes for each key.
+    */
+    pub fn get_address(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> String {
+        match self.single {
+            BackendEnum::Cluster(_) => "cluster".to_owned(),
+            _ => self.describe_hosts(cluster_backends, &|_, backend| backend.get_host().to_string()).remove(0),
+        }
+    }
+
+    // Describes the state of each host connection of this backend, for INFO.
+    pub fn get_info(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<String> {
+        return self.describe_hosts(cluster_backends, &|role, backend| format!("role={},{}", role, backend.get_info()));
+    }
+
+    /*
+        The counters of each host connection of this backend, along with its role and the connection itself for its
+        state. Sentinel connections are left out, since they don't carry any of the pool's traffic.
+    */
+    pub fn get_stats<'a>(&'a self, cluster_backends: &'a Vec<(SingleBackend, usize)>) -> Vec<(String, &'a SingleBackend)> {
+        let mut hosts = self.describe_hosts(cluster_backends, &|role, backend| (role.to_owned(), backend));
+        hosts.retain(|&(ref role, _)| role != "sentinel");
+        return hosts;
+    }
+
+    /*
+        Changes whether the backend takes new requests. Disabling it also closes its connection, failing the requests
+        still queued on it. Cluster backends keep the connections to their nodes, which the cluster manages itself.
+    */
+    pub fn set_mode(
+        &mut self,
+        mode: BackendMode,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        let prev_mode = self.mode;
+        self.mode = mode;
+        if mode == BackendMode::DISABLED {
+            match self.single {
+                BackendEnum::Single(ref mut backend) => backend.close(clients, completed_clients, stats),
+                BackendEnum::Cluster(_) => {}
+                BackendEnum::Sentinel(ref mut backend) => backend.get_master().close(clients, completed_clients, stats),
+                BackendEnum::Replicated(ref mut backend) => backend.get_master().close(clients, completed_clients, stats),
+            }
+        } else if prev_mode == BackendMode::DISABLED {
+            self.reconnect(clients, completed_clients, stats);
+        }
+    }
+
+    /*
+        Closes the connection and connects again right away, rather than after the reconnect backoff. Returns false for
+        cluster backends, which can't be reconnected this way.
+    */
+    pub fn reconnect(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) -> bool {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => backend.reconnect(clients, completed_clients, stats),
+            BackendEnum::Cluster(_) => { return false; }
+            BackendEnum::Sentinel(ref mut backend) => backend.reconnect(clients, completed_clients, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.get_master().reconnect(clients, completed_clients, stats),
+        }
+        return true;
+    }
+
+    // Updates the counters of each host connection that isn't stored in cluster_backends, such as to reset them.
+    pub fn update_stats(&mut self, update: &dyn Fn(&mut BackendStats)) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => update(&mut backend.stats),
+            BackendEnum::Cluster(_) => {}
+            BackendEnum::Sentinel(ref mut backend) => update(&mut backend.get_master().stats),
+            BackendEnum::Replicated(ref mut backend) => update(&mut backend.get_master().stats),
+        }
+    }
+
+    fn describe_hosts<'a, T>(&'a self, cluster_backends: &'a Vec<(SingleBackend, usize)>, describe: &dyn Fn(&str, &'a SingleBackend) -> T) -> Vec<T> {
+        match self.single {
+            BackendEnum::Single(ref backend) => vec![describe("master", backend)],
+            BackendEnum::Cluster(ref backend) => backend.describe_hosts(cluster_backends, describe),
+            BackendEnum::Sentinel(ref backend) => backend.describe_hosts(cluster_backends, describe),
+            BackendEnum::Replicated(ref backend) => backend.describe_hosts(cluster_backends, describe),
         }
     }
 
@@ -124,6 +351,8 @@ impl Backend {
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.init_connection(),
             BackendEnum::Cluster(ref mut backend) => backend.init_connection(cluster_backends),
+            BackendEnum::Sentinel(ref mut backend) => backend.init_connection(cluster_backends),
+            BackendEnum::Replicated(ref mut backend) => backend.init_connection(cluster_backends),
         }
     }
 
@@ -146,6 +375,8 @@ impl Backend {
                     stats
                 )
             }
+            BackendEnum::Sentinel(ref mut backend) => backend.handle_timeout(token, clients, cluster_backends, completed_clients, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.handle_timeout(token, clients, cluster_backends, completed_clients, stats),
         }
     }
 
@@ -177,6 +408,38 @@ impl Backend {
                     stats,
                 )
             }
+            BackendEnum::Sentinel(ref mut backend) => backend.write_message(message, client_token, request_id, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.write_message(message, client_token, request_id, stats),
+        }
+    }
+
+    // Like write_message, but for read commands, which may be sent to a replica instead.
+    pub fn write_read_message(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<(), WriteError> {
+        match self.single {
+            BackendEnum::Replicated(ref mut backend) => backend.write_read_message(message, client_token, cluster_backends, request_id, stats),
+            _ => self.write_message(message, client_token, cluster_backends, request_id, stats),
+        }
+    }
+
+    // Whether reads can go to other hosts than writes, with write_read_message.
+    pub fn has_read_replicas(&self) -> bool {
+        match self.single {
+            BackendEnum::Replicated(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn set_read_balance(&mut self, read_balance: ReadBalance) {
+        match self.single {
+            BackendEnum::Replicated(ref mut backend) => backend.set_read_balance(read_balance),
+            _ => {}
         }
     }
 
@@ -195,6 +458,8 @@ impl Backend {
                 backend.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
             }
             BackendEnum::Cluster(ref mut backend) => backend.handle_backend_response(token, clients, next_cluster_token_value, cluster_backends, completed_clients, stats),
+            BackendEnum::Sentinel(ref mut backend) => backend.handle_backend_response(token, clients, cluster_backends, completed_clients, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.handle_backend_response(token, clients, cluster_backends, completed_clients, stats),
         };
     }
 
@@ -209,6 +474,8 @@ impl Backend {
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.handle_backend_failure(clients, completed_clients, stats),
             BackendEnum::Cluster(ref mut backend) => backend.handle_backend_failure(token, clients, cluster_backends, completed_clients, stats),
+            BackendEnum::Sentinel(ref mut backend) => backend.handle_backend_failure(token, clients, cluster_backends, completed_clients, stats),
+            BackendEnum::Replicated(ref mut backend) => backend.handle_backend_failure(token, clients, cluster_backends, completed_clients, stats),
         }
     }
 }
@@ -218,9 +485,10 @@ pub struct SingleBackend {
     status: BackendStatus,
     pub weight: usize,
     host: SocketAddr,
-    pub queue: VecDeque<(ClientToken, Instant, usize)>,
+    // (client token, deadline, request id, command index in Stats.command_latency) of each request sent.
+    pub queue: VecDeque<(ClientToken, Instant, usize, usize)>,
     failure_limit: usize,
-    retry_timeout: usize,
+    retry_backoff: RetryBackoff,
     failure_count: usize,
     config: BackendConfig,
     pool_token: usize,
@@ -234,6 +502,12 @@ pub struct SingleBackend {
     waiting_for_ping_resp: bool,
     pub num_backends: usize,
     cached_backend_shards: Rc<RefCell<Option<Vec<usize>>>>,
+    health_check: Option<HealthCheck>,
+    health_timer: Option<Timer<Instant>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    // Set when the connection is subscribed to a pubsub channel, and may receive messages without any request.
+    subscribed: bool,
+    pub stats: BackendStats,
 }
 impl SingleBackend {
     pub fn new(
@@ -243,7 +517,7 @@ impl SingleBackend {
         poll_registry: &Rc<RefCell<Poll>>,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_backoff: RetryBackoff,
         pool_token: usize,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
@@ -258,7 +532,7 @@ impl SingleBackend {
             timeout: timeout,
             poll_registry: Rc::clone(poll_registry),
             failure_limit: failure_limit,
-            retry_timeout: retry_timeout,
+            retry_backoff: retry_backoff,
             failure_count: 0,
             weight: config.weight,
             config: config,
@@ -271,28 +545,39 @@ impl SingleBackend {
             waiting_for_ping_resp: false,
             num_backends: num_backends,
             cached_backend_shards: Rc::clone(cached_backend_shards),
+            health_check: None,
+            health_timer: None,
+            circuit_breaker: None,
+            subscribed: false,
+            stats: BackendStats::default(),
         };
         (backend, Vec::new())
     }
 
     pub fn reregister_token(&mut self, new_token: BackendToken, new_num_backends: usize) -> Result<(), std::io::Error> {
         self.num_backends = new_num_backends;
+        self.token = new_token;
         match self.socket {
             Some(ref s) => {
-                self.token = new_token;
                 try!(self.poll_registry.borrow_mut().reregister(s.get_ref(), new_token, Ready::readable() | Ready::writable(), PollOpt::edge()));
             }
             None => {}
         }
         match self.retry_timer {
             Some(ref t) => {
-                try!(self.poll_registry.borrow_mut().reregister(t, Token(new_token.0 + self.num_backends), Ready::readable(), PollOpt::edge()));
+                try!(self.poll_registry.borrow_mut().reregister(t, get_timer_token(new_token, self.num_backends, RETRY_TIMER), Ready::readable(), PollOpt::edge()));
             }
             None => {}
         }
         match self.timer {
             Some(ref t) => {
-                try!(self.poll_registry.borrow_mut().reregister(t, Token(new_token.0 + 2* self.num_backends), Ready::readable(), PollOpt::edge()));
+                try!(self.poll_registry.borrow_mut().reregister(t, get_timer_token(new_token, self.num_backends, REQUEST_TIMER), Ready::readable(), PollOpt::edge()));
+            }
+            None => {}
+        }
+        match self.health_timer {
+            Some(ref t) => {
+                try!(self.poll_registry.borrow_mut().reregister(t, get_timer_token(new_token, self.num_backends, HEALTH_CHECK_TIMER), Ready::readable(), PollOpt::edge()));
             }
             None => {}
         }
@@ -303,8 +588,107 @@ impl SingleBackend {
         self.pool_token = new_token_value;
     }
 
+    // A backend with an open circuit, or a half-open one that sent all its probes, is unavailable, so that its keys
+    // move to other backends with auto_eject_hosts.
     pub fn is_available(&self) -> bool {
-        return self.status == BackendStatus::READY;
+        return self.status == BackendStatus::READY && is_circuit_available(&self.circuit_breaker);
+    }
+
+    // The state and counters of the connection, for STATS <pool> <backend>.
+    pub fn get_stats_info(&self) -> String {
+        format!("host: {}\nstatus: {:?}\nqueue: {}\n{}", self.host, self.status, self.queue.len(), self.stats)
+    }
+
+    pub fn get_host(&self) -> SocketAddr {
+        return self.host;
+    }
+
+    /*
+        Points the backend to a new host, such as after a failover. Pending requests to the old host receive an error,
+        but clients stay connected.
+    */
+    pub fn change_host(
+        &mut self,
+        host: SocketAddr,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if self.status != BackendStatus::DISCONNECTED {
+            self.mark_backend_down(clients, completed_clients, stats);
+        }
+        self.host = host;
+        self.retry_backoff.reset();
+        self.init_connection();
+    }
+
+    // Closes the connection and connects again right away, with the reconnect backoff starting over.
+    pub fn reconnect(
+        &mut self,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        self.close(clients, completed_clients, stats);
+        self.retry_backoff.reset();
+        self.init_connection();
+    }
+
+    pub fn set_subscribed(&mut self, subscribed: bool) {
+        self.subscribed = subscribed;
+    }
+
+    pub fn get_backoff_info(&self) -> String {
+        format!(
+            "{} status={:?} attempts={} last_retry_timeout={} next_retry_timeout={}",
+            self.host,
+            self.status,
+            self.retry_backoff.attempts,
+            self.retry_backoff.last_delay,
+            self.retry_backoff.current_timeout,
+        )
+    }
+
+    pub fn get_info(&self) -> String {
+        format!(
+            "host={},status={:?},queue={},failures={},retry_attempts={},next_retry_timeout={}",
+            self.host,
+            self.status,
+            self.queue.len(),
+            self.failure_count,
+            self.retry_backoff.attempts,
+            self.retry_backoff.current_timeout,
+        )
+    }
+
+    /*
+        Callback for the retry timer. The retry timer is used both for reconnecting to the backend, and for moving an
+        open circuit to half-open.
+    */
+    pub fn handle_retry_timeout(&mut self, stats: &mut Stats) {
+        let prev_circuit_state = get_circuit_state(&self.circuit_breaker);
+        match self.circuit_breaker {
+            Some(ref mut circuit_breaker) => circuit_breaker.half_open_if_expired(Instant::now(), stats),
+            None => {}
+        }
+        self.handle_circuit_transition(prev_circuit_state);
+        self.init_connection();
+    }
+
+    // Logs a change in the circuit state, and updates the sharding and timers accordingly.
+    fn handle_circuit_transition(&mut self, prev_circuit_state: Option<CircuitState>) {
+        let (circuit_state, open_timeout) = match self.circuit_breaker {
+            Some(ref circuit_breaker) => (circuit_breaker.state, circuit_breaker.open_timeout),
+            None => { return; }
+        };
+        if prev_circuit_state == Some(circuit_state) {
+            return;
+        }
+        info!("Circuit breaker for backend {} changed from {:?} to {:?}", self.host, prev_circuit_state.unwrap(), circuit_state);
+        *self.cached_backend_shards.borrow_mut() = None;
+        if circuit_state == CircuitState::Open {
+            self.schedule_retry_timer(open_timeout);
+        }
     }
 
     pub fn init_connection(&mut self) {
@@ -324,6 +708,9 @@ impl SingleBackend {
             debug!("Trying to connect when already connected!");
             return Ok(());
         }
+        if self.retry_backoff.attempts > 0 {
+            self.stats.reconnects += 1;
+        }
 
         // Setup the server socket
         let socket = try!(TcpStream::connect(&self.host));
@@ -384,12 +771,129 @@ impl SingleBackend {
             wait_for_resp = true;
         }
 
-        if !wait_for_resp {
+        if !wait_for_resp && !is_ejected(&self.health_check) {
             change_state(&mut self.status, BackendStatus::READY);
             *self.cached_backend_shards.borrow_mut() = None;
         }
     }
 
+    pub fn enable_circuit_breaker(&mut self, circuit_breaker: CircuitBreaker) {
+        self.circuit_breaker = Some(circuit_breaker);
+    }
+
+    pub fn enable_health_check(&mut self, health_check: HealthCheck) {
+        let interval = health_check.interval;
+        self.health_check = Some(health_check);
+        self.set_health_timer(interval);
+    }
+
+    /*
+        Callback for the health check timer. Sends the health check command to the backend, and marks the backend down
+        if it has failed too many consecutive checks.
+        A check that has not been answered by the time the next one is due counts as a failure.
+    */
+    pub fn handle_health_check(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        match self.health_timer {
+            Some(ref mut t) => while t.poll().is_some() {},
+            None => { return; }
+        }
+        let (interval, waiting_for_resp, request) = match self.health_check {
+            Some(ref health_check) => (health_check.interval, health_check.waiting_for_resp, health_check.request.clone()),
+            None => { return; }
+        };
+        self.set_health_timer(interval);
+
+        // Backends that are still connecting are handled by the retry timer instead.
+        let handshake_done = !self.waiting_for_auth_resp && !self.waiting_for_db_resp && !self.waiting_for_ping_resp;
+        if self.socket.is_none() || !handshake_done ||
+            (self.status != BackendStatus::READY && self.status != BackendStatus::CONNECTED) {
+            return;
+        }
+
+        let succeeded = if waiting_for_resp {
+            debug!("Health check for backend {} was not answered in time.", self.host);
+            false
+        } else {
+            match self.write_to_backend_stream(NULL_TOKEN, &request, (Instant::now(), 0), stats) {
+                Ok(_) => true,
+                Err(err) => {
+                    debug!("Failed to send health check to backend {}. Received error: {}", self.host, err);
+                    false
+                }
+            }
+        };
+        match self.health_check {
+            Some(ref mut health_check) => {
+                if succeeded {
+                    health_check.waiting_for_resp = true;
+                } else {
+                    health_check.record_failure();
+                }
+            }
+            None => {}
+        }
+        self.eject_if_unhealthy(clients, completed_clients, stats);
+    }
+
+    // Marks the backend down if it has reached its health check's unhealthy threshold.
+    // Returns whether the backend was marked down.
+    fn eject_if_unhealthy(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) -> bool {
+        match self.health_check {
+            Some(ref mut health_check) => {
+                if !health_check.is_unhealthy() {
+                    return false;
+                }
+                health_check.eject();
+            }
+            None => { return false; }
+        }
+        error!("Backend {} failed its health checks. Marking it as down.", self.host);
+        self.handle_backend_failure(clients, completed_clients, stats);
+        return true;
+    }
+
+    fn set_health_timer(&mut self, interval: usize) {
+        if self.health_timer.is_none() {
+            let timer = create_timer();
+            let timer_token = get_timer_token(self.token, self.num_backends, HEALTH_CHECK_TIMER);
+            match self.poll_registry.borrow_mut().register(&timer, timer_token, Ready::readable(), PollOpt::edge()) {
+                Ok(_) => {}
+                Err(err) => {
+                    // Expected to occur only when timer is registered to a different poll (which shouldn't happen).
+                    panic!("Failed to register health check timer to poll. Received error: {}", err);
+                }
+            };
+            self.health_timer = Some(timer);
+        }
+
+        let timestamp = Instant::now() + Duration::from_millis(interval as u64);
+        match self.health_timer {
+            Some(ref mut timer) => {
+                match timer.set_timeout(Duration::from_millis(interval as u64), timestamp) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        // Expected to occur only in cases of usize integer overflow.
+                        panic!("Failure setting timer timeout: {}.", err);
+                    }
+                }
+            }
+            None => {
+                // Never expected to occur.
+                panic!("Timer does not exist after being instantiated.");
+            }
+        }
+    }
+
     // Handles a potential timeout.
     // Returns a boolean, signifying whether to mark this backend as down or not.
     pub fn handle_timeout(
@@ -454,8 +958,44 @@ impl SingleBackend {
                 *self.cached_backend_shards.borrow_mut() = None;
                 self.init_connection();
             }
+            else if head.0 == NULL_TOKEN {
+                // A timed out health check counts as a failed check. It is only judged by the health check threshold,
+                // not by failure_limit. Below the threshold, the connection is still reopened, since the late reply
+                // would otherwise be taken as the reply to the next request.
+                let health_check_state = match self.health_check {
+                    Some(ref mut health_check) if health_check.waiting_for_resp => {
+                        health_check.waiting_for_resp = false;
+                        health_check.record_failure();
+                        Some(health_check.is_unhealthy())
+                    }
+                    _ => None,
+                };
+                match health_check_state {
+                    Some(true) => {
+                        match self.health_check {
+                            Some(ref mut health_check) => health_check.eject(),
+                            None => {}
+                        }
+                        error!("Backend {} failed its health checks. Marking it as down.", self.host);
+                        return true;
+                    }
+                    Some(false) => {
+                        self.close(clients, completed_clients, stats);
+                        self.init_connection();
+                        return false;
+                    }
+                    None => {}
+                }
+            }
 
             if head.0 != NULL_TOKEN {
+                self.stats.timeouts += 1;
+                let prev_circuit_state = get_circuit_state(&self.circuit_breaker);
+                match self.circuit_breaker {
+                    Some(ref mut circuit_breaker) => circuit_breaker.record_failure(stats),
+                    None => {}
+                }
+                self.handle_circuit_transition(prev_circuit_state);
                 debug!("Trying to find client: {:?}", (head.0));
                 handle_write_to_client(
                     clients,
@@ -493,6 +1033,11 @@ impl SingleBackend {
         *self.cached_backend_shards.borrow_mut() = None;
         self.failure_count = 0;
         self.socket = None;
+        self.subscribed = false;
+        match self.health_check {
+            Some(ref mut health_check) => health_check.waiting_for_resp = false,
+            None => {}
+        }
     }
 
     // Marks the backend as down. Returns an error message to all pending requests.
@@ -502,6 +1047,19 @@ impl SingleBackend {
         clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
+    ) {
+        if self.status == BackendStatus::READY {
+            self.stats.ejections += 1;
+        }
+        self.close(clients, completed_clients, stats);
+    }
+
+    // Closes the connection, if there is one. Returns an error message to all pending requests.
+    pub fn close(
+        &mut self,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
     ) {
         self.disconnect();
 
@@ -512,8 +1070,8 @@ impl SingleBackend {
         let mut possible_token = self.queue.pop_front();
         loop {
             match possible_token {
-                Some((NULL_TOKEN, _, _)) => {}
-                Some((client_token, instant, id)) => {
+                Some((NULL_TOKEN, _, _, _)) => {}
+                Some((client_token, instant, id, _)) => {
                     handle_write_to_client(
                         clients,
                         &client_token.0,
@@ -539,7 +1097,28 @@ impl SingleBackend {
         // TODO: get rid of this wrapper function.
         match self.status {
             BackendStatus::READY => {
-                return self.write_to_backend_stream(client_token, message, request_id, stats);
+                let prev_circuit_state = get_circuit_state(&self.circuit_breaker);
+                let was_available = is_circuit_available(&self.circuit_breaker);
+                let allowed = match self.circuit_breaker {
+                    Some(ref mut circuit_breaker) => circuit_breaker.allow_request(stats),
+                    None => true,
+                };
+                if was_available && !is_circuit_available(&self.circuit_breaker) {
+                    // The last probe of a half-open circuit was sent. Its other keys go back to other backends.
+                    *self.cached_backend_shards.borrow_mut() = None;
+                }
+                if !allowed {
+                    return Err(WriteError::CircuitOpen);
+                }
+                let res = self.write_to_backend_stream(client_token, message, request_id, stats);
+                if res.is_err() {
+                    match self.circuit_breaker {
+                        Some(ref mut circuit_breaker) => circuit_breaker.record_failure(stats),
+                        None => {}
+                    }
+                }
+                self.handle_circuit_transition(prev_circuit_state);
+                return res;
             }
             _ => {
                 debug!("No backend connection.");
@@ -564,8 +1143,10 @@ impl SingleBackend {
         // This can be considered DISCONNECTED already. If that's the case, disconnect should flush all responses in the queue.
         // This does happen because when disconnecting, the socket is set to None.
 
-        // Read all responses if there are any left.
-        while self.queue.len() > 0 {
+        let prev_circuit_state = get_circuit_state(&self.circuit_breaker);
+
+        // Read all responses if there are any left. Subscribed connections may also have pubsub messages to read.
+        while self.queue.len() > 0 || self.subscribed {
             let res = route_backend_response(
                 &mut self.socket,
                 clients,
@@ -574,20 +1155,31 @@ impl SingleBackend {
                 &mut self.waiting_for_auth_resp,
                 &mut self.waiting_for_db_resp,
                 &mut self.waiting_for_ping_resp,
+                &mut self.health_check,
+                &mut self.circuit_breaker,
+                self.timeout,
                 internal_resp_handler,
                 &self.cached_backend_shards,
                 completed_clients,
+                &mut self.stats,
                 stats,
             );
             match res {
                 Ok(true) => continue,
-                Ok(false) => { return; }
+                Ok(false) => { break; }
                 Err(err) => {
                     error!("Received incompatible response from backend. Forcing a disconnect. Received error while parsing: {}", err);
                     self.mark_backend_down(clients, completed_clients, stats);
                 }
             }
         }
+        self.handle_circuit_transition(prev_circuit_state);
+        // The backend has passed its PING, so reconnects start from the initial delay again.
+        if self.status == BackendStatus::READY && self.retry_backoff.attempts > 0 {
+            debug!("Backend {} is ready after {} reconnect attempts.", self.host, self.retry_backoff.attempts);
+            self.retry_backoff.reset();
+        }
+        self.eject_if_unhealthy(clients, completed_clients, stats);
         return;
     }
 
@@ -602,10 +1194,16 @@ impl SingleBackend {
     }
 
     fn set_retry_timer(&mut self) {
+        let retry_timeout = self.retry_backoff.next_delay();
+        debug!("Retrying backend {} in {} ms. Attempt: {}", self.host, retry_timeout, self.retry_backoff.attempts);
+        self.schedule_retry_timer(retry_timeout);
+    }
+
+    fn schedule_retry_timer(&mut self, retry_timeout: usize) {
         if self.retry_timer.is_none() {
-        debug!("Creating timer");
+            debug!("Creating timer");
             let timer = create_timer();
-            let timer_token = Token(self.token.0 + self.num_backends);
+            let timer_token = get_timer_token(self.token, self.num_backends, RETRY_TIMER);
             match self.poll_registry.borrow_mut().register(&timer, timer_token, Ready::readable(), PollOpt::edge()) {
                 Ok(_) => {}
                 Err(err) => {
@@ -616,11 +1214,11 @@ impl SingleBackend {
             self.retry_timer = Some(timer);
         }
 
-        let now = Instant::now();  
-        let timestamp = now + Duration::from_millis(self.retry_timeout as u64);
+        let now = Instant::now();
+        let timestamp = now + Duration::from_millis(retry_timeout as u64);
         match self.retry_timer {
             Some(ref mut timer) => {
-                match timer.set_timeout(Duration::new(0, (1000000 * self.retry_timeout) as u32), timestamp) {
+                match timer.set_timeout(Duration::from_millis(retry_timeout as u64), timestamp) {
                     Ok(_) => { }
                     Err(err) => {
                         // Expected to occur only in cases of usize integer overflow.
@@ -648,14 +1246,25 @@ impl SingleBackend {
             None => return Err(WriteError::NoSocket),
         };
         stats.send_backend_bytes += bytes_written;
+        self.stats.send_bytes += bytes_written;
+        // Internal requests aren't timed, so they keep command index 0. Neither are the copies written for a mirror or a
+        // migration, which would otherwise count each client request twice.
+        let mut command_index = 0;
+        if client_token != NULL_TOKEN && client_token != MIRROR_TOKEN {
+            self.stats.requests += 1;
+            command_index = match get_command_name(message) {
+                Ok(name) => stats.command_index(name),
+                Err(_) => stats.command_index(b"UNKNOWN"),
+            };
+        }
         // TODO: Keep trying on self.socket if it's INTERRUPTED or WOULDBLOCK, otherwise DISCONNECT the backend connection.
         let timestamp = request_id.0 + Duration::from_millis(self.timeout as u64);
-        self.queue.push_back((client_token, timestamp, request_id.1));
+        self.queue.push_back((client_token, timestamp, request_id.1, command_index));
         // Need to guarantee that queue is ordered. Is there any possibility
         if self.queue.len() == 1 && self.timeout != 0 {
             if self.timer.is_none() {
                 let timer = create_timer();
-                let timer_token = Token(self.token.0 + 2 * self.num_backends);
+                let timer_token = get_timer_token(self.token, self.num_backends, REQUEST_TIMER);
                 debug!("Registered timer: {:?}", timer_token);
                 match self.poll_registry.borrow_mut().register(&timer, timer_token, Ready::readable(), PollOpt::edge()) {
                     Ok(_) => {}
@@ -693,6 +1302,7 @@ fn handle_internal_response(
     waiting_for_auth_resp: &mut bool,
     waiting_for_db_resp: &mut bool,
     waiting_for_ping_resp: &mut bool,
+    health_check: &mut Option<HealthCheck>,
     response: &[u8],
     internal_resp_handler: &mut FnMut(&[u8]),
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
@@ -708,15 +1318,63 @@ fn handle_internal_response(
         *waiting_for_ping_resp = false;
     }
     else {
-        internal_resp_handler(response);
+        match *health_check {
+            Some(ref mut health_check) if health_check.waiting_for_resp => {
+                handle_health_check_response(status, health_check, response, cached_backend_shards);
+            }
+            _ => internal_resp_handler(response),
+        }
+        return;
+    }
+    if !*waiting_for_auth_resp && !*waiting_for_db_resp && !*waiting_for_ping_resp && !is_ejected(health_check) {
+        change_state(status, BackendStatus::READY);
+        *cached_backend_shards.borrow_mut() = None;
+    }
+}
+
+/*
+    Records the result of a health check. A backend that was marked down by its health check is kept CONNECTED after
+    reconnecting, and is only made READY once it passes enough consecutive checks.
+*/
+fn handle_health_check_response(
+    status: &mut BackendStatus,
+    health_check: &mut HealthCheck,
+    response: &[u8],
+    cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
+) {
+    health_check.waiting_for_resp = false;
+    if !health_check.is_expected_reply(response) {
+        debug!("Health check received an unexpected reply: {:?}", std::str::from_utf8(response));
+        health_check.record_failure();
         return;
     }
-    if !*waiting_for_auth_resp && !*waiting_for_db_resp && !*waiting_for_ping_resp {
+    if health_check.record_success() && *status == BackendStatus::CONNECTED {
         change_state(status, BackendStatus::READY);
         *cached_backend_shards.borrow_mut() = None;
     }
 }
 
+fn get_circuit_state(circuit_breaker: &Option<CircuitBreaker>) -> Option<CircuitState> {
+    match *circuit_breaker {
+        Some(ref circuit_breaker) => Some(circuit_breaker.state),
+        None => None,
+    }
+}
+
+fn is_circuit_available(circuit_breaker: &Option<CircuitBreaker>) -> bool {
+    match *circuit_breaker {
+        Some(ref circuit_breaker) => circuit_breaker.is_available(),
+        None => true,
+    }
+}
+
+fn is_ejected(health_check: &Option<HealthCheck>) -> bool {
+    match *health_check {
+        Some(ref health_check) => health_check.ejected,
+        None => false,
+    }
+}
+
 fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool {
     // TODO: Rethink change state flow.
     if *status == target_state {
@@ -748,21 +1406,25 @@ fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool
 }
 
 /*
-    This should only be called if there is a request in the queue.
-    Will panic if the queue is empty.
+    This should only be called if there is a request in the queue, or if the connection is subscribed to pubsub.
+    Responses that don't match any request are pubsub messages, and go to the internal response handler.
     Returns whether there may be more responses or not.
 */
 fn route_backend_response(
     stream: &mut Option<BufReader<TcpStream>>,
     clients: &mut HashMap<usize, (BufferedClient, usize)>,
-    queue: &mut VecDeque<(Token, Instant, usize)>,
+    queue: &mut VecDeque<(Token, Instant, usize, usize)>,
     status: &mut BackendStatus,
     waiting_for_auth_resp: &mut bool,
     waiting_for_db_resp: &mut bool,
     waiting_for_ping_resp: &mut bool,
+    health_check: &mut Option<HealthCheck>,
+    circuit_breaker: &mut Option<CircuitBreaker>,
+    timeout: usize,
     internal_resp_handler: &mut FnMut(&[u8]),
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
     completed_clients: &mut VecDeque<ClientTokenValue>,
+    backend_stats: &mut BackendStats,
     stats: &mut Stats,
 ) -> Result<bool, RedisError> {
     match stream {
@@ -817,9 +1479,9 @@ fn route_backend_response(
                         return Ok(false);
                     }
 
-                    let (client_token, request_id) = match queue.pop_front() {
-                        Some((client_token, instant, id)) => (client_token, (instant, id)),
-                        None => panic!("No more client token in backend queue, even though queue length was >0 just now!"),
+                    let (client_token, request_id, command_index) = match queue.pop_front() {
+                        Some((client_token, instant, id, command_index)) => (client_token, (instant, id), command_index),
+                        None => (NULL_TOKEN, (Instant::now(), 0), 0),
                     };
 
                     if client_token == NULL_TOKEN {
@@ -828,11 +1490,27 @@ fn route_backend_response(
                             waiting_for_auth_resp,
                             waiting_for_db_resp,
                             waiting_for_ping_resp,
+                            health_check,
                             response,
                             internal_resp_handler,
                             cached_backend_shards,
                         );
                     } else {
+                        // The queue stores the deadline of each request, so subtract the timeout to get its start.
+                        let latency = Instant::now() - (request_id.0 - Duration::from_millis(timeout as u64));
+                        match *circuit_breaker {
+                            Some(ref mut circuit_breaker) => circuit_breaker.record_response(latency, stats),
+                            None => {}
+                        }
+                        if client_token != MIRROR_TOKEN {
+                            let latency_us = latency.as_secs() * 1_000_000 + latency.subsec_nanos() as u64 / 1_000;
+                            backend_stats.latency.record(latency_us);
+                            match stats.command_latency.get_mut(command_index) {
+                                Some(&mut (_, ref mut histogram)) => histogram.record(latency_us),
+                                None => {}
+                            }
+                            backend_stats.record_response(response);
+                        }
                         handle_write_to_client(clients, &client_token.0, response, request_id, completed_clients, stats);
                     }
                     break response.len()
@@ -840,14 +1518,15 @@ fn route_backend_response(
             };
             s.consume(len);
             stats.recv_backend_bytes += len;
+            backend_stats.recv_bytes += len;
 
             return Ok(true);
         }
         // This case occurs if the backend is disconnected. If that's the case, then it should send error messges to clients.
         None => {
             let (client_token, request_id) = match queue.pop_front() {
-                Some((client_token, instant, id)) => (client_token, (instant, id)),
-                None => panic!("No more client token in backend queue, even though queue length was >0 just now!"),
+                Some((client_token, instant, id, _)) => (client_token, (instant, id)),
+                None => return Ok(false),
             };
             if client_token != NULL_TOKEN {
                 handle_write_to_client(clients,&client_token.0, b"ERR Backend disconnected", request_id, completed_clients, stats);
@@ -857,69 +1536,8 @@ fn route_backend_response(
     }
 }
 
-// This extracts the command from the stream.
-// TODO: Use a StreamingIterator: https://github.com/rust-lang/rfcs/pull/1598
-pub fn parse_redis_command<R: Read>(stream: &mut BufReader<R>) -> String {
-    let mut command = String::new();
-    let mut string = String::new();
-    let _ = stream.read_line(&mut string);
-    match string.chars().next() {
-        Some('$') => {
-            if Some('-') == {
-                let mut chars = string.chars();
-                chars.next();
-                chars.next()
-            } {
-                return string;
-            }
-            let bytes = {
-                let mut next_line = String::from(&string[1..]);
-                next_line.pop();
-                next_line.pop();
-                match next_line.parse::<usize>() {
-                    Ok(int) => int,
-                    Err(err) => {
-                        error!("Could not parse array response length: {} {} {}", string, err, next_line);
-                        0
-                    }
-                }
-            };
-            let mut buf = vec![0; bytes + 2];
-            let _ = stream.read_exact(&mut buf);
-            match String::from_utf8(buf) {
-                Ok(result) => {
-                    command.push_str(&result);
-                    string.push_str(&result);
-                }
-                Err(err) => error!("Could not parse from utf8 buffer: {}", err),
-            }
-        }
-        Some('*') => {
-            let lines = {
-                let mut next_line = String::from(&string[1..]);
-                next_line.pop();
-                next_line.pop();
-                match next_line.parse::<usize>() {
-                    Ok(int) => int,
-                    Err(err) => {
-                        error!("Could not parse array response length: {} {} {}", string, err, next_line);
-                        0
-                    }
-                }
-            };
-            for _ in 0..lines {
-                let next_line = parse_redis_command(stream);
-                command.push_str(&next_line);
-                string.push_str(&next_line);
-            }
-        }
-        _ => {}
-    }
-    command
-}
-
 // TODO: Should we want more clarity?
-fn create_timer() -> Timer<Instant> {
+pub fn create_timer() -> Timer<Instant> {
     let mut builder = Builder::default();
     builder = builder.tick_duration(Duration::from_millis(10));
     builder.build()
@@ -970,6 +1588,10 @@ pub fn handle_write_to_client(
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
 ) {
+    // Responses to mirrored requests are discarded.
+    if *client_token_value == MIRROR_TOKEN.0 {
+        return;
+    }
     let res = match clients.get_mut(client_token_value) {
         Some((client, _)) => write_to_client(client.get_mut(), client_token_value, message, request_id, completed_clients, stats),
         None => { return; }
@@ -994,15 +1616,101 @@ pub fn write_to_client(
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
 ) -> std::result::Result<usize, WriteError> {
+    let res = write_reply(client, client_token_value, message, request_id, completed_clients, stats);
+    match res {
+        Ok(bytes_written) => client.bytes_out += bytes_written,
+        Err(_) => {}
+    }
+    return res;
+}
+
+// Writes the reply to a request, or holds on to it until the reply to the client is complete.
+fn write_reply(
+    client: &mut Client,
+    client_token_value: &ClientTokenValue,
+    message: &[u8],
+    request_id: (Instant, usize),
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    stats: &mut Stats,
+) -> std::result::Result<usize, WriteError> {
+    if request_id.1 == MIGRATION_READ_ID {
+        // A read from the new pool of a migration. A miss isn't answered, and gets retried on the old pool instead.
+        if is_nil_response(message) {
+            match client.pending_fallback {
+                Some(ref mut pending_fallback) => {
+                    pending_fallback.state = FallbackState::NewMissed;
+                    completed_clients.push_back(*client_token_value);
+                    return Ok(0);
+                }
+                None => {}
+            }
+        }
+        client.pending_fallback = None;
+        completed_clients.push_back(*client_token_value);
+        stats.responses += 1;
+        return write_to_stream(&mut client.stream, message);
+    }
+    if request_id.1 == MIGRATION_FALLBACK_ID {
+        // The old pool's reply to a read that missed on the new pool. Keep a value to back-fill the new pool with.
+        let backfill = match client.pending_fallback {
+            Some(ref pending_fallback) => pending_fallback.backfill && message.get(0) == Some(&b'$') && !is_nil_response(message),
+            None => false,
+        };
+        if backfill {
+            match client.pending_fallback {
+                Some(ref mut pending_fallback) => pending_fallback.state = FallbackState::Backfill(message.to_vec()),
+                None => {}
+            }
+        } else {
+            client.pending_fallback = None;
+        }
+        completed_clients.push_back(*client_token_value);
+        stats.responses += 1;
+        return write_to_stream(&mut client.stream, message);
+    }
+    if request_id.1 == REPLICATED_WRITE_ID {
+        let (response, done) = match client.pending_replicated_write {
+            Some(ref mut replicated_write) => (replicated_write.record_reply(message), replicated_write.remaining == 0),
+            None => (None, true),
+        };
+        if done {
+            client.pending_replicated_write = None;
+            completed_clients.push_back(*client_token_value);
+        }
+        match response {
+            Some(response) => {
+                stats.responses += 1;
+                return write_to_stream(&mut client.stream, &response);
+            }
+            None => return Ok(0),
+        }
+    }
+    if request_id.1 == STRIP_KEY_PREFIX_ID {
+        let response = match client.pending_key_strip.take() {
+            Some(prefix) => strip_key_prefix(message, &prefix).unwrap_or_else(|_| message.to_vec()),
+            None => message.to_vec(),
+        };
+        completed_clients.push_back(*client_token_value);
+        stats.responses += 1;
+        return write_to_stream(&mut client.stream, &response);
+    }
     if request_id.1 == 0 {
         // Id of 0 means that request is a normal request.
         stats.responses += 1;
         write_to_stream(&mut client.stream, message)
     } else {
         // Id > 0 means that the request is a multikey request.
-        client.pending_response[request_id.1 - 1] = message.to_vec();
+        let response = match client.pending_replicated_parts.get_mut(request_id.1 - 1) {
+            Some(&mut Some(ref mut replicated_write)) => replicated_write.record_reply(message),
+            _ => Some(message.to_vec()),
+        };
+        match response {
+            Some(response) => client.pending_response[request_id.1 - 1] = response,
+            None => {}
+        }
         client.pending_count -= 1;
         if client.pending_count == 0 {
+            client.pending_replicated_parts.clear();
             // Assemble the full response.
             let mut full_message = Vec::new();
             full_message.extend_from_slice(b"*");
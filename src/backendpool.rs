@@ -7,36 +7,30 @@ use redflareproxy::PoolTokenValue;
 use redflareproxy::ClientTokenValue;
 use backend::SingleBackend;
 use redflareproxy::ClientToken;
-use client::{Client};
+use client::{Client, PendingFallback, FallbackState, ReplicatedWrite};
+use monitor::Monitor;
 use redflareproxy::ProxyError;
 use redisprotocol::extract_redis_command;
 use hash::hash;
 use redflareproxy::BackendToken;
 use redflareproxy::PoolToken;
-use config::{Distribution, BackendPoolConfig};
-use backend::{Backend};
-use redisprotocol::{extract_key, RedisError, KeyPos};
+use config::{Distribution, BackendPoolConfig, MirrorMode, ReplicaAck};
+use backend::{Backend, BackendMode};
+use redisprotocol::{extract_key, is_write_command, is_get_command, add_key_prefix, get_command_name, split_request, RedisError, KeyPos, WriteError};
 use mio::*;
 use mio::tcp::{TcpListener};
 use std::string::String;
 use std::io::{BufRead};
 use hashbrown::HashMap;
-use conhash::*;
-use conhash::Node;
+use ketama::server_name;
+use listener::Router;
+use redflareproxy::{FIRST_SOCKET_INDEX, MIRROR_TOKEN, MIGRATION_READ_ID, MIGRATION_FALLBACK_ID, STRIP_KEY_PREFIX_ID, REPLICATED_WRITE_ID, PoolIndex};
+use distribution::{ShardLookup, jump_consistent_hash, least_loaded_backend, uses_lookup};
 use rand::thread_rng;
 use rand::Rng;
 use std::cell::RefCell;
 use std::rc::Rc;
-
-#[derive(Clone)]
-struct IndexNode {
-    index: usize,
-}
-impl Node for IndexNode {
-    fn name(&self) -> String {
-        format!("{:?}", self.index)
-    }
-}
+use std::time::{Duration, Instant};
 
 pub struct BackendPool {
     pub token: PoolToken,
@@ -46,12 +40,62 @@ pub struct BackendPool {
 
     // Cache list of backend tokens. Used for sharding purposes.
     pub cached_backend_shards: Rc<RefCell<Option<Vec<usize>>>>,
+    // Lookup structure of the distribution, if it needs one. Rebuilt along with cached_backend_shards, whenever that is
+    // cleared.
+    pub cached_shard_lookup: Option<ShardLookup>,
 
     // index corresponding to the first backend associated with this pool.
     pub first_backend_index: usize,
     pub num_backends: usize,
 
     pub listen_socket: Option<TcpListener>,
+
+    // Index of the pool that requests are mirrored to. See BackendPoolConfig::mirror.
+    pub mirror_pool_index: Option<PoolIndex>,
+
+    // Index of the pool that keys are migrated from. See BackendPoolConfig::migrate_from.
+    pub migrate_from_pool_index: Option<PoolIndex>,
+    pub migration_state: MigrationState,
+
+    // Index of the pool that requests go to while this pool has no available backend. See BackendPoolConfig::fallback_pool.
+    pub fallback_pool_index: Option<PoolIndex>,
+}
+
+/*
+    Progress of moving keys from the old pool (migrate_from) to the pool itself. Set from the admin port, and only
+    moves forward:
+    Off: Everything goes to the old pool.
+    DualWrite: Writes go to both pools, and the new pool's reply is returned. Reads go to the new pool, and fall back to
+        the old pool when the new pool replies with nil.
+    CutOver: Writes still go to both pools, so that the old pool can be switched back to. Reads only go to the new pool.
+    Finished: Everything goes to the new pool.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationState {
+    Off,
+    DualWrite,
+    CutOver,
+    Finished,
+}
+impl MigrationState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MigrationState::Off => "off",
+            MigrationState::DualWrite => "dual_write",
+            MigrationState::CutOver => "cut_over",
+            MigrationState::Finished => "finished",
+        }
+    }
+
+    // The state reached by the admin action, if it is the next step of the migration.
+    pub fn advance(&self, action: &str) -> Option<MigrationState> {
+        match (*self, action) {
+            (MigrationState::Off, "START") => Some(MigrationState::DualWrite),
+            (MigrationState::DualWrite, "CUTOVER") => Some(MigrationState::CutOver),
+            (MigrationState::CutOver, "FINISH") => Some(MigrationState::Finished),
+            _ => None,
+        }
+    }
 }
 
 impl BackendPool {
@@ -66,16 +110,42 @@ impl BackendPool {
             first_backend_index: first_backend_index,
             listen_socket: None,
             cached_backend_shards: Rc::new(RefCell::new(None)),
+            cached_shard_lookup: None,
+            mirror_pool_index: None,
+            migrate_from_pool_index: None,
+            migration_state: MigrationState::Off,
+            fallback_pool_index: None,
         }
     }
 
+    // Looks up the indexes of the mirror pool and the pool migrated from. Needs to be redone whenever the list of pools
+    // changes.
+    pub fn resolve_pool_references(&mut self, pool_names: &Vec<String>) {
+        self.mirror_pool_index = match self.config.mirror {
+            Some(ref mirror) => pool_names.iter().position(|pool_name| pool_name == mirror),
+            None => None,
+        };
+        self.migrate_from_pool_index = match self.config.migrate_from {
+            Some(ref old_pool) => pool_names.iter().position(|pool_name| pool_name == old_pool),
+            None => None,
+        };
+        self.fallback_pool_index = match self.config.fallback_pool {
+            Some(ref fallback_pool) => pool_names.iter().position(|pool_name| pool_name == fallback_pool),
+            None => None,
+        };
+    }
+
     /*
         Attempts to establish the pool by binding to the listening socket, and registering to the event poll.
         If this process fails, an error is returned.
     */
     pub fn connect(&mut self, poll_registry: &mut Poll) -> Result<(), ProxyError> {
+        // Pools without a listener are only reached through the routes of a listener.
+        let addr = match self.config.listen {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
         // Setup the server socket
-        let addr = self.config.listen;
         let server_socket = match TcpListener::bind(&addr) {
             Ok(soc) => soc,
             Err(err) => {
@@ -114,31 +184,7 @@ impl BackendPool {
         stats: &mut Stats,
     ) {
         match self.listen_socket {
-            Some(ref mut listener) => {
-                loop {
-                    let mut stream = match listener.accept() {
-                        Ok(s) => s.0,
-                        Err(e) => {
-                            if e.kind() == std::io::ErrorKind::WouldBlock {
-                                return;
-                            }
-                            panic!("Failed for some reason {:?}", e);
-                        }
-                    };
-                    let client_token = Token(*next_client_token_value);
-                    *next_client_token_value += 1;
-                    match poll.borrow_mut().register(&stream, client_token, Ready::readable(), PollOpt::edge()) {
-                        Ok(_) => {
-                            clients.insert(client_token.0, (BufReader::new(Client::new(stream)), self.token.0));
-                            stats.accepted_clients += 1;
-                            debug!("Backend Connection accepted: client {:?}", client_token);
-                        }
-                        Err(err) => {
-                            error!("Failed to register client token to poll: {:?}", err);
-                        }
-                    };
-                }
-            }
+            Some(ref mut listener) => accept_client_connections(listener, self.token, poll, next_client_token_value, clients, stats),
             None => {
                 error!("Listen socket is no more when accepting!");
                 return
@@ -147,48 +193,136 @@ impl BackendPool {
     }
 }
 
+/*
+    Accepts all pending connections on the listen socket. Each client is stored with the token of the pool or listener
+    that accepted it, which decides where its requests are routed.
+*/
+pub fn accept_client_connections(
+    listener: &mut TcpListener,
+    owner_token: Token,
+    poll: &Rc<RefCell<Poll>>,
+    next_client_token_value: &mut ClientTokenValue,
+    clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+    stats: &mut Stats,
+) {
+    loop {
+        let (stream, addr) = match listener.accept() {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    return;
+                }
+                panic!("Failed for some reason {:?}", e);
+            }
+        };
+        let client_token = Token(*next_client_token_value);
+        *next_client_token_value += 1;
+        match poll.borrow_mut().register(&stream, client_token, Ready::readable(), PollOpt::edge()) {
+            Ok(_) => {
+                clients.insert(client_token.0, (BufReader::new(Client::new(stream, addr)), owner_token.0));
+                stats.accepted_clients += 1;
+                debug!("Backend Connection accepted: client {:?}", client_token);
+            }
+            Err(err) => {
+                error!("Failed to register client token to poll: {:?}", err);
+                stats.rejected_clients += 1;
+            }
+        };
+    }
+}
+
 // Based on the given command, determine which Backend to use, if any.
 pub fn shard<'a>(
     cached_backend_shards: &mut Option<Vec<usize>>,
+    cached_shard_lookup: &mut Option<ShardLookup>,
     config: &BackendPoolConfig,
     backends: &'a mut [Backend],
     key: &[u8]) -> Result<&'a mut Backend, RedisError> {
+    let backend_index = if config.replicas > 1 {
+        // Reads go to the first available copy of the key.
+        let replica_indexes = try!(shard_replicas(cached_backend_shards, cached_shard_lookup, config, backends, key));
+        match replica_indexes.iter().find(|&&index| backends[index].is_available()) {
+            Some(&index) => index,
+            None => replica_indexes[0],
+        }
+    } else {
+        try!(shard_index(cached_backend_shards, cached_shard_lookup, config, backends, key))
+    };
+    return Ok(&mut backends[backend_index]);
+}
+
+/*
+    Returns the indexes of the backends holding the copies of the key, in a pool with replicas. The first one is the
+    backend that the key shards to. The others are the next distinct backends on the ring for the distributions with a
+    lookup, and the next backends in the order of the servers otherwise. Backends that can't receive keys are skipped.
+*/
+pub fn shard_replicas(
+    cached_backend_shards: &mut Option<Vec<usize>>,
+    cached_shard_lookup: &mut Option<ShardLookup>,
+    config: &BackendPoolConfig,
+    backends: &[Backend],
+    key: &[u8]) -> Result<Vec<usize>, RedisError> {
+    let first_index = try!(shard_index(cached_backend_shards, cached_shard_lookup, config, backends, key));
+    if uses_lookup(&config.distribution) {
+        let tag = get_tag(key, &config.hash_tag);
+        match cached_shard_lookup {
+            Some(lookup) => return Ok(lookup.dispatch_replicas(hash(&config.hash_function, &tag), config.replicas)),
+            None => { panic!("No cached shard lookup"); }
+        }
+    }
+    let mut indexes = Vec::with_capacity(config.replicas);
+    indexes.push(first_index);
+    for i in 1..backends.len() {
+        if indexes.len() == config.replicas {
+            break;
+        }
+        let index = (first_index + i) % backends.len();
+        if backends[index].weight > 0 && is_sharded(config, &backends[index]) {
+            indexes.push(index);
+        }
+    }
+    return Ok(indexes);
+}
+
+/*
+    Whether keys shard to the backend. Backends that were drained or disabled from the admin port never do, and with
+    auto_eject_hosts, neither do backends that are down.
+*/
+fn is_sharded(config: &BackendPoolConfig, backend: &Backend) -> bool {
+    return backend.mode == BackendMode::ENABLED && (!config.auto_eject_hosts || backend.is_available());
+}
+
+// Returns the index of the backend that the key shards to.
+fn shard_index(
+    cached_backend_shards: &mut Option<Vec<usize>>,
+    cached_shard_lookup: &mut Option<ShardLookup>,
+    config: &BackendPoolConfig,
+    backends: &[Backend],
+    key: &[u8]) -> Result<usize, RedisError> {
     let tag = get_tag(key, &config.hash_tag);
 
-    // How does the ConsistentHashing library work?
-    if config.distribution == Distribution::Ketama {
-        let mut consistent_hash = conhash::ConsistentHash::new();
+    // The lookup is only rebuilt when the backends that are available change, which also clears cached_backend_shards.
+    if uses_lookup(&config.distribution) && (cached_shard_lookup.is_none() || cached_backend_shards.is_none()) {
+        let mut servers = Vec::with_capacity(backends.len());
         let mut i = 0;
         for backend in backends.iter() {
-            // 40 is pulled to match twemproxy's ketama.
-            consistent_hash.add(&IndexNode{index: i}, backend.weight * 40);
-            //consistent_hash.add(&TokenNode {token: i.clone()}, backend.weight);
-            // TODO: Determine # of replicas. Most likely it's just the weight.
-            // TODO: Check if there's any discrepancy between this key and twemproxy.
-            i += 1;
-        }
-        let hashed_index = match consistent_hash.get(tag) {
-            Some(n) => n.index,
-            None => {
-                return Err(RedisError::NoBackend);
-            }
-        };
-        match backends.get_mut(hashed_index) {
-            Some(b) => {
-                return Ok(b);
-            }
-            None => {
-                error!("Consistent hashing hashed to a nonexistent backend! Index: {}. This should never happen. Please contact author.", hashed_index);
-                return Err(RedisError::NoBackend);
+            if is_sharded(config, backend) {
+                let name = match config.servers.get(i) {
+                    Some(backend_config) => server_name(backend_config, i),
+                    None => format!("{}", i),
+                };
+                servers.push((i, name, backend.weight));
             }
+            i += 1;
         }
+        *cached_shard_lookup = ShardLookup::new(&config.distribution, &servers);
     }
 
     if cached_backend_shards.is_none() {
         // Get total size:
         let mut total_weight = 0;
-        for ref mut backend in backends.iter_mut() {
-            if !config.auto_eject_hosts || backend.is_available() {
+        for backend in backends.iter() {
+            if is_sharded(config, backend) {
                 total_weight += backend.weight;
             }
         }
@@ -198,8 +332,8 @@ pub fn shard<'a>(
 
         let mut index = 0;
         let mut backend_index = 0;
-        for ref mut backend in backends.iter_mut() {
-            if !config.auto_eject_hosts || backend.is_available() {
+        for backend in backends.iter() {
+            if is_sharded(config, backend) {
                 for _i in index..index+backend.weight {
                     mapping.push(backend_index);
                 }
@@ -215,11 +349,57 @@ pub fn shard<'a>(
         Some(mapping) => mapping.len(),
         None => { panic!("No cached backend mapping"); }
     };
+    // Occurs when all backends have been ejected.
+    if total_weight == 0 {
+        return Err(RedisError::NoBackend);
+    }
+
+    if uses_lookup(&config.distribution) {
+        let hashed_index = match cached_shard_lookup {
+            Some(lookup) => match lookup.dispatch(hash(&config.hash_function, &tag)) {
+                Some(index) => index,
+                None => {
+                    return Err(RedisError::NoBackend);
+                }
+            },
+            None => { panic!("No cached shard lookup"); }
+        };
+        match backends.get(hashed_index) {
+            Some(_) => {
+                return Ok(hashed_index);
+            }
+            None => {
+                error!("Consistent hashing hashed to a nonexistent backend! Index: {}. This should never happen. Please contact author.", hashed_index);
+                return Err(RedisError::NoBackend);
+            }
+        }
+    }
 
     let shard: Result<usize, ProxyError> = match config.distribution {
         Distribution::Modula => Ok(hash(&config.hash_function, &tag) % total_weight), // Should be using key, not command.
-        Distribution::Random => Ok(thread_rng().gen_range(0, total_weight - 1)),
-        _ => panic!("Impossible to hit this with ketama!"),
+        Distribution::Random => Ok(thread_rng().gen_range(0, total_weight)),
+        Distribution::LeastOutstanding | Distribution::PowerOfTwoChoices => {
+            let mut rng = thread_rng();
+            let backend_index = if config.distribution == Distribution::LeastOutstanding {
+                let candidates = backends.iter().enumerate()
+                    .filter(|&(_, backend)| is_sharded(config, backend))
+                    .map(|(index, backend)| (index, backend.outstanding_requests(), backend.weight));
+                least_loaded_backend(candidates, &mut rng)
+            } else {
+                let mapping = match cached_backend_shards {
+                    Some(mapping) => mapping,
+                    None => { panic!("No cached backend mapping"); }
+                };
+                let choices = [mapping[rng.gen_range(0, total_weight)], mapping[rng.gen_range(0, total_weight)]];
+                least_loaded_backend(choices.iter().map(|&index| (index, backends[index].outstanding_requests(), backends[index].weight)), &mut rng)
+            };
+            match backend_index {
+                Some(backend_index) => return Ok(backend_index),
+                None => return Err(RedisError::NoBackend),
+            }
+        }
+        Distribution::JumpConsistent => Ok(jump_consistent_hash(hash(&config.hash_function, &tag) as u64, total_weight)),
+        _ => panic!("Impossible to hit this with a distribution that uses a lookup!"),
     };
     match shard {
         Ok(shard_no) => {
@@ -230,7 +410,7 @@ pub fn shard<'a>(
                     None => { panic!("No cached backend mapping when getting backend"); }
                 };
                 debug!("Now got index: {:?}", backend_index);
-                return Ok(backends.get_mut(*backend_index).unwrap());
+                return Ok(*backend_index);
             }
         }
         Err(error) => debug!("Received {:?} while sharding!", error),
@@ -282,6 +462,64 @@ fn get_tag<'a>(key: &'a [u8], tags: &String) -> &'a [u8] {
     return key;
 }
 
+/*
+    Whether a request is a write. Finding out parses the command name, so it is only done once a feature of the pool
+    that the request goes to depends on it: replicas, read_your_writes, mirror_mode, migrations and replicated backends.
+*/
+struct WriteCheck<'a> {
+    request: &'a [u8],
+    is_write: Option<bool>,
+}
+impl<'a> WriteCheck<'a> {
+    fn new(request: &'a [u8]) -> WriteCheck<'a> {
+        WriteCheck {
+            request: request,
+            is_write: None,
+        }
+    }
+
+    // For requests made up by the proxy, which are known to be reads or writes.
+    fn known(is_write: bool) -> WriteCheck<'static> {
+        WriteCheck {
+            request: b"",
+            is_write: Some(is_write),
+        }
+    }
+
+    fn is_write(&mut self) -> bool {
+        match self.is_write {
+            Some(is_write) => return is_write,
+            None => {}
+        }
+        let is_write = is_write_command(self.request);
+        self.is_write = Some(is_write);
+        return is_write;
+    }
+}
+
+// Records a write from the client, so that its following reads go to the master. See read_your_writes.
+fn record_write(client: &mut Client, read_your_writes: usize, now: Instant) {
+    if read_your_writes > 0 {
+        client.last_write = Some(now);
+    }
+}
+
+// Returns whether the client wrote recently enough that its reads should go to the master.
+fn is_pinned_to_master(client: &Client, read_your_writes: usize, now: Instant) -> bool {
+    match client.last_write {
+        Some(last_write) => now < last_write + Duration::from_millis(read_your_writes as u64),
+        None => false,
+    }
+}
+
+// Response sent to the client when its request could not be written to the backend.
+fn get_write_error_response(err: &WriteError) -> &'static [u8] {
+    match *err {
+        WriteError::CircuitOpen => b"-ERROR: Circuit open\r\n",
+        _ => b"-ERROR: Not connected\r\n",
+    }
+}
+
 pub fn handle_timeout(
     backend: &mut Backend,
     backend_token: BackendToken,
@@ -309,16 +547,512 @@ fn mark_backend_down(
     //}
 }
 
+/*
+    Routes the key to a pool with the router of the client, and shards it within that pool. Also returns the index of
+    that pool, and the request rewritten with the pool's key_prefix, if it has one. While none of the pool's backends
+    are available, the key is sharded within the pool's fallback_pool instead.
+*/
+fn route_and_shard<'a>(
+    backendpools: &mut Vec<BackendPool>,
+    router: &Router,
+    backends: &'a mut Vec<Backend>,
+    request: &[u8],
+    key: &[u8],
+    write_check: &mut WriteCheck,
+    can_fall_back: bool,
+    stats: &mut Stats,
+) -> Result<(&'a mut Backend, PoolIndex, Option<Vec<u8>>), RedisError> {
+    let pool_index = get_migration_target(backendpools, router.route(key), write_check, can_fall_back);
+    let pool_index = get_fallback_target(backendpools, backends, pool_index, stats);
+    let (backend, prefixed_request) = try!(shard_in_pool(backendpools, pool_index, backends, request, key));
+    return Ok((backend, pool_index, prefixed_request));
+}
+
+/*
+    Shards the key within the given pool. If the pool has a key_prefix, the key is sharded with the prefix, and the
+    request is returned with the prefix added to its keys.
+*/
+fn shard_in_pool<'a>(
+    backendpools: &mut Vec<BackendPool>,
+    pool_index: PoolIndex,
+    backends: &'a mut Vec<Backend>,
+    request: &[u8],
+    key: &[u8],
+) -> Result<(&'a mut Backend, Option<Vec<u8>>), RedisError> {
+    let num_pools = backendpools.len();
+    let backend_pool = match backendpools.get_mut(pool_index) {
+        Some(pool) => pool,
+        None => return Err(RedisError::NoBackend),
+    };
+    let start_backend_index = backend_pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools;
+    let last_index = start_backend_index + backend_pool.num_backends;
+    let pool_backends = match backends.get_mut(start_backend_index..last_index) {
+        Some(b) => b,
+        None => panic!("Unable to get full backends from {:?} to {:?}", start_backend_index, last_index),
+    };
+    let prefixed = match backend_pool.config.key_prefix {
+        Some(ref prefix) => Some((try!(add_key_prefix(request, prefix.as_bytes())), [prefix.as_bytes(), key].concat())),
+        None => None,
+    };
+    let backend = try!(shard(
+        &mut backend_pool.cached_backend_shards.borrow_mut(),
+        &mut backend_pool.cached_shard_lookup,
+        &backend_pool.config,
+        pool_backends,
+        match prefixed {
+            Some((_, ref prefixed_key)) => prefixed_key,
+            None => key,
+        }
+    ));
+    return Ok((backend, prefixed.map(|(prefixed_request, _)| prefixed_request)));
+}
+
+/*
+    Returns the indexes in backends of the copies of the key, for a pool with replicas, along with the request rewritten
+    with the pool's key_prefix, if it has one.
+*/
+fn shard_replicas_in_pool(
+    backendpools: &mut Vec<BackendPool>,
+    pool_index: PoolIndex,
+    backends: &mut Vec<Backend>,
+    request: &[u8],
+    key: &[u8],
+) -> Result<(Vec<usize>, Option<Vec<u8>>), RedisError> {
+    let num_pools = backendpools.len();
+    let backend_pool = match backendpools.get_mut(pool_index) {
+        Some(pool) => pool,
+        None => return Err(RedisError::NoBackend),
+    };
+    let start_backend_index = backend_pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools;
+    let last_index = start_backend_index + backend_pool.num_backends;
+    let pool_backends = match backends.get(start_backend_index..last_index) {
+        Some(b) => b,
+        None => panic!("Unable to get full backends from {:?} to {:?}", start_backend_index, last_index),
+    };
+    let prefixed = match backend_pool.config.key_prefix {
+        Some(ref prefix) => Some((try!(add_key_prefix(request, prefix.as_bytes())), [prefix.as_bytes(), key].concat())),
+        None => None,
+    };
+    let replica_indexes = try!(shard_replicas(
+        &mut backend_pool.cached_backend_shards.borrow_mut(),
+        &mut backend_pool.cached_shard_lookup,
+        &backend_pool.config,
+        pool_backends,
+        match prefixed {
+            Some((_, ref prefixed_key)) => prefixed_key,
+            None => key,
+        }
+    ));
+    let replica_indexes = replica_indexes.iter().map(|index| start_backend_index + index).collect();
+    return Ok((replica_indexes, prefixed.map(|(prefixed_request, _)| prefixed_request)));
+}
+
+// Returns the number of backends that each key of the pool is stored on.
+fn get_replicas(backendpools: &Vec<BackendPool>, pool_index: PoolIndex) -> usize {
+    match backendpools.get(pool_index) {
+        Some(pool) => pool.config.replicas,
+        None => 1,
+    }
+}
+
+/*
+    Writes a request to every copy of the key, in a pool with replicas. The replies are combined into the one reply to
+    the client by ReplicatedWrite, following the pool's replica_ack. Copies that can't be written to count as failed
+    replies, and an error is only returned if no copy could be written to. An id other than REPLICATED_WRITE_ID is the
+    position of a split MSET write, whose reply goes into the client's pending_response once it is decided.
+*/
+fn write_replicated(
+    backendpools: &mut Vec<BackendPool>,
+    pool_index: PoolIndex,
+    client: &mut Client,
+    client_token: ClientToken,
+    backends: &mut Vec<Backend>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    request: &[u8],
+    key: &[u8],
+    instant: Instant,
+    id: usize,
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    stats: &mut Stats,
+) -> Result<(), WriteError> {
+    let (replica_indexes, prefixed_request) = match shard_replicas_in_pool(backendpools, pool_index, backends, request, key) {
+        Ok(res) => res,
+        Err(_) => return Err(WriteError::BackendNotReady),
+    };
+    let request = match prefixed_request {
+        Some(ref prefixed_request) => prefixed_request,
+        None => request,
+    };
+    let num_copies = replica_indexes.len();
+    let needed_acks = match backendpools[pool_index].config.replica_ack {
+        ReplicaAck::First => 1,
+        ReplicaAck::All => num_copies,
+        ReplicaAck::Quorum => num_copies / 2 + 1,
+    };
+    set_replicated_write(client, id, Some(ReplicatedWrite::new(needed_acks, num_copies)));
+    let mut failures = Vec::new();
+    for backend_index in replica_indexes {
+        match backends[backend_index].write_message(request, client_token, cluster_backends, (instant, id), stats) {
+            Ok(_) => {}
+            Err(err) => {
+                debug!("Replica could not be written to. Received error: {}", err);
+                failures.push(err);
+            }
+        }
+    }
+    if failures.len() == num_copies {
+        set_replicated_write(client, id, None);
+        return Err(failures.pop().unwrap());
+    }
+    for err in failures.iter() {
+        try!(write_to_client(client, &client_token.0, get_write_error_response(err), (instant, id), completed_clients, stats));
+    }
+    return Ok(());
+}
+
+// Sets or clears the replicated write of the request with the given id. A split MSET write waits for a reply from each
+// of its copies, instead of the one reply it is counted for in pending_count.
+fn set_replicated_write(client: &mut Client, id: usize, replicated_write: Option<ReplicatedWrite>) {
+    if id == REPLICATED_WRITE_ID {
+        client.pending_replicated_write = replicated_write;
+        return;
+    }
+    match client.pending_replicated_parts[id - 1].take() {
+        Some(previous) => client.pending_count -= previous.remaining - 1,
+        None => {}
+    }
+    match replicated_write {
+        Some(ref replicated_write) => client.pending_count += replicated_write.remaining - 1,
+        None => {}
+    }
+    client.pending_replicated_parts[id - 1] = replicated_write;
+}
+
+/*
+    Returns the fallback pool of the given pool while none of the pool's backends are available, otherwise the pool
+    itself. Only one fallback is followed, so that fallback pools pointing at each other can't loop.
+*/
+fn get_fallback_target(backendpools: &Vec<BackendPool>, backends: &Vec<Backend>, pool_index: PoolIndex, stats: &mut Stats) -> PoolIndex {
+    let pool = match backendpools.get(pool_index) {
+        Some(pool) => pool,
+        None => return pool_index,
+    };
+    let fallback_pool_index = match pool.fallback_pool_index {
+        Some(fallback_pool_index) => fallback_pool_index,
+        None => return pool_index,
+    };
+    let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - backendpools.len();
+    let pool_backends = &backends[start_backend_index..start_backend_index + pool.num_backends];
+    if pool_backends.iter().any(|backend| backend.is_available()) {
+        return pool_index;
+    }
+    stats.pool_fallbacks += 1;
+    return fallback_pool_index;
+}
+
+/*
+    Returns the pool that a request to the given pool goes to. Requests to a pool that is being migrated to go to the
+    old pool, until the migration lets them go to the new one. Reads that can't fall back to the old pool on a miss wait
+    for the cut over.
+*/
+fn get_migration_target(backendpools: &Vec<BackendPool>, pool_index: PoolIndex, write_check: &mut WriteCheck, can_fall_back: bool) -> PoolIndex {
+    match get_migration(backendpools, pool_index) {
+        Some((old_pool_index, MigrationState::Off)) => old_pool_index,
+        Some((old_pool_index, MigrationState::DualWrite)) if !can_fall_back && !write_check.is_write() => old_pool_index,
+        _ => pool_index,
+    }
+}
+
+/*
+    Sends a KEYS or SCAN to the pool, with its pattern limited to the pool's key_prefix. The prefix is stripped from the
+    keys in the reply. Only pools with a key_prefix and a single backend support these, since all the keys under the
+    prefix are then on that backend. Returns the error response for the client if the request can't be sent.
+*/
+fn write_keyspace_request(
+    backendpools: &mut Vec<BackendPool>,
+    pool_index: PoolIndex,
+    client: &mut Client,
+    client_token: ClientToken,
+    backends: &mut Vec<Backend>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    request: &[u8],
+    instant: Instant,
+    stats: &mut Stats,
+) -> Result<(), &'static [u8]> {
+    let num_pools = backendpools.len();
+    let (prefix, backend_index, read_your_writes) = match backendpools.get(pool_index) {
+        Some(pool) => {
+            match pool.config.key_prefix {
+                Some(ref prefix) if pool.config.servers.len() == 1 && !pool.config.servers[0].use_cluster => {
+                    (prefix.as_bytes().to_vec(), pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools, pool.config.read_your_writes)
+                }
+                _ => return Err(b"-ERROR: KEYS and SCAN need a pool with a key_prefix and one backend\r\n"),
+            }
+        }
+        None => return Err(b"-ERROR: No backend\r\n"),
+    };
+    let prefixed_request = match add_key_prefix(request, &prefix) {
+        Ok(prefixed_request) => prefixed_request,
+        Err(_) => return Err(b"-ERROR: Invalid redis protocol\r\n"),
+    };
+    let backend = match backends.get_mut(backend_index) {
+        Some(backend) => backend,
+        None => return Err(b"-ERROR: No backend\r\n"),
+    };
+    let res = if is_pinned_to_master(client, read_your_writes, instant) {
+        backend.write_message(&prefixed_request, client_token, cluster_backends, (instant, STRIP_KEY_PREFIX_ID), stats)
+    } else {
+        backend.write_read_message(&prefixed_request, client_token, cluster_backends, (instant, STRIP_KEY_PREFIX_ID), stats)
+    };
+    match res {
+        Ok(_) => {
+            // Wait for the reply before handling more requests, so that it can be told apart.
+            client.pending_key_strip = Some(prefix);
+            return Ok(());
+        }
+        Err(err) => {
+            debug!("Backend could not be written to. Received error: {}", err);
+            return Err(get_write_error_response(&err));
+        }
+    }
+}
+
+// Returns the pool migrated from and the state of the migration, if the pool is being migrated to.
+fn get_migration(backendpools: &Vec<BackendPool>, pool_index: PoolIndex) -> Option<(PoolIndex, MigrationState)> {
+    match backendpools.get(pool_index) {
+        Some(pool) => pool.migrate_from_pool_index.map(|old_pool_index| (old_pool_index, pool.migration_state)),
+        None => None,
+    }
+}
+
+// Returns the pool that writes to the given pool are also copied to, while it is being migrated to.
+fn get_migration_copy_pool(backendpools: &Vec<BackendPool>, pool_index: PoolIndex) -> Option<PoolIndex> {
+    match get_migration(backendpools, pool_index) {
+        Some((old_pool_index, MigrationState::DualWrite)) | Some((old_pool_index, MigrationState::CutOver)) => Some(old_pool_index),
+        _ => None,
+    }
+}
+
+// Returns the read_your_writes setting of the pool.
+fn get_read_your_writes(backendpools: &Vec<BackendPool>, pool_index: PoolIndex) -> usize {
+    match backendpools.get(pool_index) {
+        Some(pool) => pool.config.read_your_writes,
+        None => 0,
+    }
+}
+
+/*
+    Copies a request to the mirror pool of the pool it was sent to, if it has one and the request is picked by
+    mirror_mode and mirror_percentage. Nothing is returned to the client for it, and failures are only counted.
+*/
+fn mirror_request(
+    backendpools: &mut Vec<BackendPool>,
+    backends: &mut Vec<Backend>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    pool_index: PoolIndex,
+    key: &[u8],
+    message: &[u8],
+    write_check: &mut WriteCheck,
+    instant: Instant,
+    stats: &mut Stats,
+) {
+    let mirror_pool_index = match backendpools.get(pool_index) {
+        Some(pool) => {
+            if pool.mirror_pool_index.is_some() && pool.config.mirror_mode == MirrorMode::Writes && !write_check.is_write() {
+                return;
+            }
+            if pool.config.mirror_percentage < 100 && thread_rng().gen_range(0, 100) >= pool.config.mirror_percentage {
+                return;
+            }
+            match pool.mirror_pool_index {
+                Some(mirror_pool_index) => mirror_pool_index,
+                None => return,
+            }
+        }
+        None => return,
+    };
+    if write_discarded(backendpools, backends, cluster_backends, mirror_pool_index, key, message, instant, stats) {
+        stats.mirrored_requests += 1;
+    } else {
+        stats.mirror_failures += 1;
+    }
+}
+
+// Writes a request to the given pool, discarding its response. Returns whether the request could be written.
+fn write_discarded(
+    backendpools: &mut Vec<BackendPool>,
+    backends: &mut Vec<Backend>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    pool_index: PoolIndex,
+    key: &[u8],
+    message: &[u8],
+    instant: Instant,
+    stats: &mut Stats,
+) -> bool {
+    match shard_in_pool(backendpools, pool_index, backends, message, key) {
+        Ok((backend, prefixed_message)) => {
+            let message = match prefixed_message {
+                Some(ref prefixed_message) => prefixed_message,
+                None => message,
+            };
+            match backend.write_message(message, MIRROR_TOKEN, cluster_backends, (instant, 0), stats) {
+                Ok(_) => return true,
+                Err(err) => {
+                    debug!("Backend could not be written to for a discarded request. Received error: {}", err);
+                    return false;
+                }
+            }
+        }
+        Err(_) => return false,
+    }
+}
+
+/*
+    Moves a migration read of the client along, once the new pool has missed, or the old pool has answered with a value
+    to back-fill. Returns whether the client can send its next request.
+*/
+fn continue_migration_read(
+    backendpools: &mut Vec<BackendPool>,
+    client: &mut Client,
+    client_token: ClientToken,
+    backends: &mut Vec<Backend>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    stats: &mut Stats,
+) -> Result<bool, WriteError> {
+    let mut pending_fallback = match client.pending_fallback.take() {
+        Some(pending_fallback) => pending_fallback,
+        None => return Ok(true),
+    };
+    let instant = Instant::now();
+    match pending_fallback.state {
+        FallbackState::WaitingForNew | FallbackState::WaitingForOld => {
+            client.pending_fallback = Some(pending_fallback);
+            return Ok(false);
+        }
+        FallbackState::NewMissed => {
+            let err_resp: &[u8] = match shard_in_pool(backendpools, pending_fallback.old_pool_index, backends, &pending_fallback.request, &pending_fallback.key) {
+                Ok((backend, prefixed_request)) => {
+                    let request = match prefixed_request {
+                        Some(ref prefixed_request) => prefixed_request,
+                        None => &pending_fallback.request,
+                    };
+                    match backend.write_message(request, client_token, cluster_backends, (instant, MIGRATION_FALLBACK_ID), stats) {
+                        Ok(_) => {
+                            stats.migration_fallbacks += 1;
+                            pending_fallback.state = FallbackState::WaitingForOld;
+                            client.pending_fallback = Some(pending_fallback);
+                            return Ok(false);
+                        }
+                        Err(err) => {
+                            debug!("Old pool could not be written to for a migration read. Received error: {}", err);
+                            get_write_error_response(&err)
+                        }
+                    }
+                }
+                Err(_) => b"-ERROR: No backend\r\n",
+            };
+            try!(write_to_client(client, &client_token.0, err_resp, (instant, 0), completed_clients, stats));
+            return Ok(true);
+        }
+        FallbackState::Backfill(ref value) => {
+            // NX, so that a write that reached the new pool since the read isn't overwritten.
+            let key = &pending_fallback.key;
+            let mut backfill_msg: Vec<u8> = Vec::with_capacity(40 + key.len() + value.len());
+            backfill_msg.extend_from_slice(b"*4\r\n$3\r\nSET\r\n$");
+            backfill_msg.extend_from_slice(&key.len().to_string().as_bytes());
+            backfill_msg.extend_from_slice(b"\r\n");
+            backfill_msg.extend_from_slice(key);
+            backfill_msg.extend_from_slice(b"\r\n");
+            backfill_msg.extend_from_slice(value);
+            backfill_msg.extend_from_slice(b"$2\r\nNX\r\n");
+            if write_discarded(backendpools, backends, cluster_backends, pending_fallback.new_pool_index, key, &backfill_msg, instant, stats) {
+                stats.migration_backfills += 1;
+            }
+            return Ok(true);
+        }
+    }
+}
+
+/*
+    Shows the request on the admin MONITOR, when an admin client is monitoring. The request is shown as the client sent
+    it, along with the backend that it went to.
+*/
+fn monitor_request(
+    monitor: &mut Option<Monitor>,
+    backendpools: &Vec<BackendPool>,
+    pool_index: PoolIndex,
+    backend: &Backend,
+    cluster_backends: &Vec<(SingleBackend, usize)>,
+    client: &Client,
+    key: &[u8],
+    request: &[u8],
+) {
+    match *monitor {
+        Some(ref mut monitor) if monitor.wants(pool_index, key) => {
+            monitor.record(client, &backendpools[pool_index].name, &backend.get_address(cluster_backends), request);
+        }
+        _ => {}
+    }
+}
+
+/*
+    Answers the CLIENT commands that a client sends to the proxy. Only SETNAME and GETNAME are supported, since the
+    client's connections to the backends are shared with other clients. Names show up in the admin CLIENT LIST.
+*/
+fn handle_client_command(client: &mut Client, request: &[u8]) -> Vec<u8> {
+    let args = match split_request(request) {
+        Ok(args) => args,
+        Err(_) => return b"-ERROR: Invalid redis protocol\r\n".to_vec(),
+    };
+    let subcommand = args.get(1).map(|arg| arg.to_ascii_uppercase());
+    match (subcommand.as_ref().map(|arg| arg.as_slice()), args.len()) {
+        (Some(b"SETNAME"), 3) => {
+            // Like redis, names can't have spaces, which would break the lines of CLIENT LIST.
+            if args[2].iter().any(|&c| c <= b' ' || c > b'~') {
+                return b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n".to_vec();
+            }
+            client.name = match args[2].len() {
+                0 => None,
+                _ => Some(String::from_utf8_lossy(args[2]).into_owned()),
+            };
+            return b"+OK\r\n".to_vec();
+        }
+        (Some(b"GETNAME"), 2) => {
+            match client.name {
+                Some(ref name) => return format!("${}\r\n{}\r\n", name.len(), name).into_bytes(),
+                None => return b"$-1\r\n".to_vec(),
+            }
+        }
+        _ => return b"-ERROR: Unsupported command\r\n".to_vec(),
+    }
+}
+
 pub fn handle_client_readable(
-    backend_pool: &mut BackendPool,
+    backendpools: &mut Vec<BackendPool>,
+    router: &Router,
     client: &mut BufferedClient,
     client_token: ClientToken,
-    backends: &mut [Backend],
+    backends: &mut Vec<Backend>,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
+    monitor: &mut Option<Monitor>,
 ) -> bool {
     debug!("Handling client: {:?}", &client_token);
+    match continue_migration_read(backendpools, &mut client.inner, client_token, backends, cluster_backends, completed_clients, stats) {
+        Ok(true) => {}
+        Ok(false) => return true,
+        Err(_) => return false,
+    }
+    // Wait for the replies to a KEYS, SCAN or replicated write before handling more requests.
+    if client.inner.pending_key_strip.is_some() || client.inner.pending_replicated_write.is_some() {
+        return true;
+    }
+    let enable_advanced_commands = match backendpools.get(router.default_pool_index) {
+        Some(pool) => pool.enable_advanced_commands,
+        None => false,
+    };
 
     // 1. Pull command from client.
     let buf_len = loop {
@@ -349,30 +1083,78 @@ pub fn handle_client_readable(
                 debug!("Extracted from client:\n{:?}", std::str::from_utf8(&client_request));
                 if client_request.len() > 0 {
                     stats.requests += 1;
+                    match get_command_name(&client_request) {
+                        Ok(command) => {
+                            client.inner.last_command.clear();
+                            client.inner.last_command.extend_from_slice(command);
+                        }
+                        Err(_) => {}
+                    }
                     match extract_key(&client_request) {
                         Ok(KeyPos::Single(key)) => {
-                            let backend = shard(
-                                &mut backend_pool.cached_backend_shards.borrow_mut(),
-                                &mut backend_pool.config,
-                                backends,
-                                key
-                            ).unwrap();
-                            match backend.write_message(
-                                &client_request,
-                                client_token,
-                                cluster_backends,
-                                (instant, id),
-                                stats
-                            ) {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    debug!("Backend could not be written to. Received error: {}", err);
-                                    err_resp = Some(b"-ERROR: Not connected\r\n");
+                            let mut write_check = WriteCheck::new(&client_request);
+                            let mut routed_pool_index = None;
+                            match route_and_shard(backendpools, router, backends, &client_request, key, &mut write_check, true, stats) {
+                                Ok((backend, pool_index, prefixed_request)) => {
+                                    routed_pool_index = Some(pool_index);
+                                    monitor_request(monitor, backendpools, pool_index, backend, cluster_backends, &client.inner, key, client_request);
+                                    let request = match prefixed_request {
+                                        Some(ref prefixed_request) => prefixed_request,
+                                        None => client_request,
+                                    };
+                                    let read_your_writes = get_read_your_writes(backendpools, pool_index);
+                                    if read_your_writes > 0 && write_check.is_write() {
+                                        record_write(&mut client.inner, read_your_writes, instant);
+                                    }
+                                    match get_migration(backendpools, pool_index) {
+                                        Some((old_pool_index, MigrationState::DualWrite)) if !write_check.is_write() => {
+                                            // The new pool may not have the key yet. Wait for its reply before handling more requests.
+                                            id = MIGRATION_READ_ID;
+                                            client.inner.pending_fallback = Some(PendingFallback {
+                                                request: client_request.to_vec(),
+                                                key: key.to_vec(),
+                                                old_pool_index: old_pool_index,
+                                                new_pool_index: pool_index,
+                                                backfill: backendpools[pool_index].config.migration_backfill && is_get_command(&client_request),
+                                                state: FallbackState::WaitingForNew,
+                                            });
+                                        }
+                                        _ => {}
+                                    }
+                                    let res = if get_replicas(backendpools, pool_index) > 1 && write_check.is_write() {
+                                        write_replicated(backendpools, pool_index, &mut client.inner, client_token, backends, cluster_backends, client_request, key, instant, REPLICATED_WRITE_ID, completed_clients, stats)
+                                    } else if !backend.has_read_replicas() || write_check.is_write() || is_pinned_to_master(&client.inner, read_your_writes, instant) {
+                                        backend.write_message(request, client_token, cluster_backends, (instant, id), stats)
+                                    } else {
+                                        backend.write_read_message(request, client_token, cluster_backends, (instant, id), stats)
+                                    };
+                                    match res {
+                                        Ok(_) => {}
+                                        Err(err) => {
+                                            debug!("Backend could not be written to. Received error: {}", err);
+                                            err_resp = Some(get_write_error_response(&err));
+                                        }
+                                    };
+                                }
+                                Err(_) => {
+                                    err_resp = Some(b"-ERROR: No backend\r\n");
                                 }
-                            };
+                            }
+                            match routed_pool_index {
+                                Some(pool_index) => {
+                                    match get_migration_copy_pool(backendpools, pool_index) {
+                                        Some(old_pool_index) if write_check.is_write() => {
+                                            write_discarded(backendpools, backends, cluster_backends, old_pool_index, key, &client_request, instant, stats);
+                                        }
+                                        _ => {}
+                                    }
+                                    mirror_request(backendpools, backends, cluster_backends, pool_index, key, &client_request, &mut write_check, instant, stats);
+                                }
+                                None => {}
+                            }
                         }
                         Ok(KeyPos::Multi(vec)) => {
-                            if !backend_pool.enable_advanced_commands {
+                            if !enable_advanced_commands {
                                 err_resp = Some(b"-ProxyError: Advanced commands are currently disabled. They can be enabled by setting 'enable_advanced_commands' to true in the proxy config\r\n");
                             } else {
                                 client.inner.pending_response = Vec::new();
@@ -381,12 +1163,6 @@ pub fn handle_client_readable(
                                     id += 1;
                                     client.inner.pending_response.push(Vec::new());
 
-                                    let backend = shard(
-                                        &mut backend_pool.cached_backend_shards.borrow_mut(),
-                                        &mut backend_pool.config,
-                                        backends,
-                                        key
-                                    ).unwrap();
                                     let mut split_msg : Vec<u8> = Vec::with_capacity(25 + key.len());
                                     split_msg.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$");
                                     split_msg.extend_from_slice(&key.len().to_string().as_bytes());
@@ -394,17 +1170,37 @@ pub fn handle_client_readable(
                                     split_msg.extend_from_slice(key);
                                     split_msg.extend_from_slice(b"\r\n");
 
-                                    match backend.write_message(
-                                        &split_msg,
-                                        client_token,
-                                        cluster_backends,
-                                        (instant, id),
-                                        stats
-                                    ) {
+                                    let (backend, pool_index, prefixed_msg) = match route_and_shard(backendpools, router, backends, &split_msg, key, &mut WriteCheck::known(false), false, stats) {
+                                        Ok(res) => res,
+                                        Err(_) => {
+                                            if write_to_client(
+                                                &mut client.inner,
+                                                &client_token.0,
+                                                b"-ERROR: No backend\r\n",
+                                                (instant, id),
+                                                completed_clients,
+                                                stats
+                                            ).is_err() {
+                                                return false;
+                                            };
+                                            continue;
+                                        }
+                                    };
+                                    monitor_request(monitor, backendpools, pool_index, backend, cluster_backends, &client.inner, key, &split_msg);
+                                    let request = match prefixed_msg {
+                                        Some(ref prefixed_msg) => prefixed_msg,
+                                        None => &split_msg,
+                                    };
+                                    let res = if is_pinned_to_master(&client.inner, get_read_your_writes(backendpools, pool_index), instant) {
+                                        backend.write_message(request, client_token, cluster_backends, (instant, id), stats)
+                                    } else {
+                                        backend.write_read_message(request, client_token, cluster_backends, (instant, id), stats)
+                                    };
+                                    match res {
                                         Ok(_) => {}
                                         Err(err) => {
                                             debug!("Backend could not be written to when splitting. Received error: {}", err);
-                                            let resp = b"-ERROR: Not connected\r\n";
+                                            let resp = get_write_error_response(&err);
                                             if write_to_client(
                                                 &mut client.inner,
                                                 &client_token.0,
@@ -417,25 +1213,22 @@ pub fn handle_client_readable(
                                             };
                                         }
                                     };
+                                    mirror_request(backendpools, backends, cluster_backends, pool_index, key, &split_msg, &mut WriteCheck::known(false), instant, stats);
                                 }
                             }
                         }
                         Ok(KeyPos::MultiSet(vec)) => {
-                            if !backend_pool.enable_advanced_commands {
+                            if !enable_advanced_commands {
                                 err_resp = Some(b"-ProxyError: Advanced commands are currently disabled. They can be enabled by setting 'enable_advanced_commands' to true in the proxy config\r\n");
                             } else {
                                 client.inner.pending_response = Vec::new();
                                 client.inner.pending_count = vec.len();
+                                client.inner.pending_replicated_parts = Vec::new();
                                 for (key, args) in vec.iter() {
                                     id += 1;
                                     client.inner.pending_response.push(Vec::new());
+                                    client.inner.pending_replicated_parts.push(None);
 
-                                    let backend = shard(
-                                        &mut backend_pool.cached_backend_shards.borrow_mut(),
-                                        &mut backend_pool.config,
-                                        backends,
-                                        key
-                                    ).unwrap();
                                     let mut split_msg : Vec<u8> = Vec::with_capacity(35 + key.len() + args.len());
                                     split_msg.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$");
                                     split_msg.extend_from_slice(&key.len().to_string().as_bytes());
@@ -447,21 +1240,47 @@ pub fn handle_client_readable(
                                     split_msg.extend_from_slice(args);
                                     split_msg.extend_from_slice(b"\r\n");
 
-                                    match backend.write_message(
-                                        &split_msg,
-                                        client_token,
-                                        cluster_backends,
-                                        (instant, id),
-                                        stats
-                                    ) {
+                                    let (backend, pool_index, prefixed_msg) = match route_and_shard(backendpools, router, backends, &split_msg, key, &mut WriteCheck::known(true), false, stats) {
+                                        Ok(res) => res,
+                                        Err(_) => {
+                                            if write_to_client(
+                                                &mut client.inner,
+                                                &client_token.0,
+                                                b"-ERROR: No backend\r\n",
+                                                (instant, id),
+                                                completed_clients,
+                                                stats
+                                            ).is_err() {
+                                                return false;
+                                            };
+                                            continue;
+                                        }
+                                    };
+                                    monitor_request(monitor, backendpools, pool_index, backend, cluster_backends, &client.inner, key, &split_msg);
+                                    record_write(&mut client.inner, get_read_your_writes(backendpools, pool_index), instant);
+                                    let res = if get_replicas(backendpools, pool_index) > 1 {
+                                        write_replicated(backendpools, pool_index, &mut client.inner, client_token, backends, cluster_backends, &split_msg, key, instant, id, completed_clients, stats)
+                                    } else {
+                                        backend.write_message(
+                                            match prefixed_msg {
+                                                Some(ref prefixed_msg) => prefixed_msg,
+                                                None => &split_msg,
+                                            },
+                                            client_token,
+                                            cluster_backends,
+                                            (instant, id),
+                                            stats
+                                        )
+                                    };
+                                    match res {
                                         Ok(_) => {}
                                         Err(err) => {
                                             debug!("Backend could not be written to when splitting. Received error: {}", err);
-                                            let resp = b"-ERROR: Not connected\r\n".to_vec();
+                                            let resp = get_write_error_response(&err);
                                             if write_to_client(
                                                 &mut client.inner,
                                                 &client_token.0,
-                                                &resp,
+                                                resp,
                                                 (instant, id),
                                                 completed_clients,
                                                 stats
@@ -470,9 +1289,35 @@ pub fn handle_client_readable(
                                             };
                                         }
                                     };
+                                    match get_migration_copy_pool(backendpools, pool_index) {
+                                        Some(old_pool_index) => {
+                                            write_discarded(backendpools, backends, cluster_backends, old_pool_index, key, &split_msg, instant, stats);
+                                        }
+                                        None => {}
+                                    }
+                                    mirror_request(backendpools, backends, cluster_backends, pool_index, key, &split_msg, &mut WriteCheck::known(true), instant, stats);
                                 }
                             }
                         }
+                        Ok(KeyPos::Keyspace) => {
+                            let pool_index = get_migration_target(backendpools, router.default_pool_index, &mut WriteCheck::known(false), false);
+                            match write_keyspace_request(backendpools, pool_index, &mut client.inner, client_token, backends, cluster_backends, &client_request, instant, stats) {
+                                Ok(_) => {
+                                    if monitor.is_some() {
+                                        // Pools that take KEYS and SCAN have a single backend.
+                                        let backend_index = backendpools[pool_index].first_backend_index - FIRST_SOCKET_INDEX - backendpools.len();
+                                        monitor_request(monitor, backendpools, pool_index, &backends[backend_index], cluster_backends, &client.inner, b"", &client_request);
+                                    }
+                                }
+                                Err(resp) => err_resp = Some(resp),
+                            }
+                        }
+                        Ok(KeyPos::Proxy) => {
+                            let reply = handle_client_command(&mut client.inner, &client_request);
+                            if write_to_client(&mut client.inner, &client_token.0, &reply, (instant, id), completed_clients, stats).is_err() {
+                                return false;
+                            }
+                        }
                         Err(RedisError::NoBackend) => {
                             err_resp = Some(b"-ERROR: No backend\r\n");
                         }
@@ -497,12 +1342,15 @@ pub fn handle_client_readable(
                         }
                     };
                 }
-                let more_buf = buf.len() > client_request.len() && client.inner.pending_count == 0;
+                let more_buf = buf.len() > client_request.len() && client.inner.pending_count == 0 && client.inner.pending_fallback.is_none() &&
+                    client.inner.pending_key_strip.is_none() && client.inner.pending_replicated_write.is_none();
                 (consumed_len, err_resp, more_buf)
             }
         };
         client.consume(buf_len);
         stats.recv_client_bytes += buf_len;
+        client.inner.bytes_in += buf_len;
+        client.inner.last_active = instant;
 
 
         match err_resp {
@@ -0,0 +1,90 @@
+use config::BackendPoolConfig;
+use rand::thread_rng;
+use rand::Rng;
+use std::cmp::min;
+
+/*
+    Reconnect policy for a backend.
+    Each failed reconnect attempt multiplies the delay before the next attempt, up to a maximum. With jitter enabled,
+    the actual delay is picked uniformly between 0 and the current delay (full jitter), so that many proxies do not
+    reconnect to a flapping backend at the same time.
+*/
+#[derive(Clone)]
+pub struct RetryBackoff {
+    initial_timeout: usize,
+    multiplier: usize,
+    max_timeout: usize,
+    jitter: bool,
+
+    // Number of reconnect attempts since the backend was last ready.
+    pub attempts: usize,
+    // Delay in ms before the next reconnect attempt, before jitter is applied.
+    pub current_timeout: usize,
+    // The delay in ms that was scheduled for the last reconnect attempt.
+    pub last_delay: usize,
+}
+
+impl RetryBackoff {
+    pub fn from_config(config: &BackendPoolConfig) -> RetryBackoff {
+        RetryBackoff {
+            initial_timeout: config.retry_timeout,
+            multiplier: config.retry_backoff_multiplier,
+            max_timeout: config.retry_max_timeout,
+            jitter: config.retry_jitter,
+            attempts: 0,
+            current_timeout: config.retry_timeout,
+            last_delay: 0,
+        }
+    }
+
+    // Returns the delay before the next reconnect attempt, and backs off the delay for the attempt after it.
+    pub fn next_delay(&mut self) -> usize {
+        let delay = if self.jitter && self.current_timeout > 0 {
+            thread_rng().gen_range(0, self.current_timeout + 1)
+        } else {
+            self.current_timeout
+        };
+        self.attempts += 1;
+        self.last_delay = delay;
+        self.current_timeout = min(self.current_timeout.saturating_mul(self.multiplier), self.max_timeout);
+        return delay;
+    }
+
+    // Called once the backend is ready again.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.current_timeout = self.initial_timeout;
+        self.last_delay = 0;
+    }
+}
+
+#[test]
+fn test_retry_backoff() {
+    let mut backoff = RetryBackoff {
+        initial_timeout: 100,
+        multiplier: 2,
+        max_timeout: 500,
+        jitter: false,
+        attempts: 0,
+        current_timeout: 100,
+        last_delay: 0,
+    };
+    assert_eq!(backoff.next_delay(), 100);
+    assert_eq!(backoff.next_delay(), 200);
+    assert_eq!(backoff.next_delay(), 400);
+    assert_eq!(backoff.next_delay(), 500);
+    assert_eq!(backoff.next_delay(), 500);
+    assert_eq!(backoff.attempts, 5);
+    backoff.reset();
+    assert_eq!(backoff.attempts, 0);
+    assert_eq!(backoff.last_delay, 0);
+    assert_eq!(backoff.next_delay(), 100);
+
+    backoff.reset();
+    backoff.jitter = true;
+    for _ in 0..10 {
+        let current_timeout = backoff.current_timeout;
+        assert!(backoff.next_delay() <= current_timeout);
+    }
+    assert_eq!(backoff.current_timeout, 500);
+}
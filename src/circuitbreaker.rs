@@ -0,0 +1,208 @@
+use config::BackendPoolConfig;
+use stats::Stats;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    // Requests flow normally.
+    Closed,
+    // Requests fail fast, without being sent to the backend.
+    Open,
+    // A limited number of probe requests are let through to check if the backend has recovered.
+    HalfOpen,
+}
+
+/*
+    Circuit breaker for a single backend.
+    Keeps a rolling window of request outcomes. A request fails if it times out, can't be written, or takes longer
+    than the latency threshold. Once the window is full and the ratio of failures reaches the error rate, the circuit
+    opens. After the open timeout, the circuit becomes half-open and lets probe requests through. If all probes
+    succeed, the circuit closes, otherwise it opens again.
+*/
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    window: usize,
+    // Percentage of failed requests in the window that opens the circuit.
+    error_rate: usize,
+    // Responses slower than this (in ms) count as failures. 0 disables the latency check.
+    latency_threshold: usize,
+    // How long (in ms) the circuit stays open before probing the backend.
+    pub open_timeout: usize,
+    half_open_probes: usize,
+
+    // Rolling window of outcomes. true means that the request failed.
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    pub state: CircuitState,
+    opened_at: Instant,
+    probes_sent: usize,
+    probes_succeeded: usize,
+}
+
+impl CircuitBreaker {
+    // Returns None if the circuit breaker is disabled for the pool.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<CircuitBreaker> {
+        if config.circuit_breaker_window == 0 {
+            return None;
+        }
+        Some(CircuitBreaker {
+            window: config.circuit_breaker_window,
+            error_rate: config.circuit_breaker_error_rate,
+            latency_threshold: config.circuit_breaker_latency_threshold,
+            open_timeout: config.circuit_breaker_open_timeout,
+            half_open_probes: config.circuit_breaker_half_open_probes,
+            outcomes: VecDeque::with_capacity(config.circuit_breaker_window + 1),
+            failures: 0,
+            state: CircuitState::Closed,
+            opened_at: Instant::now(),
+            probes_sent: 0,
+            probes_succeeded: 0,
+        })
+    }
+
+    // Returns whether a request can be sent to the backend.
+    pub fn allow_request(&mut self, stats: &mut Stats) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen if self.probes_sent < self.half_open_probes => {
+                self.probes_sent += 1;
+                true
+            }
+            _ => {
+                stats.circuit_breaker_rejected += 1;
+                false
+            }
+        }
+    }
+
+    /*
+        Returns whether the backend can take requests from sharding. A half-open circuit only does until its probes
+        are sent, so that the rest of its keys keep going to other backends as while it is open.
+    */
+    pub fn is_available(&self) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => self.probes_sent < self.half_open_probes,
+            CircuitState::Open => false,
+        }
+    }
+
+    // Records a response from the backend, along with how long it took.
+    pub fn record_response(&mut self, latency: Duration, stats: &mut Stats) {
+        let failed = self.latency_threshold > 0 && latency > Duration::from_millis(self.latency_threshold as u64);
+        self.record(failed, stats);
+    }
+
+    pub fn record_failure(&mut self, stats: &mut Stats) {
+        self.record(true, stats);
+    }
+
+    fn record(&mut self, failed: bool, stats: &mut Stats) {
+        match self.state {
+            CircuitState::Closed => {
+                self.outcomes.push_back(failed);
+                if failed {
+                    self.failures += 1;
+                }
+                if self.outcomes.len() > self.window {
+                    if self.outcomes.pop_front() == Some(true) {
+                        self.failures -= 1;
+                    }
+                }
+                if self.outcomes.len() == self.window && self.failures * 100 >= self.error_rate * self.window {
+                    self.open(stats);
+                }
+            }
+            CircuitState::HalfOpen => {
+                if failed {
+                    self.open(stats);
+                } else {
+                    self.probes_succeeded += 1;
+                    if self.probes_succeeded >= self.half_open_probes {
+                        self.close(stats);
+                    }
+                }
+            }
+            // Responses to requests sent before the circuit opened are ignored.
+            CircuitState::Open => {}
+        }
+    }
+
+    // Lets probe requests through if the circuit has been open for longer than the open timeout.
+    pub fn half_open_if_expired(&mut self, now: Instant, stats: &mut Stats) {
+        if self.state != CircuitState::Open || now < self.opened_at + Duration::from_millis(self.open_timeout as u64) {
+            return;
+        }
+        self.state = CircuitState::HalfOpen;
+        self.probes_sent = 0;
+        self.probes_succeeded = 0;
+        stats.circuit_breaker_half_opened += 1;
+    }
+
+    fn open(&mut self, stats: &mut Stats) {
+        self.state = CircuitState::Open;
+        self.opened_at = Instant::now();
+        stats.circuit_breaker_opened += 1;
+    }
+
+    fn close(&mut self, stats: &mut Stats) {
+        self.state = CircuitState::Closed;
+        self.outcomes.clear();
+        self.failures = 0;
+        stats.circuit_breaker_closed += 1;
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let mut stats = Stats::new();
+    let mut breaker = CircuitBreaker {
+        window: 4,
+        error_rate: 50,
+        latency_threshold: 10,
+        open_timeout: 0,
+        half_open_probes: 2,
+        outcomes: VecDeque::new(),
+        failures: 0,
+        state: CircuitState::Closed,
+        opened_at: Instant::now(),
+        probes_sent: 0,
+        probes_succeeded: 0,
+    };
+
+    // The circuit only opens once the window is full.
+    breaker.record_failure(&mut stats);
+    breaker.record_response(Duration::from_millis(20), &mut stats);
+    breaker.record_response(Duration::from_millis(1), &mut stats);
+    assert_eq!(breaker.state, CircuitState::Closed);
+    breaker.record_response(Duration::from_millis(1), &mut stats);
+    assert_eq!(breaker.state, CircuitState::Open);
+    assert!(!breaker.is_available());
+    assert!(!breaker.allow_request(&mut stats));
+    assert_eq!(stats.circuit_breaker_rejected, 1);
+
+    // A failed probe opens the circuit again.
+    breaker.half_open_if_expired(Instant::now(), &mut stats);
+    assert!(breaker.allow_request(&mut stats));
+    breaker.record_failure(&mut stats);
+    assert_eq!(breaker.state, CircuitState::Open);
+
+    // All probes have to succeed to close the circuit. Only that many probes are let through, and the backend is
+    // unavailable to sharding once they are sent.
+    breaker.half_open_if_expired(Instant::now(), &mut stats);
+    assert!(breaker.is_available());
+    assert!(breaker.allow_request(&mut stats));
+    assert!(breaker.allow_request(&mut stats));
+    assert!(!breaker.is_available());
+    assert!(!breaker.allow_request(&mut stats));
+    breaker.record_response(Duration::from_millis(1), &mut stats);
+    assert_eq!(breaker.state, CircuitState::HalfOpen);
+    breaker.record_response(Duration::from_millis(1), &mut stats);
+    assert_eq!(breaker.state, CircuitState::Closed);
+    assert!(breaker.is_available());
+
+    assert_eq!(stats.circuit_breaker_opened, 2);
+    assert_eq!(stats.circuit_breaker_half_opened, 2);
+    assert_eq!(stats.circuit_breaker_closed, 1);
+}
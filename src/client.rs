@@ -1,6 +1,8 @@
 use std::io::Read;
+use std::net::SocketAddr;
 use mio::net::TcpStream;
 use bufreader::BufReader;
+use std::time::Instant;
 
 pub struct Client {
     pub stream: TcpStream,
@@ -8,14 +10,141 @@ pub struct Client {
     pub pending_response: Vec<Vec<u8>>,
     // Remaining number of responses needed for multikey request. 0 means that no multikey request is inflight.
     pub pending_count: usize,
+    // Time of the last write command sent by the client. Used to send its reads to the master right after a write.
+    pub last_write: Option<Instant>,
+    // Read of a migrating pool that may still need to go to the old pool. The client's next requests wait for it.
+    pub pending_fallback: Option<PendingFallback>,
+    // Key prefix to strip from the reply to a KEYS or SCAN. The client's next requests wait for the reply.
+    pub pending_key_strip: Option<Vec<u8>>,
+    // Write sent to every copy of a key. The client's next requests wait for all the copies to reply.
+    pub pending_replicated_write: Option<ReplicatedWrite>,
+    // Writes of a split MSET sent to every copy of their key, by position in pending_response. Each copy's reply counts
+    // towards pending_count.
+    pub pending_replicated_parts: Vec<Option<ReplicatedWrite>>,
+
+    // What the admin CLIENT LIST command shows about the connection.
+    pub addr: SocketAddr,
+    pub created: Instant,
+    pub last_active: Instant,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub last_command: Vec<u8>,
+    // Set by the client with CLIENT SETNAME.
+    pub name: Option<String>,
+}
+
+/*
+    A read sent to the new pool of a migration. If the new pool misses, the read is sent to the old pool, and with
+    back-fill enabled, a GET's value is then written to the new pool.
+*/
+pub struct PendingFallback {
+    pub request: Vec<u8>,
+    pub key: Vec<u8>,
+    pub old_pool_index: usize,
+    pub new_pool_index: usize,
+    pub backfill: bool,
+    pub state: FallbackState,
+}
+
+/*
+    A write sent to each backend holding a copy of the key, in a pool with replicas. The client gets a single reply: the
+    first successful reply once needed_acks copies succeeded, or the first error once that can't happen anymore.
+*/
+pub struct ReplicatedWrite {
+    pub needed_acks: usize,
+    pub remaining: usize,
+    acks: usize,
+    replied: bool,
+    reply: Option<Vec<u8>>,
+    error: Option<Vec<u8>>,
+}
+impl ReplicatedWrite {
+    pub fn new(needed_acks: usize, num_copies: usize) -> ReplicatedWrite {
+        ReplicatedWrite {
+            needed_acks: needed_acks,
+            remaining: num_copies,
+            acks: 0,
+            replied: false,
+            reply: None,
+            error: None,
+        }
+    }
+
+    // Records the reply of one copy. Returns the reply for the client, once it is decided.
+    pub fn record_reply(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        self.remaining -= 1;
+        if message.get(0) == Some(&b'-') {
+            if self.error.is_none() {
+                self.error = Some(message.to_vec());
+            }
+        } else {
+            self.acks += 1;
+            if self.reply.is_none() {
+                self.reply = Some(message.to_vec());
+            }
+        }
+        if self.replied {
+            return None;
+        }
+        if self.acks >= self.needed_acks {
+            self.replied = true;
+            return self.reply.take();
+        }
+        if self.acks + self.remaining < self.needed_acks {
+            self.replied = true;
+            return self.error.take();
+        }
+        return None;
+    }
+}
+
+#[test]
+fn test_replicated_write() {
+    // Quorum of 3.
+    let mut write = ReplicatedWrite::new(2, 3);
+    assert_eq!(write.record_reply(b"+OK\r\n"), None);
+    assert_eq!(write.record_reply(b"-ERROR: Not connected\r\n"), None);
+    assert_eq!(write.record_reply(b"+OK\r\n"), Some(b"+OK\r\n".to_vec()));
+    assert_eq!(write.remaining, 0);
+
+    // All of 2, where the first copy fails.
+    let mut write = ReplicatedWrite::new(2, 2);
+    assert_eq!(write.record_reply(b"-ERROR: Not connected\r\n"), Some(b"-ERROR: Not connected\r\n".to_vec()));
+    assert_eq!(write.record_reply(b"+OK\r\n"), None);
+
+    // First of 2 replies once.
+    let mut write = ReplicatedWrite::new(1, 2);
+    assert_eq!(write.record_reply(b"+OK\r\n"), Some(b"+OK\r\n".to_vec()));
+    assert_eq!(write.record_reply(b"+OK\r\n"), None);
+}
+
+pub enum FallbackState {
+    WaitingForNew,
+    NewMissed,
+    WaitingForOld,
+    // Holds the reply of the old pool, a bulk string to write to the new pool.
+    Backfill(Vec<u8>),
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    pub fn new(stream: TcpStream, addr: SocketAddr) -> Client {
+        let now = Instant::now();
         Client {
             stream: stream,
             pending_response: Vec::new(),
             pending_count: 0,
+            last_write: None,
+            pending_fallback: None,
+            pending_key_strip: None,
+            pending_replicated_write: None,
+            pending_replicated_parts: Vec::new(),
+            addr: addr,
+            created: now,
+            last_active: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            last_command: Vec::new(),
+            name: None,
         }
     }
 }
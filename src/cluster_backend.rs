@@ -10,6 +10,7 @@ use redflareproxy::convert_token_to_cluster_index;
 use redflareproxy::{BackendToken, ClientToken, NULL_TOKEN};
 use backend::{BackendStatus, SingleBackend};
 use config::BackendConfig;
+use backoff::RetryBackoff;
 use std::collections::{VecDeque};
 use hashbrown::HashMap;
 use crc16::*;
@@ -28,12 +29,12 @@ pub struct ClusterBackend {
     status: BackendStatus,
     config: BackendConfig,
     token: BackendToken,
-    queue: VecDeque<(ClientToken, Instant, usize)>,
+    queue: VecDeque<(ClientToken, Instant, usize, usize)>,
     pool_token: PoolTokenValue,
     // Following are stored for future backend connections that can be established.
     timeout: usize,
     failure_limit: usize,
-    retry_timeout: usize,
+    retry_backoff: RetryBackoff,
     poll_registry: Rc<RefCell<Poll>>,
     num_backends: usize,
     waiting_for_slotsmap_resp: bool,
@@ -48,7 +49,7 @@ impl ClusterBackend {
         next_cluster_token_value: &mut usize,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_backoff: RetryBackoff,
         pool_token: usize,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
@@ -63,7 +64,7 @@ impl ClusterBackend {
             pool_token: pool_token,
             timeout: timeout,
             failure_limit: failure_limit,
-            retry_timeout: retry_timeout,
+            retry_backoff: retry_backoff,
             poll_registry: Rc::clone(poll_registry),
             num_backends: num_backends,
             waiting_for_slotsmap_resp: false,
@@ -85,7 +86,7 @@ impl ClusterBackend {
                 poll_registry,
                 timeout,
                 failure_limit,
-                retry_timeout,
+                cluster.retry_backoff.clone(),
                 pool_token,
                 num_backends,
                 &cluster.cached_backend_shards,
@@ -125,6 +126,21 @@ impl ClusterBackend {
         return self.status == BackendStatus::READY;
     }
 
+    // Describes each host connection of the cluster, along with its role.
+    pub fn describe_hosts<'a, T>(&'a self, cluster_backends: &'a Vec<(SingleBackend, usize)>, describe: &dyn Fn(&str, &'a SingleBackend) -> T) -> Vec<T> {
+        let mut info = Vec::with_capacity(self.hostnames.len());
+        for backend_token in self.hostnames.values() {
+            let cluster_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(cluster_index) {
+                Some((backend, _)) => info.push(describe("node", backend)),
+                None => {
+                    panic!("ClusterBackend is referencing a Backend that does not exist! Occurred when getting backoff info.");
+                }
+            };
+        }
+        return info;
+    }
+
     pub fn init_connection(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
         for backend_token in self.hostnames.values() {
             let client_index = convert_token_to_cluster_index(backend_token.0);
@@ -298,7 +314,7 @@ impl ClusterBackend {
 }
 
 fn initialize_slotmap(
-    queue: &mut VecDeque<(ClientToken, Instant, usize)>,
+    queue: &mut VecDeque<(ClientToken, Instant, usize, usize)>,
     backend_token: BackendToken,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     stats: &mut Stats,
@@ -371,7 +387,7 @@ fn handle_unhandled_response(
                     &cluster.poll_registry,
                     cluster.timeout,
                     cluster.failure_limit,
-                    cluster.retry_timeout,
+                    &cluster.retry_backoff,
                     cluster.pool_token,
                     cluster.num_backends,
                     &cluster.cached_backend_shards,
@@ -408,7 +424,7 @@ fn initialize_host(
     poll_registry: &Rc<RefCell<Poll>>,
     timeout: usize,
     failure_limit: usize,
-    retry_timeout: usize,
+    retry_backoff: &RetryBackoff,
     pool_token: PoolTokenValue,
     num_backends: usize,
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
@@ -425,7 +441,7 @@ fn initialize_host(
             poll_registry,
             timeout,
             failure_limit,
-            retry_timeout,
+            retry_backoff.clone(),
             pool_token,
             num_backends,
             cached_backend_shards,
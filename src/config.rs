@@ -4,40 +4,213 @@ use toml;
 use std::fs::File;
 use std::io::{Read};
 use hash::HashFunction;
+use distribution::is_load_based;
 use redflareproxy::ProxyError;
+use regex::bytes::Regex;
 
-#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+/*
+    The toml crate can neither read nor write enums, so the enums of the config are read and written as the string
+    given for each variant.
+*/
+macro_rules! config_enum_strings {
+    ($name:ident { $($variant:ident => $string:tt,)* }) => {
+        impl ::serde::Serialize for $name {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(match *self {
+                    $($name::$variant => $string,)*
+                })
+            }
+        }
+        impl ::serde::Deserialize for $name {
+            fn deserialize<D: ::serde::Deserializer>(deserializer: D) -> Result<$name, D::Error> {
+                let value = try!(String::deserialize(deserializer));
+                match value.as_str() {
+                    $($string => Ok($name::$variant),)*
+                    _ => Err(::serde::de::Error::custom(format!("unknown {} '{}'", stringify!($name), value))),
+                }
+            }
+        }
+        impl $name {
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $($name::$variant => $string,)*
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub enum Distribution {
     Modula,
     Ketama,
     Random,
+    JumpConsistent,
+    Rendezvous,
+    Maglev,
+    // Any backend can serve any key, such as replicas of the same dataset. The backend with the fewest outstanding
+    // requests for its weight is picked, either out of all of them, or out of two picked at random.
+    LeastOutstanding,
+    PowerOfTwoChoices,
+}
+config_enum_strings!(Distribution {
+    Modula => "Modula",
+    Ketama => "Ketama",
+    Random => "Random",
+    JumpConsistent => "JumpConsistent",
+    Rendezvous => "Rendezvous",
+    Maglev => "Maglev",
+    LeastOutstanding => "LeastOutstanding",
+    PowerOfTwoChoices => "PowerOfTwoChoices",
+});
+
+// Which requests are copied to the mirror pool.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum MirrorMode {
+    All,
+    Writes,
+}
+config_enum_strings!(MirrorMode {
+    All => "All",
+    Writes => "Writes",
+});
+
+// How many copies of a replicated write must succeed before the client is answered.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum ReplicaAck {
+    First,
+    All,
+    Quorum,
+}
+config_enum_strings!(ReplicaAck {
+    First => "First",
+    All => "All",
+    Quorum => "Quorum",
+});
+
+// How read commands are spread across the replicas of a backend.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum ReadBalance {
+    RoundRobin,
+    Random,
 }
+config_enum_strings!(ReadBalance {
+    RoundRobin => "RoundRobin",
+    Random => "Random",
+});
 
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
 pub struct RedFlareProxyConfig {
     pub admin: AdminConfig,
     pub pools: BTreeMap<String, BackendPoolConfig>,
 
+    // Listeners that route each key to one of the pools, by the rules in ListenerConfig.
+    #[serde(default)]
+    pub listeners: BTreeMap<String, ListenerConfig>,
+
     #[serde(default)]
     pub enable_advanced_commands: bool,
+
+    // HTTP listener serving /metrics for Prometheus.
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+
+    // Pushes the same metrics to a StatsD server.
+    #[serde(default)]
+    pub statsd: Option<StatsdConfig>,
+}
+
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct ListenerConfig {
+    pub listen: SocketAddr,
+
+    // Routes are checked in order, and the key goes to the pool of the first route that matches it.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+
+    // Pool for keys that match no route.
+    pub default_pool: String,
+}
+
+// Exactly one of prefix, glob or regex must be set.
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct RouteConfig {
+    #[serde(default)]
+    pub prefix: Option<String>,
+
+    // Supports '*' for any number of characters, '?' for a single character, and '\' to escape them.
+    #[serde(default)]
+    pub glob: Option<String>,
+
+    #[serde(default)]
+    pub regex: Option<String>,
+
+    pub pool: String,
 }
 
 fn default_retry_timeout() -> usize {
     return 1000;
 }
+fn default_retry_backoff_multiplier() -> usize {
+    return 1;
+}
+fn default_retry_max_timeout() -> usize {
+    return 30000;
+}
+fn default_circuit_breaker_error_rate() -> usize {
+    return 50;
+}
+fn default_circuit_breaker_open_timeout() -> usize {
+    return 5000;
+}
+fn default_circuit_breaker_half_open_probes() -> usize {
+    return 1;
+}
 fn default_distribution() -> Distribution {
     return Distribution::Modula;
 }
 fn default_hash_function() -> HashFunction {
     return HashFunction::Fnv1a64;
 }
+fn default_read_balance() -> ReadBalance {
+    return ReadBalance::RoundRobin;
+}
+fn default_mirror_mode() -> MirrorMode {
+    return MirrorMode::All;
+}
+fn default_mirror_percentage() -> usize {
+    return 100;
+}
+fn default_replicas() -> usize {
+    return 1;
+}
+fn default_replica_ack() -> ReplicaAck {
+    return ReplicaAck::First;
+}
 fn default_warm_sockets() -> bool {
     return true;
 }
+fn default_health_check_command() -> String {
+    return "PING".to_owned();
+}
+fn default_health_check_expected_reply() -> String {
+    return "PONG".to_owned();
+}
+fn default_health_check_threshold() -> usize {
+    return 1;
+}
+fn default_statsd_interval() -> usize {
+    return 10000;
+}
+fn default_statsd_prefix() -> String {
+    return "redflareproxy".to_owned();
+}
 
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
 pub struct BackendPoolConfig {
-    pub listen: SocketAddr,
+    // Pools without a listener only receive keys routed to them by a listener.
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
 
     pub servers: Vec<BackendConfig>,
 
@@ -47,9 +220,22 @@ pub struct BackendPoolConfig {
     #[serde(default)]
     pub failure_limit: usize,
 
+    // Delay in ms before the first reconnect attempt to a failed backend.
     #[serde(default = "default_retry_timeout")]
     pub retry_timeout: usize,
 
+    // Each failed reconnect attempt multiplies the delay by this integer factor, up to retry_max_timeout. Must be at
+    // least 1, where 1 keeps the delay constant.
+    #[serde(default = "default_retry_backoff_multiplier")]
+    pub retry_backoff_multiplier: usize,
+
+    #[serde(default = "default_retry_max_timeout")]
+    pub retry_max_timeout: usize,
+
+    // Picks a random delay between 0 and the current delay, to spread out reconnects from many proxies.
+    #[serde(default)]
+    pub retry_jitter: bool,
+
     #[serde(default)]
     pub auto_eject_hosts: bool,
 
@@ -64,12 +250,103 @@ pub struct BackendPoolConfig {
 
     #[serde(default = "default_warm_sockets")]
     pub warm_sockets: bool,
+
+    // Interval in ms between active health checks to each backend. 0 disables health checks.
+    #[serde(default)]
+    pub health_check_interval: usize,
+
+    #[serde(default = "default_health_check_command")]
+    pub health_check_command: String,
+
+    // A reply is considered healthy if it is not an error, and contains this string.
+    #[serde(default = "default_health_check_expected_reply")]
+    pub health_check_expected_reply: String,
+
+    // Number of consecutive successful checks before a backend marked down by a health check is used again.
+    #[serde(default = "default_health_check_threshold")]
+    pub health_check_healthy_threshold: usize,
+
+    // Number of consecutive failed checks before a backend is marked down.
+    #[serde(default = "default_health_check_threshold")]
+    pub health_check_unhealthy_threshold: usize,
+
+    // Number of recent requests per backend tracked by the circuit breaker. 0 disables the circuit breaker.
+    #[serde(default)]
+    pub circuit_breaker_window: usize,
+
+    // Percentage of failed requests in the window that opens the circuit.
+    #[serde(default = "default_circuit_breaker_error_rate")]
+    pub circuit_breaker_error_rate: usize,
+
+    // Responses slower than this many ms count as failures. 0 only counts timeouts and write failures.
+    #[serde(default)]
+    pub circuit_breaker_latency_threshold: usize,
+
+    // How long in ms an open circuit fails requests before letting probe requests through.
+    #[serde(default = "default_circuit_breaker_open_timeout")]
+    pub circuit_breaker_open_timeout: usize,
+
+    #[serde(default = "default_circuit_breaker_half_open_probes")]
+    pub circuit_breaker_half_open_probes: usize,
+
+    #[serde(default = "default_read_balance")]
+    pub read_balance: ReadBalance,
+
+    // After a client writes, its reads go to the master for this many ms, so that it reads its own writes even if the
+    // replicas lag behind. 0 disables this.
+    #[serde(default)]
+    pub read_your_writes: usize,
+
+    // Name of a pool that requests to this pool are copied to. Its responses are discarded, and its failures don't
+    // affect this pool.
+    #[serde(default)]
+    pub mirror: Option<String>,
+
+    #[serde(default = "default_mirror_mode")]
+    pub mirror_mode: MirrorMode,
+
+    // Percentage of the requests matching mirror_mode that are copied.
+    #[serde(default = "default_mirror_percentage")]
+    pub mirror_percentage: usize,
+
+    // Name of the pool that this pool's keys are being migrated from. The migration is driven from the admin port with
+    // MIGRATION, and until it is started, requests still go to the old pool.
+    #[serde(default)]
+    pub migrate_from: Option<String>,
+
+    // Whether values read from the old pool during a migration are written to this pool.
+    #[serde(default)]
+    pub migration_backfill: bool,
+
+    // Prefix added to every key sent to this pool, and stripped from the keys returned by KEYS and SCAN. Lets several
+    // tenants share the same backends.
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+
+    // Number of backends that each key is stored on: the backend the key shards to, followed by the next distinct
+    // backends on the ring, or in the order of the servers. Writes go to all of them, and reads to the first available
+    // one.
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+
+    // Which replies of a replicated write decide the reply to the client. First replies once a copy succeeds, All once
+    // every copy succeeded, and Quorum once a majority did. Otherwise an error reply is returned.
+    #[serde(default = "default_replica_ack")]
+    pub replica_ack: ReplicaAck,
+
+    // Name of a pool that requests are sent to instead, while none of this pool's backends are available.
+    #[serde(default)]
+    pub fallback_pool: Option<String>,
 }
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
 pub struct BackendConfig {
     #[serde(default)]
     pub host: Option<SocketAddr>,
 
+    // Name of the server on the ketama continuum. Defaults to "host:port", like twemproxy.
+    #[serde(default)]
+    pub name: Option<String>,
+
     // How to handle RedisCluster list of hosts?
 
     pub weight: usize,
@@ -88,6 +365,20 @@ pub struct BackendConfig {
 
     #[serde(default)]
     pub cluster_hosts: Vec<SocketAddr>,
+
+    // Used for master/replica sets managed by redis sentinel.
+    #[serde(default)]
+    pub use_sentinel: bool,
+
+    #[serde(default)]
+    pub sentinel_hosts: Vec<SocketAddr>,
+
+    #[serde(default)]
+    pub sentinel_master_name: Option<String>,
+
+    // Replicas of the host. Read commands are sent to them, and write commands to the host.
+    #[serde(default)]
+    pub replicas: Vec<SocketAddr>,
 }
 
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
@@ -95,6 +386,37 @@ pub struct AdminConfig {
     pub listen: String,
 }
 
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
+pub struct MetricsConfig {
+    pub listen: String,
+}
+
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
+pub struct StatsdConfig {
+    // Address of the StatsD server, which metrics are sent to over UDP.
+    pub address: SocketAddr,
+
+    // Milliseconds between each push.
+    #[serde(default = "default_statsd_interval")]
+    pub interval: usize,
+
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+
+    // Sends the pool, backend and command as DogStatsD tags. Otherwise they are part of the metric names.
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+
+/*
+    The config as TOML, such as to write it back to its file. It goes through toml::Value, which puts the plain values of
+    each table before its subtables, as TOML requires.
+*/
+pub fn config_to_toml(config: &RedFlareProxyConfig) -> Result<String, toml::ser::Error> {
+    let value = try!(toml::Value::try_from(config));
+    return toml::to_string(&value);
+}
+
 pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, ProxyError> {
     // TOOD: trim config_path
     let config_path = full_config_path.trim();
@@ -119,9 +441,127 @@ pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, Prox
         }
     };
 
+    match config.statsd {
+        Some(ref statsd) if statsd.interval == 0 => {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'interval' of statsd must be more than 0. {}", config_path))));
+        }
+        _ => {}
+    }
+
+    for (ref listener_name, ref listener_config) in &config.listeners {
+        if !config.pools.contains_key(&listener_config.default_pool) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'default_pool' {} of listener {} does not exist. {}", listener_config.default_pool, listener_name, config_path))));
+        }
+        for ref route in &listener_config.routes {
+            if !config.pools.contains_key(&route.pool) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Pool {} of a route in listener {} does not exist. {}", route.pool, listener_name, config_path))));
+            }
+            let num_rules = route.prefix.iter().count() + route.glob.iter().count() + route.regex.iter().count();
+            if num_rules != 1 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Route to pool {} in listener {} must have exactly one of 'prefix', 'glob' or 'regex'. {}", route.pool, listener_name, config_path))));
+            }
+            match route.regex {
+                Some(ref regex) => {
+                    match Regex::new(regex) {
+                        Ok(_) => {}
+                        Err(err) => {
+                            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Invalid regex {} in listener {}: {}. {}", regex, listener_name, err, config_path))));
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
     // Verify that cluster-associated configs should only be used when use_cluster is true, and verify that host is there when use_cluster is false.
     for (ref pool_name, ref pool_config) in &config.pools {
+        if pool_config.health_check_interval > 0 && pool_config.health_check_command.trim().len() == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'health_check_command' cannot be empty when health checks are enabled in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.retry_backoff_multiplier == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'retry_backoff_multiplier' must be at least 1 in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.retry_max_timeout < pool_config.retry_timeout {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'retry_max_timeout' cannot be lower than 'retry_timeout' in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.circuit_breaker_window > 0 {
+            if pool_config.circuit_breaker_error_rate == 0 || pool_config.circuit_breaker_error_rate > 100 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'circuit_breaker_error_rate' must be between 1 and 100 in pool {}. {}", pool_name, config_path))));
+            }
+            if pool_config.circuit_breaker_half_open_probes == 0 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'circuit_breaker_half_open_probes' must be at least 1 in pool {}. {}", pool_name, config_path))));
+            }
+        }
+        match pool_config.mirror {
+            Some(ref mirror) => {
+                if mirror == *pool_name || !config.pools.contains_key(mirror) {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'mirror' {} of pool {} must be another existing pool. {}", mirror, pool_name, config_path))));
+                }
+                if pool_config.mirror_percentage == 0 || pool_config.mirror_percentage > 100 {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'mirror_percentage' must be between 1 and 100 in pool {}. {}", pool_name, config_path))));
+                }
+            }
+            None => {}
+        }
+        if pool_config.replicas == 0 || pool_config.replicas > pool_config.servers.len() {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'replicas' must be between 1 and the number of servers in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.replicas > 1 {
+            if pool_config.distribution == Distribution::Random || is_load_based(&pool_config.distribution) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'replicas' needs a distribution that shards by key in pool {}. {}", pool_name, config_path))));
+            }
+            if pool_config.servers.iter().any(|backend_config| backend_config.use_cluster) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'replicas' cannot be used with cluster backends in pool {}. {}", pool_name, config_path))));
+            }
+        }
+        if is_load_based(&pool_config.distribution) && pool_config.servers.iter().any(|backend_config| backend_config.use_cluster) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("LeastOutstanding and PowerOfTwoChoices cannot be used with cluster backends in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.key_prefix == Some(String::new()) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'key_prefix' cannot be empty in pool {}. {}", pool_name, config_path))));
+        }
+        match pool_config.migrate_from {
+            Some(ref old_pool) => {
+                if old_pool == *pool_name || !config.pools.contains_key(old_pool) {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'migrate_from' {} of pool {} must be another existing pool. {}", old_pool, pool_name, config_path))));
+                }
+            }
+            None => {}
+        }
+        match pool_config.fallback_pool {
+            Some(ref fallback_pool) => {
+                if fallback_pool == *pool_name || !config.pools.contains_key(fallback_pool) {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'fallback_pool' {} of pool {} must be another existing pool. {}", fallback_pool, pool_name, config_path))));
+                }
+            }
+            None => {}
+        }
         for ref backend_config in &pool_config.servers {
+            if backend_config.replicas.len() > 0 && (backend_config.use_sentinel || backend_config.use_cluster) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Only backends with a 'host' can have 'replicas' in pool {}. {}", pool_name, config_path))));
+            }
+            if backend_config.use_sentinel {
+                if backend_config.use_cluster {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Backend cannot use both cluster and sentinel in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.host.is_some() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend cannot have a 'host' in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.cluster_hosts.len() > 0 || backend_config.cluster_name.is_some() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend cannot have any cluster configs in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.sentinel_hosts.len() == 0 {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend requires 'sentinel_hosts' in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.sentinel_master_name.is_none() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend requires a 'sentinel_master_name' in pool {}. {}", pool_name, config_path))));
+                }
+                continue;
+            }
+            if backend_config.sentinel_hosts.len() > 0 || backend_config.sentinel_master_name.is_some() {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-sentinel backend cannot have any sentinel configs in pool {}. {}", pool_name, config_path))));
+            }
             if !backend_config.use_cluster {
                 if backend_config.host.is_none() {
                     return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-cluster backend requires a 'host' in pool {}. {}", pool_name, config_path))));
@@ -148,4 +588,12 @@ pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, Prox
     }
     
     Ok(config)
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_config_to_toml() {
+    let config = load_config("tests/conf/poolstats1.toml".to_owned()).unwrap();
+    let text = config_to_toml(&config).unwrap();
+    let parsed: RedFlareProxyConfig = toml::from_str(&text).unwrap();
+    assert!(parsed == config);
+}
@@ -0,0 +1,388 @@
+use config::Distribution;
+use hash::{hash, HashFunction};
+use ketama::KetamaContinuum;
+use rand::Rng;
+use std;
+
+// Number of slots in the Maglev lookup table. Should be a prime, well above 100 times the number of backends.
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+/*
+    Precomputed lookup structures for the distributions that need more than the weight mapping in
+    cached_backend_shards. Built once per membership change, from the (backend index, name, weight) of each backend in
+    the pool that can receive keys.
+*/
+pub enum ShardLookup {
+    Ketama(KetamaContinuum),
+    Rendezvous(RendezvousTable),
+    Maglev(MaglevTable),
+}
+impl ShardLookup {
+    // Returns None for distributions that only need cached_backend_shards.
+    pub fn new(distribution: &Distribution, servers: &[(usize, String, usize)]) -> Option<ShardLookup> {
+        match distribution {
+            Distribution::Ketama => Some(ShardLookup::Ketama(KetamaContinuum::new(servers))),
+            Distribution::Rendezvous => Some(ShardLookup::Rendezvous(RendezvousTable::new(servers))),
+            Distribution::Maglev => Some(ShardLookup::Maglev(MaglevTable::new(servers))),
+            _ => None,
+        }
+    }
+
+    // Returns the backend index for the given key hash, or None if no backend can receive keys.
+    pub fn dispatch(&self, hash: usize) -> Option<usize> {
+        match self {
+            ShardLookup::Ketama(continuum) => continuum.dispatch(hash as u32),
+            ShardLookup::Rendezvous(table) => table.dispatch(hash),
+            ShardLookup::Maglev(table) => table.dispatch(hash),
+        }
+    }
+
+    // Returns up to num_replicas distinct backend indexes for the key hash, the first one being the one of dispatch.
+    pub fn dispatch_replicas(&self, hash: usize, num_replicas: usize) -> Vec<usize> {
+        match self {
+            ShardLookup::Ketama(continuum) => continuum.dispatch_replicas(hash as u32, num_replicas),
+            ShardLookup::Rendezvous(table) => table.dispatch_replicas(hash, num_replicas),
+            ShardLookup::Maglev(table) => table.dispatch_replicas(hash, num_replicas),
+        }
+    }
+}
+
+pub fn uses_lookup(distribution: &Distribution) -> bool {
+    match distribution {
+        Distribution::Ketama | Distribution::Rendezvous | Distribution::Maglev => true,
+        _ => false,
+    }
+}
+
+// Distributions that pick a backend by its load, rather than by the key.
+pub fn is_load_based(distribution: &Distribution) -> bool {
+    match distribution {
+        Distribution::LeastOutstanding | Distribution::PowerOfTwoChoices => true,
+        _ => false,
+    }
+}
+
+/*
+    Jump consistent hash. See https://arxiv.org/abs/1406.2510
+    Maps the key hash to one of num_buckets buckets. Growing the bucket count only moves keys into the new buckets, but
+    removing a bucket other than the last one moves most keys, so this suits pools that only grow or shrink at the end.
+*/
+pub fn jump_consistent_hash(key_hash: u64, num_buckets: usize) -> usize {
+    let mut key = key_hash;
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    return b as usize;
+}
+
+/*
+    Picks the backend with the fewest outstanding requests for its weight, out of the given (backend index, outstanding
+    requests, weight) candidates. Ties are broken at random, in proportion to weight, so that idle traffic is still
+    spread by weight.
+*/
+pub fn least_loaded_backend<I: IntoIterator<Item=(usize, usize, usize)>, R: Rng>(candidates: I, rng: &mut R) -> Option<usize> {
+    let mut best: Option<(usize, usize, usize)> = None;
+    // Weight of the candidates tied with the best so far.
+    let mut tied_weight = 0;
+    for (index, outstanding, weight) in candidates {
+        if weight == 0 {
+            continue;
+        }
+        match best {
+            Some((_, best_outstanding, best_weight)) => {
+                let load = outstanding * best_weight;
+                let best_load = best_outstanding * weight;
+                if load < best_load {
+                    best = Some((index, outstanding, weight));
+                    tied_weight = weight;
+                } else if load == best_load {
+                    tied_weight += weight;
+                    if rng.gen_range(0, tied_weight) < weight {
+                        best = Some((index, outstanding, weight));
+                    }
+                }
+            }
+            None => {
+                best = Some((index, outstanding, weight));
+                tied_weight = weight;
+            }
+        }
+    }
+    return best.map(|(index, _, _)| index);
+}
+
+// 64 bit finalizer of MurmurHash3, to spread the bits of the key hash combined with a backend seed.
+fn mix64(value: u64) -> u64 {
+    let mut x = value;
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    return x;
+}
+
+/*
+    Rendezvous (highest random weight) hashing. Every backend scores the key, and the highest score wins. Scores are
+    weighted with the logarithmic method, so a backend receives keys in proportion to its weight. When a backend is
+    added or removed, only the keys it wins or owned move.
+*/
+pub struct RendezvousTable {
+    // (backend index, seed, weight)
+    servers: Vec<(usize, u64, f64)>,
+}
+impl RendezvousTable {
+    pub fn new(servers: &[(usize, String, usize)]) -> RendezvousTable {
+        RendezvousTable {
+            servers: servers.iter()
+                .filter(|&&(_, _, weight)| weight > 0)
+                .map(|&(index, ref name, weight)| (index, hash(&HashFunction::Fnv1a64, name.as_bytes()) as u64, weight as f64))
+                .collect(),
+        }
+    }
+
+    pub fn dispatch(&self, hash: usize) -> Option<usize> {
+        let mut best: Option<(usize, f64)> = None;
+        for &(index, seed, weight) in self.servers.iter() {
+            let score = RendezvousTable::score(hash, seed, weight);
+            best = match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((index, score)),
+            };
+        }
+        return best.map(|(index, _)| index);
+    }
+
+    // Returns the backends with the num_replicas highest scores, highest first.
+    pub fn dispatch_replicas(&self, hash: usize, num_replicas: usize) -> Vec<usize> {
+        let mut scores: Vec<(usize, f64)> = self.servers.iter()
+            .map(|&(index, seed, weight)| (index, RendezvousTable::score(hash, seed, weight)))
+            .collect();
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        return scores.iter().take(num_replicas).map(|&(index, _)| index).collect();
+    }
+
+    fn score(hash: usize, seed: u64, weight: f64) -> f64 {
+        // Uniform in (0, 1), from the top 53 bits of the combined hash.
+        let unit = ((mix64(hash as u64 ^ seed) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        return weight / -unit.ln();
+    }
+}
+
+/*
+    Maglev hashing. See https://research.google/pubs/pub44824/
+    Each backend fills the slots of a fixed size table in the order of its own permutation, taking weight slots per
+    turn. A key maps to the slot at its hash. Lookups are a single index into the table, and a membership change only
+    moves slightly more keys than the minimum.
+*/
+pub struct MaglevTable {
+    table: Vec<usize>,
+}
+impl MaglevTable {
+    pub fn new(servers: &[(usize, String, usize)]) -> MaglevTable {
+        let servers: Vec<&(usize, String, usize)> = servers.iter().filter(|&&(_, _, weight)| weight > 0).collect();
+        if servers.len() == 0 {
+            return MaglevTable {
+                table: Vec::new(),
+            };
+        }
+        // (offset, skip) of the permutation of each backend.
+        let permutations: Vec<(usize, usize)> = servers.iter().map(|&&(_, ref name, _)| {
+            let offset = hash(&HashFunction::Fnv1a64, name.as_bytes()) % MAGLEV_TABLE_SIZE;
+            let skip = hash(&HashFunction::Murmur, name.as_bytes()) % (MAGLEV_TABLE_SIZE - 1) + 1;
+            (offset, skip)
+        }).collect();
+        let mut next = vec![0; servers.len()];
+        let mut table = vec![std::usize::MAX; MAGLEV_TABLE_SIZE];
+        let mut filled = 0;
+        'fill: loop {
+            for (i, &&(index, _, weight)) in servers.iter().enumerate() {
+                let (offset, skip) = permutations[i];
+                for _ in 0..weight {
+                    let mut slot = (offset + next[i] * skip) % MAGLEV_TABLE_SIZE;
+                    while table[slot] != std::usize::MAX {
+                        next[i] += 1;
+                        slot = (offset + next[i] * skip) % MAGLEV_TABLE_SIZE;
+                    }
+                    table[slot] = index;
+                    next[i] += 1;
+                    filled += 1;
+                    if filled == MAGLEV_TABLE_SIZE {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+        return MaglevTable {
+            table: table,
+        };
+    }
+
+    pub fn dispatch(&self, hash: usize) -> Option<usize> {
+        if self.table.len() == 0 {
+            return None;
+        }
+        return Some(self.table[hash % self.table.len()]);
+    }
+
+    // Returns up to num_replicas distinct backends, taken from the slots following the slot of the key.
+    pub fn dispatch_replicas(&self, hash: usize, num_replicas: usize) -> Vec<usize> {
+        let mut indexes = Vec::with_capacity(num_replicas);
+        for i in 0..self.table.len() {
+            let index = self.table[(hash % self.table.len() + i) % self.table.len()];
+            if !indexes.contains(&index) {
+                indexes.push(index);
+                if indexes.len() == num_replicas {
+                    break;
+                }
+            }
+        }
+        return indexes;
+    }
+}
+
+#[cfg(test)]
+fn test_servers(num_servers: usize) -> Vec<(usize, String, usize)> {
+    (0..num_servers).map(|i| (i, format!("127.0.0.1:{}", 6380 + i), 1)).collect()
+}
+
+/*
+    Hashes 10000 keys with both lookups, and returns how many keys changed backend, and how many of those did not move
+    to or from the backend that was added or removed.
+*/
+#[cfg(test)]
+fn count_moved_keys<F, G>(before: F, after: G, changed_index: usize) -> (usize, usize)
+    where F: Fn(usize) -> usize, G: Fn(usize) -> usize {
+    let mut moved = 0;
+    let mut moved_elsewhere = 0;
+    for i in 0..10000 {
+        let key_hash = hash(&HashFunction::Fnv1a64, format!("key{}", i).as_bytes());
+        let (old, new) = (before(key_hash), after(key_hash));
+        if old != new {
+            moved += 1;
+            if old != changed_index && new != changed_index {
+                moved_elsewhere += 1;
+            }
+        }
+    }
+    return (moved, moved_elsewhere);
+}
+
+#[cfg(test)]
+use init_logging;
+
+#[test]
+fn test_jump_consistent_key_movement() {
+    init_logging();
+    // Adding an 11th backend should move about 1/11 of the keys, all of them to the new backend.
+    let (moved, moved_elsewhere) = count_moved_keys(|h| jump_consistent_hash(h as u64, 10), |h| jump_consistent_hash(h as u64, 11), 10);
+    info!("Jump consistent hash: adding a backend to 10 moved {} of 10000 keys", moved);
+    assert!(moved > 0 && moved < 1400);
+    assert_eq!(moved_elsewhere, 0);
+
+    // Removing the last backend should only move the keys it owned.
+    let (moved, moved_elsewhere) = count_moved_keys(|h| jump_consistent_hash(h as u64, 10), |h| jump_consistent_hash(h as u64, 9), 9);
+    info!("Jump consistent hash: removing a backend from 10 moved {} of 10000 keys", moved);
+    assert!(moved > 0 && moved < 1500);
+    assert_eq!(moved_elsewhere, 0);
+}
+
+#[test]
+fn test_rendezvous_key_movement() {
+    init_logging();
+    let ten = RendezvousTable::new(&test_servers(10));
+    let eleven = RendezvousTable::new(&test_servers(11));
+    let (moved, moved_elsewhere) = count_moved_keys(|h| ten.dispatch(h).unwrap(), |h| eleven.dispatch(h).unwrap(), 10);
+    info!("Rendezvous: adding a backend to 10 moved {} of 10000 keys", moved);
+    assert!(moved > 0 && moved < 1400);
+    assert_eq!(moved_elsewhere, 0);
+
+    // Remove a backend from the middle.
+    let mut servers = test_servers(10);
+    servers.remove(4);
+    let nine = RendezvousTable::new(&servers);
+    let (moved, moved_elsewhere) = count_moved_keys(|h| ten.dispatch(h).unwrap(), |h| nine.dispatch(h).unwrap(), 4);
+    info!("Rendezvous: removing a backend from 10 moved {} of 10000 keys", moved);
+    assert!(moved > 0 && moved < 1500);
+    assert_eq!(moved_elsewhere, 0);
+
+    // A backend with double the weight should receive about double the keys.
+    let mut servers = test_servers(2);
+    servers[1].2 = 2;
+    let weighted = RendezvousTable::new(&servers);
+    let (to_heavier, _) = count_moved_keys(|_| 0, |h| weighted.dispatch(h).unwrap(), 1);
+    assert!(to_heavier > 6000 && to_heavier < 7300);
+}
+
+#[test]
+fn test_maglev_key_movement() {
+    init_logging();
+    let ten = MaglevTable::new(&test_servers(10));
+    let eleven = MaglevTable::new(&test_servers(11));
+    // Maglev moves slightly more than the minimal number of keys.
+    let (moved, moved_elsewhere) = count_moved_keys(|h| ten.dispatch(h).unwrap(), |h| eleven.dispatch(h).unwrap(), 10);
+    info!("Maglev: adding a backend to 10 moved {} of 10000 keys, {} between existing backends", moved, moved_elsewhere);
+    assert!(moved > 0 && moved < 1800);
+    assert!(moved_elsewhere < 500);
+
+    let mut servers = test_servers(10);
+    servers.remove(4);
+    let nine = MaglevTable::new(&servers);
+    let (moved, moved_elsewhere) = count_moved_keys(|h| ten.dispatch(h).unwrap(), |h| nine.dispatch(h).unwrap(), 4);
+    info!("Maglev: removing a backend from 10 moved {} of 10000 keys, {} between remaining backends", moved, moved_elsewhere);
+    assert!(moved > 0 && moved < 1800);
+    assert!(moved_elsewhere < 500);
+
+    // Every slot is filled, in proportion to the weights.
+    let mut servers = test_servers(2);
+    servers[1].2 = 2;
+    let weighted = MaglevTable::new(&servers);
+    let heavier_slots = weighted.table.iter().filter(|&&index| index == 1).count();
+    assert!(weighted.table.iter().all(|&index| index < 2));
+    assert!(heavier_slots > MAGLEV_TABLE_SIZE * 6 / 10 && heavier_slots < MAGLEV_TABLE_SIZE * 7 / 10);
+}
+
+#[test]
+fn test_dispatch_replicas() {
+    let servers = test_servers(5);
+    let lookups = vec![
+        ShardLookup::new(&Distribution::Ketama, &servers).unwrap(),
+        ShardLookup::new(&Distribution::Rendezvous, &servers).unwrap(),
+        ShardLookup::new(&Distribution::Maglev, &servers).unwrap(),
+    ];
+    for lookup in lookups.iter() {
+        for i in 0..100 {
+            let key_hash = hash(&HashFunction::Fnv1a64, format!("key{}", i).as_bytes());
+            let replicas = lookup.dispatch_replicas(key_hash, 3);
+            assert_eq!(replicas.len(), 3);
+            assert_eq!(Some(replicas[0]), lookup.dispatch(key_hash));
+            assert!(replicas[1] != replicas[0] && replicas[2] != replicas[0] && replicas[2] != replicas[1]);
+        }
+        // There are only as many replicas as backends.
+        assert_eq!(lookup.dispatch_replicas(0, 10).len(), 5);
+    }
+}
+
+#[test]
+fn test_least_loaded_backend() {
+    let mut rng = ::rand::thread_rng();
+    assert_eq!(least_loaded_backend(vec![(0, 3, 1), (1, 4, 2), (2, 1, 1)], &mut rng), Some(2));
+    // Outstanding requests are weighed: 4 requests on a weight of 2 is less load than 3 on a weight of 1.
+    assert_eq!(least_loaded_backend(vec![(0, 3, 1), (1, 4, 2), (2, 3, 1)], &mut rng), Some(1));
+    // Backends without weight take no requests.
+    assert_eq!(least_loaded_backend(vec![(0, 0, 0), (1, 5, 1)], &mut rng), Some(1));
+    assert_eq!(least_loaded_backend(vec![(0, 0, 0)], &mut rng), None);
+    assert_eq!(least_loaded_backend(Vec::new(), &mut rng), None);
+    // Ties go to each backend in proportion to its weight.
+    let mut picks = [0; 3];
+    for _ in 0..4000 {
+        picks[least_loaded_backend(vec![(0, 0, 1), (1, 0, 2), (2, 0, 1)], &mut rng).unwrap()] += 1;
+    }
+    assert!(picks[0] > 800 && picks[0] < 1200, "{:?}", picks);
+    assert!(picks[1] > 1800 && picks[1] < 2200, "{:?}", picks);
+    assert!(picks[2] > 800 && picks[2] < 1200, "{:?}", picks);
+}
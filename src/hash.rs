@@ -3,20 +3,55 @@ use std::default::Default;
 use crc::{crc16, crc32};
 use fasthash::*;
 use hashers::jenkins::spooky_hash;
+use md5;
 
 // Reading: https://probablydance.com/2017/02/26/i-wrote-the-fastest-hashtable/
 // Benchmarks: https://github.com/rurban/smhasher/
-// Hsieh = not implemented. See http://www.azillionmonkeys.com/qed/hash.html
+// Twemproxy's hash functions: https://github.com/twitter/twemproxy/tree/master/src/hashkit
 
 
-#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub enum HashFunction {
     Crc16,
     Crc32,
     Fnv1a64,
     Murmur,
     Jenkins,
+
+    // The following match twemproxy's hash functions bit for bit, and use twemproxy's names in the config, so that a
+    // pool moved over from twemproxy keeps the same key placement.
+    OneAtATime,
+    Md5,
+    TwemproxyCrc16,
+    TwemproxyCrc32,
+    Crc32a,
+    Fnv164,
+    TwemproxyFnv1a64,
+    Fnv132,
+    Fnv1a32,
+    Hsieh,
+    TwemproxyMurmur,
 }
+// The names of the hash functions that aren't twemproxy's say what they compute, so that none of them is a twemproxy
+// name with different casing.
+config_enum_strings!(HashFunction {
+    Crc16 => "crc16_x25",
+    Crc32 => "crc32_ieee",
+    Fnv1a64 => "fnv1a_64_full",
+    Murmur => "murmur1",
+    Jenkins => "spooky",
+    OneAtATime => "one_at_a_time",
+    Md5 => "md5",
+    TwemproxyCrc16 => "crc16",
+    TwemproxyCrc32 => "crc32",
+    Crc32a => "crc32a",
+    Fnv164 => "fnv1_64",
+    TwemproxyFnv1a64 => "fnv1a_64",
+    Fnv132 => "fnv1_32",
+    Fnv1a32 => "fnv1a_32",
+    Hsieh => "hsieh",
+    TwemproxyMurmur => "murmur",
+});
 
 pub fn hash(hash_function: &HashFunction, key: &[u8]) -> usize {
     match hash_function {
@@ -40,7 +75,180 @@ pub fn hash(hash_function: &HashFunction, key: &[u8]) -> usize {
         HashFunction::Jenkins => {
             spooky_hash::spooky(key) as usize
         }
+        HashFunction::OneAtATime => hash_one_at_a_time(key) as usize,
+        HashFunction::Md5 => hash_md5(key) as usize,
+        HashFunction::TwemproxyCrc16 => hash_crc16(key) as usize,
+        HashFunction::TwemproxyCrc32 => ((!crc32::checksum_ieee(key) >> 16) & 0x7fff) as usize,
+        HashFunction::Crc32a => crc32::checksum_ieee(key) as usize,
+        HashFunction::Fnv164 => hash_fnv1_64(key) as usize,
+        HashFunction::TwemproxyFnv1a64 => hash_fnv1a_64(key) as usize,
+        HashFunction::Fnv132 => hash_fnv1_32(key) as usize,
+        HashFunction::Fnv1a32 => hash_fnv1a_32(key) as usize,
+        HashFunction::Hsieh => hash_hsieh(key) as usize,
+        HashFunction::TwemproxyMurmur => hash_murmur(key) as usize,
+    }
+}
+
+/*
+    Twemproxy reads keys through a signed char, so bytes above 0x7f are sign extended wherever it casts a byte
+    straight to an integer. This does the same, to hash those keys identically.
+*/
+#[inline]
+fn signed_byte(byte: u8) -> u32 {
+    (byte as i8) as u32
+}
+
+fn hash_one_at_a_time(key: &[u8]) -> u32 {
+    let mut value: u32 = 0;
+    for byte in key {
+        value = value.wrapping_add(signed_byte(*byte));
+        value = value.wrapping_add(value << 10);
+        value ^= value >> 6;
+    }
+    value = value.wrapping_add(value << 3);
+    value ^= value >> 11;
+    value = value.wrapping_add(value << 15);
+    return value;
+}
+
+// The first 4 bytes of the md5 digest, little endian. Also used for the points of the ketama continuum.
+pub fn hash_md5(key: &[u8]) -> u32 {
+    let digest = md5::compute(key);
+    return read_u32_le(&digest.0[0..4]);
+}
+
+#[inline]
+pub fn read_u32_le(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24)
+}
+
+/*
+    CRC16-XMODEM, as twemproxy computes it. Twemproxy keeps the crc in a 32 bit integer and never masks it down to 16
+    bits, so the result can be larger than 0xffff.
+*/
+fn hash_crc16(key: &[u8]) -> u32 {
+    let mut crc: u32 = 0;
+    for byte in key {
+        let mut entry = ((((crc >> 8) ^ (*byte as u32)) & 0xff) as u16) << 8;
+        for _ in 0..8 {
+            entry = if entry & 0x8000 != 0 { (entry << 1) ^ 0x1021 } else { entry << 1 };
+        }
+        crc = (crc << 8) ^ (entry as u32);
+    }
+    return crc;
+}
+
+fn hash_fnv1_64(key: &[u8]) -> u32 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        hash = hash.wrapping_mul(0x100000001b3);
+        hash ^= (*byte as i8) as u64;
     }
+    return hash as u32;
+}
+
+// Twemproxy's fnv1a_64 truncates the 64 bit offset basis and prime, and hashes in 32 bits.
+fn hash_fnv1a_64(key: &[u8]) -> u32 {
+    let mut hash: u32 = 0xcbf29ce484222325u64 as u32;
+    for byte in key {
+        hash ^= signed_byte(*byte);
+        hash = hash.wrapping_mul(0x100000001b3u64 as u32);
+    }
+    return hash;
+}
+
+fn hash_fnv1_32(key: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in key {
+        hash = hash.wrapping_mul(16777619);
+        hash ^= signed_byte(*byte);
+    }
+    return hash;
+}
+
+fn hash_fnv1a_32(key: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in key {
+        hash ^= signed_byte(*byte);
+        hash = hash.wrapping_mul(16777619);
+    }
+    return hash;
+}
+
+// Paul Hsieh's SuperFastHash. See http://www.azillionmonkeys.com/qed/hash.html
+fn hash_hsieh(key: &[u8]) -> u32 {
+    if key.len() == 0 {
+        return 0;
+    }
+    let get16bits = |bytes: &[u8]| -> u32 { (bytes[0] as u32) | ((bytes[1] as u32) << 8) };
+    let mut hash: u32 = 0;
+    let rem = key.len() & 3;
+    let mut chunks = key.chunks(4);
+    for _ in 0..(key.len() >> 2) {
+        let chunk = chunks.next().unwrap();
+        hash = hash.wrapping_add(get16bits(&chunk[0..2]));
+        let tmp = (get16bits(&chunk[2..4]) << 11) ^ hash;
+        hash = (hash << 16) ^ tmp;
+        hash = hash.wrapping_add(hash >> 11);
+    }
+    let tail = &key[key.len() - rem..];
+    match rem {
+        3 => {
+            hash = hash.wrapping_add(get16bits(&tail[0..2]));
+            hash ^= hash << 16;
+            hash ^= signed_byte(tail[2]) << 18;
+            hash = hash.wrapping_add(hash >> 11);
+        }
+        2 => {
+            hash = hash.wrapping_add(get16bits(&tail[0..2]));
+            hash ^= hash << 11;
+            hash = hash.wrapping_add(hash >> 17);
+        }
+        1 => {
+            hash = hash.wrapping_add(tail[0] as u32);
+            hash ^= hash << 10;
+            hash = hash.wrapping_add(hash >> 1);
+        }
+        _ => {}
+    }
+    hash ^= hash << 3;
+    hash = hash.wrapping_add(hash >> 5);
+    hash ^= hash << 4;
+    hash = hash.wrapping_add(hash >> 17);
+    hash ^= hash << 25;
+    hash = hash.wrapping_add(hash >> 6);
+    return hash;
+}
+
+// MurmurHash2, seeded the way twemproxy seeds it.
+fn hash_murmur(key: &[u8]) -> u32 {
+    let m: u32 = 0x5bd1e995;
+    let seed: u32 = 0xdeadbeefu32.wrapping_mul(key.len() as u32);
+    let mut h: u32 = seed ^ (key.len() as u32);
+    let mut chunks = key.chunks(4);
+    for _ in 0..(key.len() >> 2) {
+        let mut k = read_u32_le(chunks.next().unwrap());
+        k = k.wrapping_mul(m);
+        k ^= k >> 24;
+        k = k.wrapping_mul(m);
+        h = h.wrapping_mul(m);
+        h ^= k;
+    }
+    let tail = &key[key.len() - (key.len() & 3)..];
+    if tail.len() == 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if tail.len() >= 1 {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(m);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(m);
+    h ^= h >> 15;
+    return h;
 }
 
 /// An implementation of the Fowler–Noll–Vo hash function.
@@ -93,4 +301,18 @@ fn test_hashing_speed() {
         hash(&HashFunction::Fnv1a64, &a.as_bytes());
     }
     info!("Time spent with default: {:?}", Instant::now() - start);
-}
\ No newline at end of file
+}
+#[test]
+fn test_twemproxy_hashes() {
+    assert_eq!(hash(&HashFunction::Crc32a, b"123456789"), 0xcbf43926);
+    assert_eq!(hash(&HashFunction::TwemproxyCrc32, b"123456789"), ((!0xcbf43926u32 >> 16) & 0x7fff) as usize);
+    assert_eq!(hash(&HashFunction::TwemproxyCrc16, b"123456789") & 0xffff, 0x31c3);
+    assert_eq!(hash(&HashFunction::Fnv132, b"a"), 0x050c5d7e);
+    assert_eq!(hash(&HashFunction::Fnv1a32, b"a"), 0xe40c292c);
+    assert_eq!(hash(&HashFunction::Fnv164, b"a"), 0xaf63bd4c8601b7be & 0xffffffff);
+    assert_eq!(hash(&HashFunction::OneAtATime, b"a"), 0xca2e9442);
+    assert_eq!(hash(&HashFunction::Md5, b""), 0xd98c1dd4);
+    assert_eq!(hash(&HashFunction::Hsieh, b""), 0);
+    // Bytes above 0x7f are sign extended, like twemproxy's signed chars.
+    assert_eq!(hash(&HashFunction::Fnv1a32, &[0xff]), ((2166136261u32 ^ 0xffffffff).wrapping_mul(16777619)) as usize);
+}
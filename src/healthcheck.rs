@@ -0,0 +1,128 @@
+use config::BackendPoolConfig;
+
+/*
+    Active health check state for a single backend.
+    A health check periodically sends a configured command to the backend, and compares the reply to the expected
+    reply. This lets an idle backend that has stopped responding be marked down before any client request hits it.
+*/
+#[derive(Clone)]
+pub struct HealthCheck {
+    // Interval between checks, in ms.
+    pub interval: usize,
+    // The encoded command that is sent to the backend.
+    pub request: Vec<u8>,
+    expected_reply: String,
+    healthy_threshold: usize,
+    unhealthy_threshold: usize,
+
+    // Whether a health check has been sent, and its reply has not been received yet.
+    pub waiting_for_resp: bool,
+    consecutive_successes: usize,
+    consecutive_failures: usize,
+    // Set when the backend was marked down by the health check. An ejected backend is only made available again once
+    // it passes enough consecutive health checks.
+    pub ejected: bool,
+}
+
+impl HealthCheck {
+    // Returns None if health checks are disabled for the pool.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<HealthCheck> {
+        if config.health_check_interval == 0 {
+            return None;
+        }
+        Some(HealthCheck {
+            interval: config.health_check_interval,
+            request: encode_command(&config.health_check_command),
+            expected_reply: config.health_check_expected_reply.clone(),
+            healthy_threshold: config.health_check_healthy_threshold,
+            unhealthy_threshold: config.health_check_unhealthy_threshold,
+            waiting_for_resp: false,
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+            ejected: false,
+        })
+    }
+
+    // An error reply is never healthy. Otherwise, the reply must contain the expected reply.
+    pub fn is_expected_reply(&self, response: &[u8]) -> bool {
+        if response.get(0) == Some(&b'-') {
+            return false;
+        }
+        return String::from_utf8_lossy(response).contains(self.expected_reply.as_str());
+    }
+
+    // Records a successful check. Returns true if an ejected backend should now be made available again.
+    pub fn record_success(&mut self) -> bool {
+        self.consecutive_failures = 0;
+        self.consecutive_successes += 1;
+        if self.ejected && self.consecutive_successes >= self.healthy_threshold {
+            self.ejected = false;
+            return true;
+        }
+        return false;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_successes = 0;
+        self.consecutive_failures += 1;
+    }
+
+    pub fn is_unhealthy(&self) -> bool {
+        return self.consecutive_failures >= self.unhealthy_threshold;
+    }
+
+    // Called when the backend is marked down by the health check.
+    pub fn eject(&mut self) {
+        self.ejected = true;
+        self.consecutive_failures = 0;
+        self.consecutive_successes = 0;
+        self.waiting_for_resp = false;
+    }
+}
+
+// Encodes a space-separated command, such as "INFO replication", into a redis request.
+fn encode_command(command: &String) -> Vec<u8> {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    let mut request = Vec::new();
+    request.extend_from_slice(b"*");
+    request.extend_from_slice(args.len().to_string().as_bytes());
+    request.extend_from_slice(b"\r\n");
+    for arg in args {
+        request.extend_from_slice(b"$");
+        request.extend_from_slice(arg.len().to_string().as_bytes());
+        request.extend_from_slice(b"\r\n");
+        request.extend_from_slice(arg.as_bytes());
+        request.extend_from_slice(b"\r\n");
+    }
+    request
+}
+
+#[test]
+fn test_health_check() {
+    assert_eq!(encode_command(&"PING".to_owned()), b"*1\r\n$4\r\nPING\r\n".to_vec());
+    assert_eq!(encode_command(&"INFO replication".to_owned()), b"*2\r\n$4\r\nINFO\r\n$11\r\nreplication\r\n".to_vec());
+
+    let mut health_check = HealthCheck {
+        interval: 100,
+        request: encode_command(&"INFO replication".to_owned()),
+        expected_reply: "role:master".to_owned(),
+        healthy_threshold: 2,
+        unhealthy_threshold: 2,
+        waiting_for_resp: false,
+        consecutive_successes: 0,
+        consecutive_failures: 0,
+        ejected: false,
+    };
+    assert!(health_check.is_expected_reply(b"$28\r\n# Replication\r\nrole:master\r\n\r\n"));
+    assert!(!health_check.is_expected_reply(b"$27\r\n# Replication\r\nrole:slave\r\n\r\n"));
+    assert!(!health_check.is_expected_reply(b"-ERR role:master\r\n"));
+
+    health_check.record_failure();
+    assert!(!health_check.is_unhealthy());
+    health_check.record_failure();
+    assert!(health_check.is_unhealthy());
+    health_check.eject();
+    assert!(!health_check.record_success());
+    assert!(health_check.record_success());
+    assert!(!health_check.ejected);
+}
@@ -0,0 +1,181 @@
+use std;
+
+/*
+    Latency histogram in the style of HdrHistogram. Values below 128 each get their own bucket, and larger values are
+    bucketed by their top 7 bits, so that any recorded value is reported within 1/64th of itself. Buckets are only
+    allocated up to the largest value recorded, which keeps idle histograms small.
+*/
+const SUB_BUCKETS: u64 = 128;
+const HALF_SUB_BUCKETS: u64 = 64;
+
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+impl LatencyHistogram {
+    pub fn record(&mut self, value: u64) {
+        let index = bucket_index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.count += 1;
+        self.sum += value;
+        if value > self.max {
+            self.max = value;
+        }
+    }
+
+    pub fn add(&mut self, other: &LatencyHistogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (index, count) in other.counts.iter().enumerate() {
+            self.counts[index] += *count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        if other.max > self.max {
+            self.max = other.max;
+        }
+    }
+
+    /*
+        The values recorded since an earlier copy of this histogram was taken. The max stays the max of this histogram,
+        as the max of the values since then isn't known.
+    */
+    pub fn subtract(&self, earlier: &LatencyHistogram) -> LatencyHistogram {
+        let mut difference = self.clone();
+        for (index, count) in earlier.counts.iter().enumerate() {
+            match difference.counts.get_mut(index) {
+                Some(difference_count) => *difference_count = difference_count.saturating_sub(*count),
+                None => break,
+            }
+        }
+        difference.count = self.count.saturating_sub(earlier.count);
+        difference.sum = self.sum.saturating_sub(earlier.sum);
+        return difference;
+    }
+
+    // The (highest value, count) of each bucket with values in it.
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        return self.counts.iter().enumerate()
+            .filter(|&(_, count)| *count > 0)
+            .map(|(index, count)| (std::cmp::min(bucket_highest_value(index), self.max), *count))
+            .collect();
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.clear();
+        self.count = 0;
+        self.sum = 0;
+        self.max = 0;
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    pub fn sum(&self) -> u64 {
+        return self.sum;
+    }
+
+    // The value that the given fraction of the recorded values are at or below, such as 0.99 for p99.
+    pub fn percentile(&self, fraction: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = std::cmp::max((fraction * self.count as f64).ceil() as u64, 1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= target {
+                return std::cmp::min(bucket_highest_value(index), self.max);
+            }
+        }
+        return self.max;
+    }
+
+    // The count and percentiles, in microseconds, as shown by the admin LATENCY command.
+    pub fn summary(&self) -> String {
+        format!(
+            "count={} p50={} p90={} p99={} p999={} max={}",
+            self.count,
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(0.999),
+            self.max,
+        )
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    // Shifted so that the value keeps its top 7 bits, between 64 and 127.
+    let shift = 63 - value.leading_zeros() as u64 - 6;
+    return (SUB_BUCKETS + (shift - 1) * HALF_SUB_BUCKETS + (value >> shift) - HALF_SUB_BUCKETS) as usize;
+}
+
+// The highest value that falls in the bucket.
+fn bucket_highest_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = (index - SUB_BUCKETS) / HALF_SUB_BUCKETS + 1;
+    let sub_bucket = (index - SUB_BUCKETS) % HALF_SUB_BUCKETS + HALF_SUB_BUCKETS;
+    return ((sub_bucket + 1) << shift).wrapping_sub(1);
+}
+
+#[test]
+fn test_bucket_index() {
+    assert_eq!(bucket_index(0), 0);
+    assert_eq!(bucket_index(127), 127);
+    assert_eq!(bucket_index(128), 128);
+    assert_eq!(bucket_index(129), 128);
+    assert_eq!(bucket_index(255), 191);
+    assert_eq!(bucket_index(256), 192);
+    assert_eq!(bucket_highest_value(128), 129);
+    assert_eq!(bucket_highest_value(191), 255);
+    assert_eq!(bucket_highest_value(192), 259);
+    // Every value is within 1/64th of the highest value of its bucket.
+    for &value in [1000u64, 12345, 999999, 123456789, std::u64::MAX].iter() {
+        let highest = bucket_highest_value(bucket_index(value));
+        assert!(highest >= value);
+        assert!(highest - value <= value / 64);
+    }
+}
+
+#[test]
+fn test_latency_histogram() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.percentile(0.99), 0);
+    for value in 1..1001 {
+        histogram.record(value);
+    }
+    // Reported as the highest value of the bucket, 500 being in the bucket of 500 to 503.
+    assert_eq!(histogram.percentile(0.5), 503);
+    assert_eq!(histogram.percentile(0.9), 903);
+    assert_eq!(histogram.percentile(1.0), 1000);
+    assert_eq!(histogram.summary(), "count=1000 p50=503 p90=903 p99=991 p999=999 max=1000");
+    assert_eq!(histogram.sum(), 500500);
+
+    let mut total = LatencyHistogram::default();
+    total.add(&histogram);
+    total.record(100000);
+    assert_eq!(total.percentile(1.0), 100000);
+    assert_eq!(total.percentile(0.5), 503);
+    assert_eq!(total.sum(), 600500);
+    let difference = total.subtract(&histogram);
+    assert_eq!(difference.count(), 1);
+    assert_eq!(difference.sum(), 100000);
+    assert_eq!(difference.buckets(), vec![(100000, 1)]);
+    total.reset();
+    assert_eq!(total.summary(), "count=0 p50=0 p90=0 p99=0 p999=0 max=0");
+}
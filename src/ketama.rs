@@ -0,0 +1,142 @@
+use config::BackendConfig;
+use hash::read_u32_le;
+use md5;
+use std;
+
+/*
+    Twemproxy's ketama continuum. See https://github.com/twitter/twemproxy/blob/master/src/hashkit/nc_ketama.c
+    Each server gets 160 points on the continuum, scaled by its share of the total weight. The points of a server are
+    the md5 digests of "<name>-<n>", each digest giving 4 points. A key belongs to the server owning the first point at
+    or after the hash of the key, wrapping around to the first point.
+    Built the same way as twemproxy, so that keys land on the same servers when moving a pool over from twemproxy.
+*/
+const KETAMA_POINTS_PER_SERVER: usize = 160;
+const KETAMA_POINTS_PER_HASH: usize = 4;
+// Twemproxy leaves the port out of the name for the memcached port, to match libmemcached.
+const KETAMA_DEFAULT_PORT: u16 = 11211;
+
+pub struct KetamaContinuum {
+    // (point value, backend index), sorted by value.
+    points: Vec<(u32, usize)>,
+}
+impl KetamaContinuum {
+    /*
+        Builds the continuum out of (backend index, name, weight) of the servers that are in the continuum. With
+        auto_eject_hosts, twemproxy only includes live servers, and scales the points by the number of live servers.
+    */
+    pub fn new(servers: &[(usize, String, usize)]) -> KetamaContinuum {
+        let num_servers = servers.len();
+        let total_weight: usize = servers.iter().map(|&(_, _, weight)| weight).sum();
+        let mut points = Vec::with_capacity(num_servers * KETAMA_POINTS_PER_SERVER);
+        for &(index, ref name, weight) in servers {
+            // Computed in single precision floats like twemproxy, since the rounding decides the number of points.
+            let pct = weight as f32 / total_weight as f32;
+            let scaled = pct * KETAMA_POINTS_PER_SERVER as f32 / KETAMA_POINTS_PER_HASH as f32 * num_servers as f32;
+            let points_per_server = ((scaled as f64 + 0.0000000001) as f32).floor() as usize * KETAMA_POINTS_PER_HASH;
+            for pointer in 1..(points_per_server / KETAMA_POINTS_PER_HASH + 1) {
+                let digest = md5::compute(format!("{}-{}", name, pointer - 1).as_bytes());
+                for x in 0..KETAMA_POINTS_PER_HASH {
+                    points.push((read_u32_le(&digest.0[x * 4..x * 4 + 4]), index));
+                }
+            }
+        }
+        points.sort_by_key(|&(value, _)| value);
+        return KetamaContinuum {
+            points: points,
+        };
+    }
+
+    // Returns the backend index for the given key hash, or None if the continuum has no points.
+    pub fn dispatch(&self, hash: u32) -> Option<usize> {
+        if self.points.len() == 0 {
+            return None;
+        }
+        return Some(self.points[self.position(hash)].1);
+    }
+
+    /*
+        Returns up to num_replicas distinct backend indexes for the given key hash. The first one is the backend of
+        dispatch, followed by the next distinct backends going around the continuum.
+    */
+    pub fn dispatch_replicas(&self, hash: u32, num_replicas: usize) -> Vec<usize> {
+        let mut indexes = Vec::with_capacity(num_replicas);
+        if self.points.len() == 0 {
+            return indexes;
+        }
+        let start = self.position(hash);
+        for i in 0..self.points.len() {
+            let index = self.points[(start + i) % self.points.len()].1;
+            if !indexes.contains(&index) {
+                indexes.push(index);
+                if indexes.len() == num_replicas {
+                    break;
+                }
+            }
+        }
+        return indexes;
+    }
+
+    // Position of the first point at or after the hash, wrapping around to the first point.
+    fn position(&self, hash: u32) -> usize {
+        let position = match self.points.binary_search_by(|&(value, _)| {
+            if value < hash { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }
+        }) {
+            Ok(position) => position,
+            Err(position) => position,
+        };
+        if position == self.points.len() {
+            return 0;
+        }
+        return position;
+    }
+}
+
+/*
+    The name of a server on the continuum. Uses the configured name if there is one, otherwise "host:port" like
+    twemproxy does. Backends without a host (cluster and sentinel backends) fall back to their index in the pool.
+*/
+pub fn server_name(config: &BackendConfig, index: usize) -> String {
+    match config.name {
+        Some(ref name) => return name.clone(),
+        None => {}
+    }
+    match config.host {
+        Some(host) => {
+            if host.port() == KETAMA_DEFAULT_PORT {
+                return format!("{}", host.ip());
+            }
+            return format!("{}", host);
+        }
+        None => return format!("{}", index),
+    }
+}
+
+#[test]
+fn test_ketama_points() {
+    let servers = vec![
+        (0, "127.0.0.1:6381".to_string(), 1),
+        (1, "127.0.0.1:6382".to_string(), 1),
+        (2, "127.0.0.1:6383".to_string(), 2),
+    ];
+    let continuum = KetamaContinuum::new(&servers);
+    // 160 points per server on average, split by weight.
+    assert_eq!(continuum.points.len(), 480);
+    assert_eq!(continuum.points.iter().filter(|&&(_, index)| index == 2).count(), 240);
+    for window in continuum.points.windows(2) {
+        assert!(window[0].0 <= window[1].0);
+    }
+
+    // The first point of a server is the first 4 bytes of md5("<name>-0"), little endian.
+    let first_point = read_u32_le(&md5::compute(b"127.0.0.1:6381-0").0[0..4]);
+    assert_eq!(continuum.dispatch(first_point), Some(0));
+    // Hashes past the last point wrap around to the first point.
+    assert_eq!(continuum.dispatch(std::u32::MAX), Some(continuum.points[0].1));
+    assert_eq!(KetamaContinuum::new(&[]).dispatch(0), None);
+
+    // Replicas start with the dispatched server, and are distinct.
+    let replicas = continuum.dispatch_replicas(first_point, 2);
+    assert_eq!(replicas.len(), 2);
+    assert_eq!(replicas[0], 0);
+    assert!(replicas[1] != 0);
+    assert_eq!(continuum.dispatch_replicas(first_point, 5).len(), 3);
+}
@@ -0,0 +1,275 @@
+use client::BufferedClient;
+use stats::Stats;
+use config::{ListenerConfig, RouteConfig};
+use redflareproxy::{ProxyError, ClientTokenValue, PoolIndex};
+use backendpool::accept_client_connections;
+use mio::*;
+use mio::tcp::TcpListener;
+use hashbrown::HashMap;
+use regex::bytes::Regex;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/*
+    A listen socket that is not tied to a single pool. The key of each request is routed to a pool by the rules of the
+    listener, before being sharded within that pool.
+*/
+pub struct Listener {
+    pub token: Token,
+    pub config: ListenerConfig,
+    pub name: String,
+    pub router: Router,
+    pub listen_socket: Option<TcpListener>,
+}
+
+impl Listener {
+    pub fn new(name: String, token: Token, config: ListenerConfig, pool_names: &Vec<String>) -> Listener {
+        debug!("ListenerToken: {:?} for listener: {:?}", token, name);
+        Listener {
+            router: Router::from_config(&config, pool_names),
+            name: name,
+            token: token,
+            config: config,
+            listen_socket: None,
+        }
+    }
+
+    pub fn connect(&mut self, poll_registry: &mut Poll) -> Result<(), ProxyError> {
+        let addr = self.config.listen;
+        let server_socket = match TcpListener::bind(&addr) {
+            Ok(soc) => soc,
+            Err(err) => {
+                return Err(ProxyError::PoolBindSocketFailure(addr, err));
+            }
+        };
+        match poll_registry.register(&server_socket, self.token, Ready::readable(), PollOpt::edge()) {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(ProxyError::PoolPollFailure(err));
+            }
+        };
+        self.listen_socket = Some(server_socket);
+        return Ok(());
+    }
+
+    // Moves the listener to a new token, and rebuilds its router against the new list of pools.
+    pub fn update(&mut self, name: String, token: Token, config: ListenerConfig, pool_names: &Vec<String>, poll_registry: &mut Poll) {
+        match self.listen_socket {
+            Some(ref s) => {
+                let _ = poll_registry.reregister(s, token, Ready::readable(), PollOpt::edge());
+            }
+            None => {}
+        }
+        self.router = Router::from_config(&config, pool_names);
+        self.name = name;
+        self.token = token;
+        self.config = config;
+    }
+
+    pub fn accept_client_connection(
+        &mut self,
+        poll: &Rc<RefCell<Poll>>,
+        next_client_token_value: &mut ClientTokenValue,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, usize)>,
+        stats: &mut Stats,
+    ) {
+        match self.listen_socket {
+            Some(ref mut listener) => accept_client_connections(listener, self.token, poll, next_client_token_value, clients, stats),
+            None => error!("Listen socket of listener {} is no more when accepting!", self.name),
+        }
+    }
+}
+
+enum KeyRule {
+    Prefix(Vec<u8>),
+    Glob(Vec<u8>),
+    Regex(Regex),
+}
+impl KeyRule {
+    fn matches(&self, key: &[u8]) -> bool {
+        match *self {
+            KeyRule::Prefix(ref prefix) => key.starts_with(prefix),
+            KeyRule::Glob(ref pattern) => glob_matches(pattern, key),
+            KeyRule::Regex(ref regex) => regex.is_match(key),
+        }
+    }
+}
+
+// Picks the pool that a key is sent to.
+pub struct Router {
+    routes: Vec<(KeyRule, PoolIndex)>,
+    pub default_pool_index: PoolIndex,
+}
+impl Router {
+    // Routes every key to the one pool. Used for clients of a pool's own listen socket.
+    pub fn for_pool(pool_index: PoolIndex) -> Router {
+        Router {
+            routes: Vec::new(),
+            default_pool_index: pool_index,
+        }
+    }
+
+    // The pools and regexes are verified when loading the config.
+    pub fn from_config(config: &ListenerConfig, pool_names: &Vec<String>) -> Router {
+        let pool_index = |name: &String| -> PoolIndex {
+            match pool_names.iter().position(|pool_name| pool_name == name) {
+                Some(index) => index,
+                None => panic!("Listener is routing to pool {}, which does not exist!", name),
+            }
+        };
+        let routes = config.routes.iter().map(|route: &RouteConfig| {
+            let rule = match (&route.prefix, &route.glob, &route.regex) {
+                (&Some(ref prefix), _, _) => KeyRule::Prefix(prefix.as_bytes().to_vec()),
+                (_, &Some(ref glob), _) => KeyRule::Glob(glob.as_bytes().to_vec()),
+                (_, _, &Some(ref regex)) => KeyRule::Regex(Regex::new(regex).unwrap()),
+                _ => panic!("Route to pool {} has no rule!", route.pool),
+            };
+            (rule, pool_index(&route.pool))
+        }).collect();
+        Router {
+            routes: routes,
+            default_pool_index: pool_index(&config.default_pool),
+        }
+    }
+
+    pub fn route(&self, key: &[u8]) -> PoolIndex {
+        for &(ref rule, pool_index) in self.routes.iter() {
+            if rule.matches(key) {
+                return pool_index;
+            }
+        }
+        return self.default_pool_index;
+    }
+}
+
+// Matches the key against a glob pattern with '*', '?', '[...]' classes and '\' escapes, like redis' KEYS.
+pub fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Position in the pattern after the last '*', and the position in the key it was matched up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    p += 1;
+                    backtrack = Some((p, k));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                b'[' => {
+                    let (matched, next_p) = match_class(pattern, p, key[k]);
+                    if matched {
+                        p = next_p;
+                        k += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == key[k] => {
+                    p += 2;
+                    k += 1;
+                    continue;
+                }
+                c if c != b'\\' && c == key[k] => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match backtrack {
+            Some((star_p, star_k)) => {
+                // Let the last '*' take one more character, and retry from there.
+                p = star_p;
+                k = star_k + 1;
+                backtrack = Some((star_p, star_k + 1));
+            }
+            None => return false,
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    return p == pattern.len();
+}
+
+/*
+    Matches a character against the class that starts at the '[' at start. As in redis, a leading '^' negates the class,
+    'a-z' is a range and '\' escapes. Returns whether the character matched, and the position after the class. A class
+    without its ']' runs to the end of the pattern.
+*/
+fn match_class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] { (pattern[p], pattern[p + 2]) } else { (pattern[p + 2], pattern[p]) };
+            matched |= c >= low && c <= high;
+            p += 2;
+        } else {
+            matched |= pattern[p] == c;
+        }
+        p += 1;
+    }
+    return (matched != negate, std::cmp::min(p + 1, pattern.len()));
+}
+
+#[test]
+fn test_glob_matches() {
+    assert!(glob_matches(b"session:*", b"session:123"));
+    assert!(glob_matches(b"session:*", b"session:"));
+    assert!(!glob_matches(b"session:*", b"sessions:123"));
+    assert!(glob_matches(b"*:user:?", b"app:user:1"));
+    assert!(!glob_matches(b"*:user:?", b"app:user:12"));
+    assert!(glob_matches(b"a*b*c", b"aXXbYYbZc"));
+    assert!(!glob_matches(b"a*b*c", b"aXXbYYbZ"));
+    assert!(glob_matches(b"literal\\*", b"literal*"));
+    assert!(!glob_matches(b"literal\\*", b"literalX"));
+    assert!(glob_matches(b"*", b""));
+    assert!(glob_matches(b"user:[0-9]", b"user:7"));
+    assert!(!glob_matches(b"user:[0-9]", b"user:a"));
+    assert!(glob_matches(b"h[ae]llo", b"hallo"));
+    assert!(!glob_matches(b"h[ae]llo", b"hillo"));
+    assert!(glob_matches(b"h[^e]llo", b"hallo"));
+    assert!(!glob_matches(b"h[^e]llo", b"hello"));
+    // Reversed ranges are allowed, and escapes make '-' and ']' part of the class.
+    assert!(glob_matches(b"[z-a]", b"m"));
+    assert!(glob_matches(b"[a\\-z]", b"-"));
+    assert!(!glob_matches(b"[a\\-z]", b"m"));
+    assert!(glob_matches(b"[\\]]x", b"]x"));
+    assert!(glob_matches(b"*[0-9]", b"abc1"));
+    // Escaped brackets, as in a key_prefix added to a KEYS pattern, match themselves.
+    assert!(glob_matches(b"\\[a\\]*", b"[a]:1"));
+    assert!(!glob_matches(b"\\[a\\]*", b"a:1"));
+}
+
+#[test]
+fn test_router() {
+    let config = ListenerConfig {
+        listen: "127.0.0.1:1530".parse().unwrap(),
+        routes: vec![
+            RouteConfig { prefix: Some("session:".to_owned()), glob: None, regex: None, pool: "sessions".to_owned() },
+            RouteConfig { prefix: None, glob: Some("cache:*".to_owned()), regex: None, pool: "cache".to_owned() },
+            RouteConfig { prefix: None, glob: None, regex: Some("^user:[0-9]+$".to_owned()), pool: "sessions".to_owned() },
+        ],
+        default_pool: "main".to_owned(),
+    };
+    let pool_names = vec!["cache".to_owned(), "main".to_owned(), "sessions".to_owned()];
+    let router = Router::from_config(&config, &pool_names);
+    assert_eq!(router.route(b"session:abc"), 2);
+    assert_eq!(router.route(b"cache:abc"), 0);
+    assert_eq!(router.route(b"user:42"), 2);
+    assert_eq!(router.route(b"user:abc"), 1);
+    assert_eq!(router.route(b"other"), 1);
+}
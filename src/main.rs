@@ -12,7 +12,7 @@ extern crate clap;
 use redflareproxy::ProxyError;
 use clap::{Arg, App};
 extern crate daemonize;
-extern crate conhash;
+extern crate md5;
 extern crate rand;
 extern crate crc16;
 extern crate bufstream;
@@ -22,6 +22,7 @@ extern crate fasthash;
 extern crate hashers;
 extern crate hashbrown;
 extern crate memchr;
+extern crate regex;
 use log::LogLevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
@@ -29,15 +30,28 @@ use log4rs::encode::pattern::PatternEncoder;
 use log4rs::config::{Appender, Config, Root};
 
 mod admin;
+mod metrics;
+mod statsd;
 mod redflareproxy;
+#[macro_use]
 mod config;
 mod backend;
+mod backoff;
+mod circuitbreaker;
 mod cluster_backend;
 mod backendpool;
+mod listener;
+mod sentinel_backend;
+mod replicated_backend;
 mod redisprotocol;
 mod hash;
+mod ketama;
+mod distribution;
+mod healthcheck;
 mod client;
 mod stats;
+mod histogram;
+mod monitor;
 
 mod bufreader;
 
@@ -0,0 +1,404 @@
+use backend::{Backend, SingleBackend};
+use backendpool::BackendPool;
+use config::MetricsConfig;
+use histogram::LatencyHistogram;
+use redflareproxy::{METRICS_LISTENER, FIRST_METRICS_CLIENT_INDEX, STATSD_TIMER, FIRST_SOCKET_INDEX, ClientToken};
+use stats::Stats;
+
+use mio::*;
+use mio::tcp::{TcpListener, TcpStream};
+use hashbrown::HashMap;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+// Requests are only a request line and a few headers, so anything larger is dropped.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/*
+    HTTP listener serving /metrics in the Prometheus text format. Connections are handled on the proxy's event loop like
+    every other socket: requests are read as they arrive, and responses are written as far as the socket takes them,
+    with the rest written on the next writable event. Each connection serves a single request, and is then closed.
+*/
+pub struct MetricsPort {
+    pub config: MetricsConfig,
+    socket: TcpListener,
+    clients: HashMap<usize, MetricsClient>,
+}
+
+struct MetricsClient {
+    stream: TcpStream,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    written: usize,
+}
+
+impl MetricsPort {
+    pub fn new(config: MetricsConfig, poll: &Poll) -> MetricsPort {
+        let addr = match config.listen.parse() {
+            Ok(addr) => addr,
+            Err(error) => {
+                panic!("Unable to parse the metrics listen port from config: {}. Reason: {:?}", config.listen, error);
+            }
+        };
+
+        let server_socket = match TcpListener::bind(&addr) {
+            Ok(socket) => socket,
+            Err(error) => {
+                panic!("Unable to bind to metrics listen port: {:?}. Reason: {:?}", addr, error);
+            }
+        };
+
+        match poll.register(&server_socket, METRICS_LISTENER, Ready::readable(), PollOpt::edge()) {
+            Ok(_) => {}
+            Err(error) => {
+                panic!("Failed to register metrics listener socket to poll. Reason: {:?}", error);
+            }
+        };
+        debug!("Registered metrics socket.");
+
+        MetricsPort {
+            config: config,
+            socket: server_socket,
+            clients: HashMap::new(),
+        }
+    }
+
+    // Accepts connections while there are free tokens, between FIRST_METRICS_CLIENT_INDEX and STATSD_TIMER.
+    pub fn accept_client_connection(&mut self, poll: &mut Poll) {
+        loop {
+            match self.socket.accept() {
+                Ok((s, _)) => {
+                    let token = match (FIRST_METRICS_CLIENT_INDEX..STATSD_TIMER.0).find(|t| !self.clients.contains_key(t)) {
+                        Some(token) => Token(token),
+                        None => {
+                            warn!("Too many metrics connections. Dropping the new one.");
+                            continue;
+                        }
+                    };
+                    match poll.register(&s, token, Ready::readable() | Ready::writable(), PollOpt::edge()) {
+                        Ok(_) => {}
+                        Err(error) => {
+                            error!("Failed to register metrics client socket to poll. Reason: {:?}", error);
+                            continue;
+                        }
+                    };
+                    self.clients.insert(token.0, MetricsClient {
+                        stream: s,
+                        request: Vec::new(),
+                        response: Vec::new(),
+                        written: 0,
+                    });
+                }
+                Err(error) => {
+                    if error.kind() == std::io::ErrorKind::WouldBlock {
+                        return;
+                    }
+                    error!("Unable to accept metrics client connection. Reason: {:?}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    /*
+        Handles an event on a metrics connection. Returns the method and path of the request once it has been read in
+        full, so that the caller can respond to it. Connections already being responded to keep writing instead.
+    */
+    pub fn handle_client_event(&mut self, token: ClientToken) -> Option<(String, String)> {
+        let done = {
+            let client = match self.clients.get_mut(&token.0) {
+                Some(client) => client,
+                None => {
+                    debug!("Metrics client {:?} triggered an event, but it is no longer stored.", token);
+                    return None;
+                }
+            };
+            if client.response.len() > 0 {
+                client.flush()
+            } else {
+                match client.read_request() {
+                    Ok(Some(request_line)) => return Some(request_line),
+                    Ok(None) => return None,
+                    Err(()) => true,
+                }
+            }
+        };
+        if done {
+            self.clients.remove(&token.0);
+        }
+        return None;
+    }
+
+    pub fn respond(&mut self, token: ClientToken, status: &str, body: &str) {
+        let done = match self.clients.get_mut(&token.0) {
+            Some(client) => {
+                client.response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body,
+                ).into_bytes();
+                client.flush()
+            }
+            None => return,
+        };
+        if done {
+            self.clients.remove(&token.0);
+        }
+    }
+}
+
+impl MetricsClient {
+    /*
+        Reads what is available of the request. Returns the method and path from the request line once the headers are
+        complete, and Err when the connection should be closed.
+    */
+    fn read_request(&mut self) -> Result<Option<(String, String)>, ()> {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(()),
+                Ok(len) => {
+                    self.request.extend_from_slice(&buf[..len]);
+                    if self.request.len() > MAX_REQUEST_SIZE {
+                        return Err(());
+                    }
+                }
+                Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return Err(()),
+            }
+        }
+        if !self.request.windows(4).any(|window| window == b"\r\n\r\n") {
+            return Ok(None);
+        }
+        let request = String::from_utf8_lossy(&self.request).into_owned();
+        let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+        let method = parts.next().unwrap_or("").to_owned();
+        let path = parts.next().unwrap_or("").split('?').next().unwrap_or("").to_owned();
+        return Ok(Some((method, path)));
+    }
+
+    // Writes as much of the response as the socket takes. Returns whether the connection is done with.
+    fn flush(&mut self) -> bool {
+        while self.written < self.response.len() {
+            match self.stream.write(&self.response[self.written..]) {
+                Ok(0) => return true,
+                Ok(len) => self.written += len,
+                Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => return false,
+                Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    debug!("Unable to write to metrics client. Received error: {}", error);
+                    return true;
+                }
+            }
+        }
+        return true;
+    }
+}
+
+/*
+    A metric of the proxy, shared by the Prometheus and StatsD exporters. Names are like backend_requests, and each
+    exporter adds its own prefix and unit suffix. Labels are the pool, backend, role, host, error type or command.
+*/
+pub struct Metric {
+    pub name: String,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: MetricValue,
+}
+
+pub enum MetricValue {
+    Counter(usize),
+    Gauge(usize),
+    // Latencies in microseconds.
+    Latency(LatencyHistogram),
+}
+
+fn add_metric(metrics: &mut Vec<Metric>, name: &str, labels: &[(&'static str, String)], value: MetricValue) {
+    metrics.push(Metric {
+        name: name.to_owned(),
+        labels: labels.to_vec(),
+        value: value,
+    });
+}
+
+fn add_host_metrics(metrics: &mut Vec<Metric>, labels: &[(&'static str, String)], host: &SingleBackend) {
+    let stats = &host.stats;
+    add_metric(metrics, "backend_up", labels, MetricValue::Gauge(host.is_available() as usize));
+    add_metric(metrics, "backend_queue", labels, MetricValue::Gauge(host.queue.len()));
+    for &(name, value) in [
+        ("requests", stats.requests),
+        ("responses", stats.responses),
+        ("timeouts", stats.timeouts),
+        ("ejections", stats.ejections),
+        ("reconnects", stats.reconnects),
+        ("send_bytes", stats.send_bytes),
+        ("recv_bytes", stats.recv_bytes),
+    ].iter() {
+        add_metric(metrics, &format!("backend_{}", name), labels, MetricValue::Counter(value));
+    }
+    for (error_type, count) in stats.errors.iter() {
+        let mut error_labels = labels.to_vec();
+        error_labels.push(("type", error_type.clone()));
+        add_metric(metrics, "backend_errors", &error_labels, MetricValue::Counter(*count));
+    }
+    add_metric(metrics, "backend_latency", labels, MetricValue::Latency(stats.latency.clone()));
+}
+
+/*
+    Collects every counter, gauge and latency histogram of the proxy. Backend metrics are labeled with their pool, their
+    index in the pool, their role and their host.
+*/
+pub fn collect_metrics(
+    stats: &Stats,
+    backendpools: &Vec<BackendPool>,
+    backends: &Vec<Backend>,
+    cluster_backends: &Vec<(SingleBackend, usize)>,
+    num_clients: usize,
+    start_time: Instant,
+) -> Vec<Metric> {
+    let mut metrics = Vec::new();
+    add_metric(&mut metrics, "uptime_seconds", &[], MetricValue::Gauge(start_time.elapsed().as_secs() as usize));
+    add_metric(&mut metrics, "clients", &[], MetricValue::Gauge(num_clients));
+    for &(name, value) in stats.counters().iter() {
+        add_metric(&mut metrics, name, &[], MetricValue::Counter(value));
+    }
+    for pool in backendpools.iter() {
+        let pool_labels = vec![("pool", pool.name.clone())];
+        let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - backendpools.len();
+        let pool_backends = &backends[start_backend_index..start_backend_index + pool.num_backends];
+        let num_available = pool_backends.iter().filter(|backend| backend.is_available()).count();
+        add_metric(&mut metrics, "pool_backends", &pool_labels, MetricValue::Gauge(pool_backends.len()));
+        add_metric(&mut metrics, "pool_backends_available", &pool_labels, MetricValue::Gauge(num_available));
+        let mut latency = LatencyHistogram::default();
+        for (index, backend) in pool_backends.iter().enumerate() {
+            for (role, host) in backend.get_stats(cluster_backends) {
+                let mut labels = pool_labels.clone();
+                labels.push(("backend", index.to_string()));
+                labels.push(("role", role));
+                labels.push(("host", host.get_host().to_string()));
+                add_host_metrics(&mut metrics, &labels, host);
+                latency.add(&host.stats.latency);
+            }
+        }
+        add_metric(&mut metrics, "pool_latency", &pool_labels, MetricValue::Latency(latency));
+    }
+    for &(ref command, ref histogram) in stats.command_latency.iter() {
+        if histogram.count() == 0 {
+            continue;
+        }
+        add_metric(&mut metrics, "command_latency", &[("command", command.clone())], MetricValue::Latency(histogram.clone()));
+    }
+    return metrics;
+}
+
+/*
+    A metric family in the Prometheus text format. The samples are (name suffix, labels, value), the suffix being empty
+    except for the _sum and _count of summaries.
+*/
+struct Family {
+    name: String,
+    kind: &'static str,
+    samples: Vec<(&'static str, String, String)>,
+}
+
+fn add_sample(families: &mut Vec<Family>, name: String, kind: &'static str, suffix: &'static str, labels: String, value: String) {
+    match families.iter_mut().find(|family| family.name == name) {
+        Some(family) => {
+            family.samples.push((suffix, labels, value));
+            return;
+        }
+        None => {}
+    }
+    families.push(Family {
+        name: name,
+        kind: kind,
+        samples: vec![(suffix, labels, value)],
+    });
+}
+
+fn format_seconds(microseconds: u64) -> String {
+    return format!("{}", microseconds as f64 / 1_000_000.0);
+}
+
+// Label values are quoted, with backslashes, quotes and newlines escaped.
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    let labels: Vec<String> = labels.iter().map(|&(name, ref value)| {
+        format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+    }).collect();
+    return labels.join(",");
+}
+
+/*
+    Renders the metrics in the Prometheus text format. Counters get a _total suffix, and latencies are summaries in
+    seconds, with their percentiles as quantiles. All the samples of a family are written together, after its TYPE.
+*/
+pub fn render_prometheus(metrics: &[Metric]) -> String {
+    let mut families = Vec::new();
+    for metric in metrics.iter() {
+        let labels = format_labels(&metric.labels);
+        match metric.value {
+            MetricValue::Counter(value) => {
+                add_sample(&mut families, format!("redflareproxy_{}_total", metric.name), "counter", "", labels, value.to_string());
+            }
+            MetricValue::Gauge(value) => {
+                add_sample(&mut families, format!("redflareproxy_{}", metric.name), "gauge", "", labels, value.to_string());
+            }
+            MetricValue::Latency(ref histogram) => {
+                let name = format!("redflareproxy_{}_seconds", metric.name);
+                let separator = if labels.len() > 0 { "," } else { "" };
+                for &(quantile, fraction) in [("0.5", 0.5), ("0.9", 0.9), ("0.99", 0.99), ("0.999", 0.999)].iter() {
+                    let quantile_labels = format!("{}{}quantile=\"{}\"", labels, separator, quantile);
+                    add_sample(&mut families, name.clone(), "summary", "", quantile_labels, format_seconds(histogram.percentile(fraction)));
+                }
+                add_sample(&mut families, name.clone(), "summary", "_sum", labels.clone(), format_seconds(histogram.sum()));
+                add_sample(&mut families, name, "summary", "_count", labels, histogram.count().to_string());
+            }
+        }
+    }
+
+    let mut output = String::new();
+    for family in families.iter() {
+        output.push_str(&format!("# TYPE {} {}\n", family.name, family.kind));
+        for &(suffix, ref labels, ref value) in family.samples.iter() {
+            if labels.len() > 0 {
+                output.push_str(&format!("{}{}{{{}}} {}\n", family.name, suffix, labels, value));
+            } else {
+                output.push_str(&format!("{}{} {}\n", family.name, suffix, value));
+            }
+        }
+    }
+    return output;
+}
+
+#[test]
+fn test_render_prometheus() {
+    let mut histogram = LatencyHistogram::default();
+    histogram.record(1500);
+    histogram.record(2500);
+    let mut metrics = Vec::new();
+    add_metric(&mut metrics, "requests", &[], MetricValue::Counter(3));
+    add_metric(&mut metrics, "pool_latency", &[("pool", "pool1".to_owned())], MetricValue::Latency(histogram));
+    add_metric(&mut metrics, "pool_backends", &[("pool", "pool1".to_owned())], MetricValue::Gauge(2));
+    add_metric(&mut metrics, "pool_latency", &[("pool", "a\"b\\c\nd".to_owned())], MetricValue::Latency(LatencyHistogram::default()));
+    // The samples of a family stay together, with the _sum and _count of each label set after its quantiles.
+    assert_eq!(render_prometheus(&metrics), "\
+# TYPE redflareproxy_requests_total counter
+redflareproxy_requests_total 3
+# TYPE redflareproxy_pool_latency_seconds summary
+redflareproxy_pool_latency_seconds{pool=\"pool1\",quantile=\"0.5\"} 0.001503
+redflareproxy_pool_latency_seconds{pool=\"pool1\",quantile=\"0.9\"} 0.0025
+redflareproxy_pool_latency_seconds{pool=\"pool1\",quantile=\"0.99\"} 0.0025
+redflareproxy_pool_latency_seconds{pool=\"pool1\",quantile=\"0.999\"} 0.0025
+redflareproxy_pool_latency_seconds_sum{pool=\"pool1\"} 0.004
+redflareproxy_pool_latency_seconds_count{pool=\"pool1\"} 2
+redflareproxy_pool_latency_seconds{pool=\"a\\\"b\\\\c\\nd\",quantile=\"0.5\"} 0
+redflareproxy_pool_latency_seconds{pool=\"a\\\"b\\\\c\\nd\",quantile=\"0.9\"} 0
+redflareproxy_pool_latency_seconds{pool=\"a\\\"b\\\\c\\nd\",quantile=\"0.99\"} 0
+redflareproxy_pool_latency_seconds{pool=\"a\\\"b\\\\c\\nd\",quantile=\"0.999\"} 0
+redflareproxy_pool_latency_seconds_sum{pool=\"a\\\"b\\\\c\\nd\"} 0
+redflareproxy_pool_latency_seconds_count{pool=\"a\\\"b\\\\c\\nd\"} 0
+# TYPE redflareproxy_pool_backends gauge
+redflareproxy_pool_backends{pool=\"pool1\"} 2
+");
+}
@@ -0,0 +1,125 @@
+use client::Client;
+use listener::glob_matches;
+use redflareproxy::{ClientTokenValue, PoolIndex};
+use redisprotocol::split_request;
+
+use rand::thread_rng;
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Output waiting for a slow monitoring client is capped. Requests past it are counted, but not shown.
+const MAX_PENDING_OUTPUT: usize = 1048576;
+
+/*
+    A tap on the requests passing through the proxy, for the admin MONITOR command. The requests that match the pool
+    and key pattern, or a sample of them, become lines like those of redis' MONITOR, which also name the pool and the
+    backend that the request was sent to:
+        +1339518083.107412 [pool1 127.0.0.1:60866 127.0.0.1:6381] "SET" "key" "value"
+    The proxy only keeps a Monitor while an admin client is monitoring, so that requests cost nothing extra otherwise.
+*/
+pub struct Monitor {
+    // The admin client that the lines are written to.
+    pub client_token: ClientTokenValue,
+    pool_index: Option<PoolIndex>,
+    pattern: Option<Vec<u8>>,
+    // Percentage of the matching requests that are shown.
+    sample_percentage: usize,
+    // Lines that have yet to be written to the admin client.
+    pub output: Vec<u8>,
+    dropped: usize,
+}
+
+impl Monitor {
+    pub fn new(client_token: ClientTokenValue, pool_index: Option<PoolIndex>, pattern: Option<Vec<u8>>, sample_percentage: usize) -> Monitor {
+        Monitor {
+            client_token: client_token,
+            pool_index: pool_index,
+            pattern: pattern,
+            sample_percentage: sample_percentage,
+            output: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    // Whether to show a request for the key to the pool. Requests without a key, like KEYS, have an empty key.
+    pub fn wants(&self, pool_index: PoolIndex, key: &[u8]) -> bool {
+        if self.pool_index.map_or(false, |index| index != pool_index) {
+            return false;
+        }
+        match self.pattern {
+            Some(ref pattern) if !glob_matches(pattern, key) => return false,
+            _ => {}
+        }
+        return self.sample_percentage >= 100 || thread_rng().gen_range(0, 100) < self.sample_percentage;
+    }
+
+    pub fn record(&mut self, client: &Client, pool_name: &str, backend_address: &str, request: &[u8]) {
+        if self.output.len() >= MAX_PENDING_OUTPUT {
+            self.dropped += 1;
+            return;
+        }
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => format!("{}.{:06}", duration.as_secs(), duration.subsec_micros()),
+            Err(_) => "0.000000".to_owned(),
+        };
+        if self.dropped > 0 {
+            self.output.extend_from_slice(format!("+{} [dropped {} requests, while the monitor fell behind]\r\n", timestamp, self.dropped).as_bytes());
+            self.dropped = 0;
+        }
+        let line = format_line(&timestamp, &client.addr.to_string(), pool_name, backend_address, request);
+        self.output.extend_from_slice(line.as_bytes());
+    }
+}
+
+// A status reply with the request's arguments quoted as redis does, so that the line never spans lines.
+fn format_line(timestamp: &str, client_address: &str, pool_name: &str, backend_address: &str, request: &[u8]) -> String {
+    let mut line = format!("+{} [{} {} {}]", timestamp, pool_name, client_address, backend_address);
+    match split_request(request) {
+        Ok(args) => {
+            for arg in args {
+                line.push(' ');
+                quote_arg(&mut line, arg);
+            }
+        }
+        Err(_) => line.push_str(" (invalid request)"),
+    }
+    line.push_str("\r\n");
+    return line;
+}
+
+fn quote_arg(line: &mut String, arg: &[u8]) {
+    line.push('"');
+    for &c in arg {
+        match c {
+            b'\\' => line.push_str("\\\\"),
+            b'"' => line.push_str("\\\""),
+            b'\n' => line.push_str("\\n"),
+            b'\r' => line.push_str("\\r"),
+            b'\t' => line.push_str("\\t"),
+            c if c >= b' ' && c <= b'~' => line.push(c as char),
+            _ => line.push_str(&format!("\\x{:02x}", c)),
+        }
+    }
+    line.push('"');
+}
+
+#[test]
+fn test_format_line() {
+    let request = b"*3\r\n$3\r\nSET\r\n$5\r\nk\"ey\n\r\n$2\r\n\x01v\r\n";
+    assert_eq!(
+        format_line("1339518083.107412", "127.0.0.1:60866", "pool1", "127.0.0.1:6381", request),
+        "+1339518083.107412 [pool1 127.0.0.1:60866 127.0.0.1:6381] \"SET\" \"k\\\"ey\\n\" \"\\x01v\"\r\n"
+    );
+}
+
+#[test]
+fn test_wants() {
+    let monitor = Monitor::new(2, Some(1), Some(b"user:*".to_vec()), 100);
+    assert!(monitor.wants(1, b"user:1"));
+    assert!(!monitor.wants(0, b"user:1"));
+    assert!(!monitor.wants(1, b"session:1"));
+    assert!(!monitor.wants(1, b""));
+
+    let monitor = Monitor::new(2, None, None, 0);
+    assert!(!monitor.wants(0, b"user:1"));
+}
@@ -7,27 +7,50 @@ use backend::SingleBackend;
 use backendpool::handle_timeout;
 use backendpool::handle_client_readable;
 use config::BackendConfig;
-use backend::Backend;
+use backend::{Backend, BackendMode};
 use admin;
-use config::{RedFlareProxyConfig, BackendPoolConfig, load_config};
+use admin::AdminReply;
+use metrics;
+use statsd;
+use config::{RedFlareProxyConfig, BackendPoolConfig, load_config, config_to_toml};
 use backendpool;
 use backendpool::BackendPool;
+use ketama::server_name;
+use monitor::Monitor;
+use listener::{Listener, Router};
 use mio::*;
 use mio::unix::{UnixReady};
 use std::mem;
 use std::cell::{RefCell};
 use std::rc::Rc;
-use stats::Stats;
+use stats::{Stats, BackendStats};
+use histogram::LatencyHistogram;
+use healthcheck::HealthCheck;
+use backoff::RetryBackoff;
+use circuitbreaker::CircuitBreaker;
 
 use hashbrown::HashMap;
 
 // For admin reqs.
-use backend::parse_redis_command;
 use toml;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::Instant;
 
 // Reserved Token space.
 pub const NULL_TOKEN: Token = Token(0);
 pub const ADMIN_LISTENER: Token = Token(1);
+pub const METRICS_LISTENER: Token = Token(3);
+// Metrics connections take the tokens from here up to STATSD_TIMER.
+pub const FIRST_METRICS_CLIENT_INDEX: usize = 4;
+pub const STATSD_TIMER: Token = Token(9);
+// Client token of requests copied to a mirror pool. No client has it, so the responses are discarded.
+pub const MIRROR_TOKEN: Token = Token(std::usize::MAX);
+// Request ids with special handling of the response, kept clear of the ids of multikey requests, which count up from 1.
+pub const MIGRATION_READ_ID: usize = std::usize::MAX;
+pub const MIGRATION_FALLBACK_ID: usize = std::usize::MAX - 1;
+pub const STRIP_KEY_PREFIX_ID: usize = std::usize::MAX - 2;
+pub const REPLICATED_WRITE_ID: usize = std::usize::MAX - 3;
 
 // Pool Listeners
 pub const FIRST_SOCKET_INDEX: usize = 10;
@@ -39,9 +62,24 @@ pub const FIRST_SOCKET_INDEX: usize = 10;
 
 // backend conns retry?
 
-// Client conns.
+// Backend health check timers.
+
+// Client conns. They start far enough after the backends that backends added at runtime don't move the backend timers
+// into the range of the clients.
+pub const FIRST_CLIENT_INDEX: usize = 100000000;
 
 pub const FIRST_CLUSTER_BACKEND_INDEX: usize = 1000000000;
+// Backends in the cluster range can't offset their timers by the number of backends, since the offset token would
+// belong to another cluster backend. Instead, each kind of timer gets its own range after the cluster backends.
+pub const CLUSTER_TOKEN_RANGE: usize = 250000000;
+pub const RETRY_TIMER: usize = 1;
+pub const REQUEST_TIMER: usize = 2;
+pub const HEALTH_CHECK_TIMER: usize = 3;
+// Listeners come after the cluster timer ranges.
+pub const FIRST_LISTENER_INDEX: usize = 2000000000;
+// Admin connections take the tokens from here, up to MAX_ADMIN_CLIENTS of them, well after the listeners.
+pub const FIRST_ADMIN_CLIENT_INDEX: usize = 3000000000;
+pub const MAX_ADMIN_CLIENTS: usize = 1000;
 // Cluster clients... start from reverse to end?
 
 pub type BackendToken = Token;
@@ -56,18 +94,27 @@ pub type BackendIndex = usize;
 pub type BackendTokenValue = usize;
 pub type TimeoutTokenValue = usize;
 pub type RequestTimeoutTokenValue = usize;
+pub type HealthCheckTokenValue = usize;
 pub type ClusterTokenValue = usize;
 
 #[derive(Clone, Copy, Debug)]
 enum SubType {
     Timeout,
     RequestTimeout,
+    HealthCheck,
     PoolServer,
     PoolListener,
     PoolClient,
     ClusterServer,
+    ClusterTimeout,
+    ClusterRequestTimeout,
+    ClusterHealthCheck,
     AdminListener,
     AdminClient,
+    MetricsListener,
+    MetricsClient,
+    StatsdTimer,
+    Listener,
 }
 
 #[derive(Debug)]
@@ -135,13 +182,18 @@ impl error::Error for ProxyError {
 pub struct RedFlareProxy {
     // This may just get integrated back into RedFlareProxy.
     admin: admin::AdminPort,
+    metrics: Option<metrics::MetricsPort>,
+    statsd: Option<statsd::StatsdExporter>,
 
     // Configs
     config: RedFlareProxyConfig,
     staged_config: Option<RedFlareProxyConfig>,
+    config_path: String,
+    staged_config_path: Option<String>,
 
     // Child structs.
     backendpools: Vec<BackendPool>,
+    listeners: Vec<Listener>,
     backends: Vec<Backend>,
     cluster_backends: Vec<(SingleBackend, BackendTokenValue)>,
 
@@ -149,15 +201,18 @@ pub struct RedFlareProxy {
     clients: HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
 
     stats: Stats,
+    // Kept while an admin client runs MONITOR.
+    monitor: Option<Monitor>,
 
     // Registry...
     poll: Rc<RefCell<Poll>>,
     next_client_token_value: ClientTokenValue,
     running: bool,
+    start_time: Instant,
 }
 impl RedFlareProxy {
     pub fn new(config_path: String) -> Result<RedFlareProxy, ProxyError> {
-        let config = try!(load_config(config_path));
+        let config = try!(load_config(config_path.clone()));
         let poll = match Poll::new() {
             Ok(poll) => Rc::new(RefCell::new(poll)),
             Err(err) => {
@@ -165,6 +220,8 @@ impl RedFlareProxy {
             }
         };
         let admin = admin::AdminPort::new(config.admin.clone(), &poll.borrow());
+        let metrics = config.metrics.clone().map(|metrics_config| metrics::MetricsPort::new(metrics_config, &poll.borrow()));
+        let statsd = config.statsd.clone().map(|statsd_config| statsd::StatsdExporter::new(statsd_config, &poll.borrow()));
 
         let num_pools = config.pools.len();
 
@@ -175,16 +232,23 @@ impl RedFlareProxy {
 
         let mut redflareproxy = RedFlareProxy {
             admin: admin,
+            metrics: metrics,
+            statsd: statsd,
             backendpools: Vec::with_capacity(num_pools),
+            listeners: Vec::new(),
             backends: Vec::with_capacity(num_backends),
             cluster_backends: Vec::new(),
             clients: HashMap::with_capacity(4096),
             config: config,
             staged_config: None,
+            config_path: config_path,
+            staged_config_path: None,
             poll: poll,
-            next_client_token_value: FIRST_SOCKET_INDEX + num_pools + 3*num_backends,
+            next_client_token_value: FIRST_CLIENT_INDEX,
             stats: Stats::new(),
+            monitor: None,
             running: true,
+            start_time: Instant::now(),
         };
         // Populate backend pools.
         let pools_config = redflareproxy.config.pools.clone();
@@ -205,6 +269,17 @@ impl RedFlareProxy {
             ));
             pool_token_value += 1;
         }
+        let pool_names: Vec<String> = redflareproxy.config.pools.keys().cloned().collect();
+        for pool in redflareproxy.backendpools.iter_mut() {
+            pool.resolve_pool_references(&pool_names);
+        }
+        let mut listener_token_value = FIRST_LISTENER_INDEX;
+        for (listener_name, listener_config) in redflareproxy.config.listeners.clone() {
+            let mut listener = Listener::new(listener_name, Token(listener_token_value), listener_config, &pool_names);
+            try!(listener.connect(&mut redflareproxy.poll.borrow_mut()));
+            redflareproxy.listeners.push(listener);
+            listener_token_value += 1;
+        }
         debug!("Initialized redflareproxy");
 
         Ok(redflareproxy)
@@ -227,18 +302,38 @@ impl RedFlareProxy {
         }
         let staged_config = mem::replace(&mut self.staged_config, None);
         self.config = staged_config.unwrap();
+        match mem::replace(&mut self.staged_config_path, None) {
+            Some(path) => self.config_path = path,
+            None => {}
+        }
 
         // Replace admin.
         if self.config.admin != self.admin.config {
             let admin = admin::AdminPort::new(self.config.admin.clone(), &self.poll.borrow());
             self.admin = admin; // TODO: what to do with old admin?
         }
+        // Replace the metrics listener. Dropping the old one closes it.
+        if self.config.metrics != self.metrics.as_ref().map(|metrics| metrics.config.clone()) {
+            self.metrics = None;
+            self.metrics = self.config.metrics.clone().map(|metrics_config| metrics::MetricsPort::new(metrics_config, &self.poll.borrow()));
+        }
+        if self.config.statsd != self.statsd.as_ref().map(|statsd| statsd.config.clone()) {
+            self.statsd = None;
+            self.statsd = self.config.statsd.clone().map(|statsd_config| statsd::StatsdExporter::new(statsd_config, &self.poll.borrow()));
+        }
 
         let mut existing_clients: HashMap<SocketAddr, Vec<BufferedClient>> = HashMap::new();
         for (_client_token_value, (client, pool_token_value)) in self.clients.drain() {
-            // check listen socket of pool_token_value.
-            let pool_index = pool_token_value - FIRST_SOCKET_INDEX;
-            let listen_socket = self.backendpools.get_mut(pool_index).unwrap().config.listen.clone();
+            // check listen socket of pool_token_value, which is a listener token for clients of a listener.
+            let listen_socket = if pool_token_value >= FIRST_LISTENER_INDEX {
+                self.listeners.get(pool_token_value - FIRST_LISTENER_INDEX).unwrap().config.listen.clone()
+            } else {
+                let pool_index = pool_token_value - FIRST_SOCKET_INDEX;
+                match self.backendpools.get_mut(pool_index).unwrap().config.listen.clone() {
+                    Some(listen_socket) => listen_socket,
+                    None => continue,
+                }
+            };
             if existing_clients.contains_key(&listen_socket) {
                 existing_clients.get_mut(&listen_socket).unwrap().push(client);
             } else {
@@ -299,7 +394,7 @@ impl RedFlareProxy {
                 let pools_config = self.config.pools.clone();
                 let mut pool_token_value = FIRST_SOCKET_INDEX;
                 let mut next_backend_token_value = FIRST_SOCKET_INDEX + num_pools;
-                let mut next_client_token_value = FIRST_SOCKET_INDEX + num_pools + 3*num_backends;
+                let mut next_client_token_value = FIRST_CLIENT_INDEX;
                 for (pool_name, pool_config) in pools_config {
                     // check if pool_config exists in remaining_pools. if it does, reregister it to the correct token.
                     match remaining_pools.remove(&pool_config) {
@@ -348,7 +443,11 @@ impl RedFlareProxy {
                             ));
                         }
                     }
-                    match existing_clients.remove(&pool_config.listen) {
+                    let existing_pool_clients = match pool_config.listen {
+                        Some(listen) => existing_clients.remove(&listen),
+                        None => None,
+                    };
+                    match existing_pool_clients {
                         Some(mut clients) => {
                             for mut client in clients.drain(0..) {
                                 let _ = self.poll.borrow_mut().reregister(&client.get_ref().stream, Token(next_client_token_value), Ready::readable() | Ready::writable(), PollOpt::edge());
@@ -362,6 +461,43 @@ impl RedFlareProxy {
                     pool_token_value += 1;
                 }
 
+                // Keep the sockets of listeners that still listen on the same address, and rebuild their routes.
+                let mut old_listeners: HashMap<SocketAddr, Listener> = HashMap::new();
+                for listener in self.listeners.drain(0..) {
+                    old_listeners.insert(listener.config.listen, listener);
+                }
+                let pool_names: Vec<String> = self.config.pools.keys().cloned().collect();
+                for pool in new_backendpools.iter_mut() {
+                    pool.resolve_pool_references(&pool_names);
+                }
+                let mut listener_token_value = FIRST_LISTENER_INDEX;
+                for (listener_name, listener_config) in self.config.listeners.clone() {
+                    let listen = listener_config.listen;
+                    let listener = match old_listeners.remove(&listen) {
+                        Some(mut listener) => {
+                            listener.update(listener_name, Token(listener_token_value), listener_config, &pool_names, &mut self.poll.borrow_mut());
+                            listener
+                        }
+                        None => {
+                            let mut listener = Listener::new(listener_name, Token(listener_token_value), listener_config, &pool_names);
+                            try!(listener.connect(&mut self.poll.borrow_mut()));
+                            listener
+                        }
+                    };
+                    self.listeners.push(listener);
+                    match existing_clients.remove(&listen) {
+                        Some(mut clients) => {
+                            for client in clients.drain(0..) {
+                                let _ = self.poll.borrow_mut().reregister(&client.get_ref().stream, Token(next_client_token_value), Ready::readable() | Ready::writable(), PollOpt::edge());
+                                new_clients.insert(next_client_token_value, (client, listener_token_value));
+                                next_client_token_value += 1;
+                            }
+                        }
+                        None => {}
+                    }
+                    listener_token_value += 1;
+                }
+
             self.backendpools = new_backendpools;
             (new_backends, new_clients)
             };
@@ -394,15 +530,18 @@ impl RedFlareProxy {
             for completed_ctv in completed_clients.drain(0..) {
                 handle_client(
                     &mut self.backendpools,
+                    &self.listeners,
                     &mut self.backends,
                     &mut self.cluster_backends,
                     &mut self.clients,
                     &mut Token(completed_ctv),
                     &mut new_completed_clients,
                     &mut self.stats,
+                    &mut self.monitor,
                     false,
                 );
             }
+            self.flush_monitor();
             let temp = completed_clients;
             completed_clients = new_completed_clients;
             new_completed_clients = temp;
@@ -457,12 +596,14 @@ impl RedFlareProxy {
                 debug!("PoolClient {:?}", token);
                 handle_client(
                     &mut self.backendpools,
+                    &self.listeners,
                     &mut self.backends,
                     &mut self.cluster_backends,
                     &mut self.clients,
                     &mut token,
                     completed_clients,
                     &mut self.stats,
+                    &mut self.monitor,
                     true,
                 );
             }
@@ -474,7 +615,7 @@ impl RedFlareProxy {
 
                 match self.backends.get_mut(token_id) {
                     Some(backend) => {
-                        backend.init_connection(&mut self.cluster_backends);
+                        backend.handle_retry_timeout(&mut self.cluster_backends, &mut self.stats);
                     }
                     None => error!("HashMap says it has token but it really doesn't! {:?}",token),
                 }
@@ -499,6 +640,18 @@ impl RedFlareProxy {
                     None => error!("HashMap says it has token but it really doesn't! {:?}", token),
                 }
             }
+            SubType::HealthCheck => {
+                debug!("HealthCheck {:?}", token);
+                let num_pools = self.backendpools.len();
+                let num_backends = self.backends.len();
+                let token_id = convert_token_to_healthcheck_index(token.0, num_pools, num_backends);
+                match self.backends.get_mut(token_id) {
+                    Some(backend) => {
+                        backend.handle_health_check(&mut self.clients, completed_clients, &mut self.stats);
+                    }
+                    None => error!("HashMap says it has token but it really doesn't! {:?}", token),
+                }
+            }
             SubType::PoolListener => {
                 debug!("PoolListener {:?}", token);
                 let token_id = convert_token_to_pool_index(token.0);
@@ -547,13 +700,78 @@ impl RedFlareProxy {
                     &mut self.stats,
                 );
             }
+            SubType::ClusterTimeout => {
+                debug!("ClusterTimeout {:?}", token);
+                let cluster_index = convert_token_to_cluster_timer_index(token.0, RETRY_TIMER);
+                match self.cluster_backends.get_mut(cluster_index) {
+                    Some((backend, _)) => backend.handle_retry_timeout(&mut self.stats),
+                    None => error!("Cluster backend for retry timer does not exist! {:?}", token),
+                }
+            }
+            SubType::ClusterRequestTimeout => {
+                debug!("ClusterRequestTimeout {:?}", token);
+                let cluster_index = convert_token_to_cluster_timer_index(token.0, REQUEST_TIMER);
+                match self.cluster_backends.get_mut(cluster_index) {
+                    Some((backend, _)) => {
+                        if backend.handle_timeout(&mut self.clients, completed_clients, &mut self.stats) {
+                            backend.handle_backend_failure(&mut self.clients, completed_clients, &mut self.stats);
+                        }
+                    }
+                    None => error!("Cluster backend for request timer does not exist! {:?}", token),
+                }
+            }
+            SubType::ClusterHealthCheck => {
+                debug!("ClusterHealthCheck {:?}", token);
+                let cluster_index = convert_token_to_cluster_timer_index(token.0, HEALTH_CHECK_TIMER);
+                match self.cluster_backends.get_mut(cluster_index) {
+                    Some((backend, _)) => backend.handle_health_check(&mut self.clients, completed_clients, &mut self.stats),
+                    None => error!("Cluster backend for health check timer does not exist! {:?}", token),
+                }
+            }
             SubType::AdminClient => {
                 debug!("AdminClient {:?}", token);
-                self.handle_client_socket(token);
+                self.handle_client_socket(token, completed_clients);
             }
             SubType::AdminListener => {
                 debug!("AdminListener {:?}", token);
-                self.admin.accept_client_connection(2, &mut self.poll.borrow_mut());
+                self.admin.accept_client_connection(&mut self.poll.borrow_mut());
+            }
+            SubType::MetricsListener => {
+                debug!("MetricsListener {:?}", token);
+                match self.metrics {
+                    Some(ref mut metrics) => metrics.accept_client_connection(&mut self.poll.borrow_mut()),
+                    None => error!("Metrics listener triggered an event, but metrics are not configured."),
+                }
+            }
+            SubType::MetricsClient => {
+                debug!("MetricsClient {:?}", token);
+                self.handle_metrics_client(token);
+            }
+            SubType::StatsdTimer => {
+                debug!("StatsdTimer {:?}", token);
+                let push = match self.statsd {
+                    Some(ref mut statsd) => statsd.handle_timeout(),
+                    None => false,
+                };
+                if push {
+                    let metrics = self.collect_metrics();
+                    match self.statsd {
+                        Some(ref mut statsd) => statsd.push(&metrics),
+                        None => {}
+                    }
+                }
+            }
+            SubType::Listener => {
+                debug!("Listener {:?}", token);
+                match self.listeners.get_mut(token.0 - FIRST_LISTENER_INDEX) {
+                    Some(listener) => listener.accept_client_connection(
+                                        &self.poll,
+                                        &mut self.next_client_token_value,
+                                        &mut self.clients,
+                                        &mut self.stats,
+                                      ),
+                    None => error!("Listener for token does not exist! {:?}", token),
+                }
             }
         }
         return;
@@ -567,105 +785,689 @@ impl RedFlareProxy {
         self.staged_config.clone()
     }
 
-    fn handle_client_socket(&mut self, token: ClientToken) {
-        let mut switching_config = false;
-        let request = {
-            let client = match self.admin.client_sockets.get_mut(&token.0) {
-                Some(c) => c,
-                None => {
-                    error!("AdminClient {:?} triggered an event, but it is no longer stored.", token);
-                    return;
+    /*
+        Describes the proxy for the admin INFO command, in sections like redis' own INFO. Given a section name, only
+        that section is returned.
+    */
+    fn get_info(&self, section: Option<&str>) -> String {
+        let section = section.map(|section| section.to_lowercase());
+        let wants = |name: &str| -> bool {
+            match section {
+                Some(ref section) => section == name || section == "all" || section == "default",
+                None => true,
+            }
+        };
+        let mut sections = Vec::new();
+        if wants("server") {
+            sections.push(format!(
+                "# Server\nversion:{}\nuptime_in_seconds:{}\nprocess_id:{}\nconfig_file:{}\n",
+                env!("CARGO_PKG_VERSION"),
+                self.start_time.elapsed().as_secs(),
+                std::process::id(),
+                self.config_path,
+            ));
+        }
+        if wants("clients") {
+            sections.push(format!(
+                "# Clients\nconnected_clients:{}\naccepted_clients:{}\nrejected_clients:{}\n",
+                self.clients.len(),
+                self.stats.accepted_clients,
+                self.stats.rejected_clients,
+            ));
+        }
+        if wants("pools") {
+            let mut info = "# Pools\n".to_owned();
+            for (index, pool) in self.backendpools.iter().enumerate() {
+                info.push_str(&format!(
+                    "pool{}:name={},listen={},distribution={},backends={}\n",
+                    index,
+                    pool.name,
+                    match pool.config.listen {
+                        Some(addr) => addr.to_string(),
+                        None => "none".to_owned(),
+                    },
+                    pool.config.distribution.name(),
+                    pool.num_backends,
+                ));
+            }
+            sections.push(info);
+        }
+        if wants("backends") {
+            let num_pools = self.backendpools.len();
+            let mut info = "# Backends\n".to_owned();
+            let mut index = 0;
+            for pool in self.backendpools.iter() {
+                let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools;
+                for backend in self.backends[start_backend_index..start_backend_index + pool.num_backends].iter() {
+                    for line in backend.get_info(&self.cluster_backends) {
+                        info.push_str(&format!("backend{}:pool={},mode={:?},{}\n", index, pool.name, backend.mode, line));
+                        index += 1;
+                    }
                 }
+            }
+            sections.push(info);
+        }
+        if wants("memory") {
+            sections.push(format!("# Memory\n{}", get_memory_info()));
+        }
+        return sections.join("\n");
+    }
+
+    /*
+        Describes the stats of a pool for the admin STATS command, adding up the counters of its backends. Given a
+        backend, describes each host connection of that backend instead.
+    */
+    fn get_pool_stats(&self, pool_name: &str, backend_name: Option<&str>) -> AdminReply {
+        let pool = match self.backendpools.iter().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return AdminReply::Error("Unknown pool!".to_owned()),
+        };
+        let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - self.backendpools.len();
+        let pool_backends = &self.backends[start_backend_index..start_backend_index + pool.num_backends];
+        match backend_name {
+            None => {
+                let mut total = BackendStats::default();
+                let mut queue = 0;
+                for backend in pool_backends.iter() {
+                    for (_, host) in backend.get_stats(&self.cluster_backends) {
+                        total.add(&host.stats);
+                        queue += host.queue.len();
+                    }
+                }
+                let num_available = pool_backends.iter().filter(|backend| backend.is_available()).count();
+                return AdminReply::Bulk(format!(
+                    "Stats for pool {}:\nbackends: {}\nbackends_available: {}\nqueue: {}\n{}",
+                    pool.name,
+                    pool_backends.len(),
+                    num_available,
+                    queue,
+                    total,
+                ));
+            }
+            Some(backend_name) => {
+                let backend = match find_pool_backend(pool, backend_name).and_then(|index| pool_backends.get(index)) {
+                    Some(backend) => backend,
+                    None => return AdminReply::Error(format!("Unknown backend in pool {}!", pool.name)),
+                };
+                let hosts: Vec<String> = backend.get_stats(&self.cluster_backends).iter().map(|&(ref role, host)| {
+                    format!("Stats for {} backend {} of pool {}:\n{}", role, backend_name, pool.name, host.get_stats_info())
+                }).collect();
+                return AdminReply::Bulk(hosts.join("\n\n"));
+            }
+        }
+    }
+
+    /*
+        Describes the latency percentiles of each pool and each command for the admin LATENCY command. Given a pool,
+        describes the pool and each host connection of its backends instead.
+    */
+    fn get_latency(&self, pool_name: Option<&str>) -> AdminReply {
+        let mut lines = vec!["Latency in microseconds:".to_owned()];
+        for pool in self.backendpools.iter() {
+            if pool_name.is_some() && pool_name != Some(pool.name.as_str()) {
+                continue;
+            }
+            let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - self.backendpools.len();
+            let pool_backends = &self.backends[start_backend_index..start_backend_index + pool.num_backends];
+            let mut total = LatencyHistogram::default();
+            let mut host_lines = Vec::new();
+            for (index, backend) in pool_backends.iter().enumerate() {
+                for (role, host) in backend.get_stats(&self.cluster_backends) {
+                    total.add(&host.stats.latency);
+                    host_lines.push(format!("backend {} {} {}: {}", index, role, host.get_host(), host.stats.latency.summary()));
+                }
+            }
+            lines.push(format!("pool {}: {}", pool.name, total.summary()));
+            if pool_name.is_some() {
+                lines.extend(host_lines);
+                return AdminReply::Bulk(lines.join("\n"));
+            }
+        }
+        if pool_name.is_some() {
+            return AdminReply::Error("Unknown pool!".to_owned());
+        }
+        let mut commands: Vec<&(String, LatencyHistogram)> = self.stats.command_latency.iter()
+            .filter(|&&(_, ref histogram)| histogram.count() > 0)
+            .collect();
+        commands.sort_by(|a, b| a.0.cmp(&b.0));
+        for &&(ref command, ref histogram) in commands.iter() {
+            lines.push(format!("command {}: {}", command, histogram.summary()));
+        }
+        return AdminReply::Bulk(lines.join("\n"));
+    }
+
+    /*
+        Changes whether a backend takes new requests, for the admin BACKEND command. The pool's sharding is rebuilt, so
+        that its keys move to the other backends, or back.
+    */
+    fn change_backend(
+        &mut self,
+        action: &str,
+        pool_name: &str,
+        backend_name: &str,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+    ) -> AdminReply {
+        let num_pools = self.backendpools.len();
+        let pool = match self.backendpools.iter().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return AdminReply::Error("Unknown pool!".to_owned()),
+        };
+        let backend_index = match find_pool_backend(pool, backend_name) {
+            Some(index) if index < pool.num_backends => pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools + index,
+            _ => return AdminReply::Error(format!("Unknown backend in pool {}!", pool.name)),
+        };
+        let backend = &mut self.backends[backend_index];
+        let mode = match action {
+            "ENABLE" => BackendMode::ENABLED,
+            "DRAIN" => BackendMode::DRAINING,
+            "DISABLE" => BackendMode::DISABLED,
+            "RECONNECT" => {
+                if backend.mode == BackendMode::DISABLED {
+                    return AdminReply::Error("Backend is disabled. ENABLE it instead.".to_owned());
+                }
+                if !backend.reconnect(&mut self.clients, completed_clients, &mut self.stats) {
+                    return AdminReply::Error("Cluster backends can't be reconnected.".to_owned());
+                }
+                info!("Reconnecting backend {} of pool {}", backend_name, pool.name);
+                return AdminReply::Status("OK".to_owned());
+            }
+            _ => return AdminReply::Error(format!("Unknown BACKEND action '{}'", action)),
+        };
+        backend.set_mode(mode, &mut self.clients, completed_clients, &mut self.stats);
+        *pool.cached_backend_shards.borrow_mut() = None;
+        info!("Backend {} of pool {} is now {:?}", backend_name, pool.name, mode);
+        return AdminReply::Status("OK".to_owned());
+    }
+
+    /*
+        Changes the servers of a pool in place, for the admin POOL command. Backends after the changed one move to the
+        next or previous token, and every backend's timers move with the new number of backends, so they are all
+        reregistered. The changes are kept in the current config, which CONFIGREWRITE writes back to its file.
+    */
+    fn change_pool(
+        &mut self,
+        action: &str,
+        pool_name: &str,
+        backend_name: Option<&str>,
+        weight: Option<&str>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+    ) -> AdminReply {
+        let pool_index = match self.backendpools.iter().position(|pool| pool.name == pool_name) {
+            Some(pool_index) => pool_index,
+            None => return AdminReply::Error("Unknown pool!".to_owned()),
+        };
+        let weight = match weight.map(|weight| weight.parse::<usize>()) {
+            Some(Ok(weight)) => Some(weight),
+            Some(Err(_)) => return AdminReply::Error("Weight must be a number!".to_owned()),
+            None => None,
+        };
+        match (action, backend_name, weight) {
+            ("ADDSERVER", Some(host), Some(weight)) => self.add_server(pool_index, host, weight),
+            ("REMOVESERVER", Some(backend_name), None) => self.remove_server(pool_index, backend_name, completed_clients),
+            ("SETWEIGHT", Some(backend_name), Some(weight)) => {
+                let pool = &mut self.backendpools[pool_index];
+                let index = match find_pool_backend(pool, backend_name) {
+                    Some(index) => index,
+                    None => return AdminReply::Error(format!("Unknown backend in pool {}!", pool.name)),
+                };
+                let backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - self.config.pools.len() + index;
+                self.backends[backend_index].weight = weight;
+                pool.config.servers[index].weight = weight;
+                self.config.pools.get_mut(&pool.name).unwrap().servers[index].weight = weight;
+                *pool.cached_backend_shards.borrow_mut() = None;
+                info!("Backend {} of pool {} now has weight {}", backend_name, pool.name, weight);
+                AdminReply::Status("OK".to_owned())
+            }
+            _ => AdminReply::Error(POOL_USAGE.to_owned()),
+        }
+    }
+
+    fn add_server(&mut self, pool_index: usize, host: &str, weight: usize) -> AdminReply {
+        let host: SocketAddr = match host.parse() {
+            Ok(host) => host,
+            Err(_) => return AdminReply::Error(format!("Invalid host '{}'. Expected ip:port.", host)),
+        };
+        let num_pools = self.backendpools.len();
+        let num_backends = self.backends.len() + 1;
+        let (backend_config, pool_config, backend_token_value) = {
+            let pool = &self.backendpools[pool_index];
+            if pool.config.servers.iter().any(|backend_config| backend_config.host == Some(host)) {
+                return AdminReply::Error(format!("Pool {} already has server {}!", pool.name, host));
+            }
+            // Servers of a pool usually share their db and password.
+            let first_server = &pool.config.servers[0];
+            let backend_config = BackendConfig {
+                host: Some(host),
+                name: None,
+                weight: weight,
+                db: first_server.db,
+                auth: first_server.auth.clone(),
+                use_cluster: false,
+                cluster_name: None,
+                cluster_hosts: Vec::new(),
+                use_sentinel: false,
+                sentinel_hosts: Vec::new(),
+                sentinel_master_name: None,
+                replicas: Vec::new(),
+            };
+            (backend_config, pool.config.clone(), pool.first_backend_index + pool.num_backends)
+        };
+
+        // Move the later backends up by one. Cluster backends point to their parent by its token.
+        for &mut (_, ref mut parent_token_value) in self.cluster_backends.iter_mut() {
+            if *parent_token_value >= backend_token_value {
+                *parent_token_value += 1;
+            }
+        }
+        for pool in self.backendpools[pool_index + 1..].iter_mut() {
+            pool.first_backend_index += 1;
+        }
+        let pool_token_value = FIRST_SOCKET_INDEX + pool_index;
+        let backend = {
+            let pool = &self.backendpools[pool_index];
+            init_backend(backend_config.clone(), &pool_config, &mut self.cluster_backends, pool_token_value, backend_token_value, &self.poll, num_backends, &pool.cached_backend_shards)
+        };
+        self.backends.insert(backend_token_value - FIRST_SOCKET_INDEX - num_pools, backend);
+        self.reregister_backends();
+
+        let pool = &mut self.backendpools[pool_index];
+        pool.num_backends += 1;
+        pool.config.servers.push(backend_config.clone());
+        self.config.pools.get_mut(&pool.name).unwrap().servers.push(backend_config);
+        *pool.cached_backend_shards.borrow_mut() = None;
+        info!("Added server {} to pool {}", host, pool.name);
+        return AdminReply::Status("OK".to_owned());
+    }
+
+    fn remove_server(&mut self, pool_index: usize, backend_name: &str, completed_clients: &mut VecDeque<ClientTokenValue>) -> AdminReply {
+        let num_pools = self.backendpools.len();
+        let (index, backend_index) = {
+            let pool = &self.backendpools[pool_index];
+            let index = match find_pool_backend(pool, backend_name) {
+                Some(index) => index,
+                None => return AdminReply::Error(format!("Unknown backend in pool {}!", pool.name)),
             };
-            parse_redis_command(client)
+            let backend_config = &pool.config.servers[index];
+            // Other kinds of backends have connections in cluster_backends, whose tokens can't be given back.
+            if backend_config.use_cluster || backend_config.use_sentinel || backend_config.replicas.len() > 0 {
+                return AdminReply::Error("Only servers with a single host can be removed.".to_owned());
+            }
+            if pool.num_backends <= pool.config.replicas {
+                return AdminReply::Error(format!("Pool {} needs at least {} servers!", pool.name, pool.config.replicas));
+            }
+            (index, pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools + index)
         };
-        debug!("RECEIVED COMMAND: {}", request);
-        let mut lines = request.lines();
-        let current_line = lines.next();
-        let res = match current_line {
+        let backend_token_value = FIRST_SOCKET_INDEX + num_pools + backend_index;
+
+        // Disabling the backend closes its connection, and fails the requests that are waiting on it. Its timers go
+        // away with it.
+        let mut backend = self.backends.remove(backend_index);
+        backend.set_mode(BackendMode::DISABLED, &mut self.clients, completed_clients, &mut self.stats);
+
+        for &mut (_, ref mut parent_token_value) in self.cluster_backends.iter_mut() {
+            if *parent_token_value > backend_token_value {
+                *parent_token_value -= 1;
+            }
+        }
+        for pool in self.backendpools[pool_index + 1..].iter_mut() {
+            pool.first_backend_index -= 1;
+        }
+        self.reregister_backends();
+
+        let pool = &mut self.backendpools[pool_index];
+        pool.num_backends -= 1;
+        let backend_config = pool.config.servers.remove(index);
+        self.config.pools.get_mut(&pool.name).unwrap().servers.remove(index);
+        *pool.cached_backend_shards.borrow_mut() = None;
+        info!("Removed server {} from pool {}", server_name(&backend_config, index), pool.name);
+        return AdminReply::Status("OK".to_owned());
+    }
+
+    // Gives every backend the token of its index, and the timer tokens for the current number of backends.
+    fn reregister_backends(&mut self) {
+        let num_pools = self.backendpools.len();
+        let num_backends = self.backends.len();
+        for (index, backend) in self.backends.iter_mut().enumerate() {
+            let token = Token(FIRST_SOCKET_INDEX + num_pools + index);
+            match backend.reregister_token(token, &mut self.cluster_backends, num_backends) {
+                Ok(_) => {}
+                Err(err) => error!("Failed to reregister backend {:?}: {}", token, err),
+            }
+        }
+    }
+
+    // The name of the pool or listener that accepted a client.
+    fn get_client_owner_name(&self, pool_token_value: PoolTokenValue) -> &str {
+        if pool_token_value >= FIRST_LISTENER_INDEX {
+            return &self.listeners[pool_token_value - FIRST_LISTENER_INDEX].name;
+        }
+        return &self.backendpools[pool_token_value - FIRST_SOCKET_INDEX].name;
+    }
+
+    // One line per client, in the format of redis' CLIENT LIST. Ages and idle times are in seconds.
+    fn get_client_list(&self) -> String {
+        let now = Instant::now();
+        let mut tokens: Vec<&ClientTokenValue> = self.clients.keys().collect();
+        tokens.sort();
+        let mut list = String::new();
+        for token in tokens {
+            let (ref client, pool_token_value) = self.clients[token];
+            let client = client.get_ref();
+            list.push_str(&format!(
+                "id={} addr={} name={} pool={} age={} idle={} net-in={} net-out={} cmd={} multi-pending={}\n",
+                token,
+                client.addr,
+                client.name.as_ref().map(|name| name.as_str()).unwrap_or(""),
+                self.get_client_owner_name(pool_token_value),
+                now.duration_since(client.created).as_secs(),
+                now.duration_since(client.last_active).as_secs(),
+                client.bytes_in,
+                client.bytes_out,
+                String::from_utf8_lossy(&client.last_command).to_lowercase(),
+                client.pending_count,
+            ));
+        }
+        return list;
+    }
+
+    /*
+        Disconnects the clients that match all of the filters, for the admin CLIENT KILL command. Like in redis, a
+        single address is a filter too. Requests that the clients still wait on are answered into the void.
+    */
+    fn kill_clients(&mut self, filters: &[&str]) -> AdminReply {
+        let mut addr = None;
+        let mut pool_name = None;
+        let mut min_idle = None;
+        if filters.len() == 1 {
+            addr = Some(filters[0]);
+        } else if filters.len() == 0 || filters.len() % 2 == 1 {
+            return AdminReply::Error(CLIENT_USAGE.to_owned());
+        } else {
+            for filter in filters.chunks(2) {
+                match filter[0].to_uppercase().as_str() {
+                    "ADDR" => addr = Some(filter[1]),
+                    "POOL" => pool_name = Some(filter[1]),
+                    "IDLE" => {
+                        match filter[1].parse::<u64>() {
+                            Ok(seconds) => min_idle = Some(seconds),
+                            Err(_) => return AdminReply::Error("Idle time must be a number of seconds!".to_owned()),
+                        }
+                    }
+                    filter => return AdminReply::Error(format!("Unknown CLIENT KILL filter '{}'", filter)),
+                }
+            }
+        }
+        let now = Instant::now();
+        let killed: Vec<ClientTokenValue> = self.clients.iter()
+            .filter(|&(_, &(ref client, pool_token_value))| {
+                let client = client.get_ref();
+                addr.map_or(true, |addr| client.addr.to_string() == addr) &&
+                pool_name.map_or(true, |pool_name| self.get_client_owner_name(pool_token_value) == pool_name) &&
+                min_idle.map_or(true, |min_idle| now.duration_since(client.last_active).as_secs() >= min_idle)
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in killed.iter() {
+            // Dropping the stream closes the connection.
+            self.clients.remove(token);
+        }
+        info!("Killed {} clients", killed.len());
+        return AdminReply::Integer(killed.len() as i64);
+    }
+
+    // Writes what the monitoring admin client has yet to receive. Monitoring stops once the client is gone.
+    fn flush_monitor(&mut self) {
+        let written = match self.monitor {
+            Some(ref monitor) if monitor.output.len() > 0 => self.admin.write_available(Token(monitor.client_token), &monitor.output),
+            _ => return,
+        };
+        match written {
+            Some(len) => {
+                match self.monitor {
+                    Some(ref mut monitor) => { monitor.output.drain(..len); }
+                    None => {}
+                }
+            }
             None => {
-                error!("AdminClient socket has nothing, when something was expected.");
-                return;
+                info!("Stopped monitoring, since the admin client is gone.");
+                self.monitor = None;
             }
-            Some("INFO") => {
-                "DERP".to_owned()
+        }
+    }
+
+    fn collect_metrics(&self) -> Vec<metrics::Metric> {
+        return metrics::collect_metrics(
+            &self.stats,
+            &self.backendpools,
+            &self.backends,
+            &self.cluster_backends,
+            self.clients.len(),
+            self.start_time,
+        );
+    }
+
+    // Responds to a metrics request once it has been read in full. Only GET /metrics is served.
+    fn handle_metrics_client(&mut self, token: ClientToken) {
+        let request = match self.metrics {
+            Some(ref mut metrics) => metrics.handle_client_event(token),
+            None => return,
+        };
+        let (status, body) = match request {
+            None => return,
+            Some((ref method, _)) if method != "GET" => ("405 Method Not Allowed", "Method not allowed.\n".to_owned()),
+            Some((_, ref path)) if path != "/metrics" => ("404 Not Found", "Not found. Metrics are at /metrics.\n".to_owned()),
+            Some(_) => ("200 OK", metrics::render_prometheus(&self.collect_metrics())),
+        };
+        match self.metrics {
+            Some(ref mut metrics) => metrics.respond(token, status, &body),
+            None => {}
+        }
+    }
+
+    fn handle_client_socket(&mut self, token: ClientToken, completed_clients: &mut VecDeque<ClientTokenValue>) {
+        for request in self.admin.read_requests(token) {
+            debug!("RECEIVED COMMAND: {:?}", request);
+            // Like in redis-cli, monitoring lasts until the client does something else.
+            if self.monitor.as_ref().map_or(false, |monitor| monitor.client_token == token.0) {
+                self.monitor = None;
             }
-            Some("PING") => {
-                "PONG".to_owned()
+            let reply = self.handle_admin_command(token, &request, completed_clients);
+            debug!("RESPONSE: {:?}", reply);
+            self.admin.write_to_client(token, reply);
+        }
+        // The token of a disconnected client goes to the next admin connection, which must not get the monitor output.
+        if !self.admin.client_sockets.contains_key(&token.0) && self.monitor.as_ref().map_or(false, |monitor| monitor.client_token == token.0) {
+            info!("Stopped monitoring, since the admin client is gone.");
+            self.monitor = None;
+        }
+    }
+
+    fn handle_admin_command(&mut self, token: ClientToken, request: &[String], completed_clients: &mut VecDeque<ClientTokenValue>) -> AdminReply {
+        let mut args = request.iter().map(|arg| arg.as_str());
+        let command = match args.next() {
+            Some(command) => command.to_uppercase(),
+            None => return AdminReply::Error("empty command".to_owned()),
+        };
+        match command.as_str() {
+            "INFO" => {
+                AdminReply::Bulk(self.get_info(args.next()))
+            }
+            "PING" => {
+                AdminReply::Status("PONG".to_owned())
             }
-            Some("LOADCONFIG") => {
-                let next_line = lines.next();
-                if next_line.is_none() {
-                    "Missing filepath argument!".to_owned()
-                } else {
-                    let argument = next_line.unwrap();
-                    let config = load_config(argument.to_owned()).unwrap();
-                    self.staged_config = Some(config);
-                    argument.to_owned()
+            "LOADCONFIG" => {
+                match args.next() {
+                    None => AdminReply::Error("Missing filepath argument!".to_owned()),
+                    Some(argument) => {
+                        match load_config(argument.to_owned()) {
+                            Ok(config) => {
+                                self.staged_config = Some(config);
+                                self.staged_config_path = Some(argument.to_owned());
+                                AdminReply::Status("OK".to_owned())
+                            }
+                            Err(err) => AdminReply::Error(format!("{}", err)),
+                        }
+                    }
                 }
             }
-            Some("SHUTDOWN") => {
+            "SHUTDOWN" => {
                 self.running = false;
-               "OK".to_owned()
+                AdminReply::Status("OK".to_owned())
             }
-            Some("STAGEDCONFIG") => {
-                let staged_config = self.get_staged_config();
-                if staged_config.is_none() {
-                    "No config staged.".to_owned()
-                } else {
-                    toml::to_string(&staged_config).unwrap()
+            "STAGEDCONFIG" => {
+                match self.get_staged_config() {
+                    None => AdminReply::Nil,
+                    Some(staged_config) => config_reply(&staged_config),
                 }
             }
-            Some("CONFIGINFO") => {
-                toml::to_string(&self.get_current_config()).unwrap()
+            "CONFIGINFO" => {
+                config_reply(&self.get_current_config())
             }
-            Some("SWITCHCONFIG") => {
-                // TODO: Need to lose reference to the stream, OR
-                // best is to orphan it. and respond OK.
-                switching_config = true;
-                // need to respond to socket later.switch_config(redflareproxy
-                "OK".to_owned()
+            "SWITCHCONFIG" => {
+                // The admin port may be replaced by the switch, in which case the reply has no client to go to.
+                match self.switch_config() {
+                    Ok(_) => AdminReply::Status("OK".to_owned()),
+                    Err(err) => AdminReply::Error(format!("{}", err)),
+                }
             }
-            Some("STATS") => {
-                format!("{}", self.stats)
+            "STATS" => {
+                // STATS [<pool> [<backend>]]. The backend is given by its index in the pool, its host or its name.
+                match args.next() {
+                    None => AdminReply::Bulk(format!("{}", self.stats)),
+                    Some(pool_name) => self.get_pool_stats(pool_name, args.next()),
+                }
             }
-            Some("RESETSTATS") => {
+            "RESETSTATS" => {
                 self.stats.reset();
-                "OK".to_owned()
+                for backend in self.backends.iter_mut() {
+                    backend.update_stats(&|stats| *stats = BackendStats::default());
+                }
+                for &mut (ref mut backend, _) in self.cluster_backends.iter_mut() {
+                    backend.stats = BackendStats::default();
+                }
+                AdminReply::Status("OK".to_owned())
             }
-            Some(unknown_command) => {
-                debug!("Unknown command: {}", unknown_command);
-                "Unknown command".to_owned()
+            "LATENCY" => {
+                // LATENCY [<pool>|RESET]
+                match args.next() {
+                    Some(argument) if argument.eq_ignore_ascii_case("RESET") => {
+                        self.stats.reset_latency();
+                        for backend in self.backends.iter_mut() {
+                            backend.update_stats(&|stats| stats.latency.reset());
+                        }
+                        for &mut (ref mut backend, _) in self.cluster_backends.iter_mut() {
+                            backend.stats.latency.reset();
+                        }
+                        AdminReply::Status("OK".to_owned())
+                    }
+                    pool_name => self.get_latency(pool_name),
+                }
             }
-        };
-        if !switching_config {
-            let mut response = String::new();
-            response.push_str("$");
-            response.push_str(&res.len().to_string());
-            response.push_str("\r\n");
-            response.push_str(res.as_str());
-            response.push_str("\r\n");
-            debug!("RESPONSE: {}", &response);
-            self.admin.write_to_client(token, response);
-        }
-        if switching_config {
-            let result = {
-                self.switch_config()
-            };
-            match result {
-                Ok(_) => {
-                    let response = "+OK\r\n".to_owned();
-                    self.admin.write_to_client(token, response);
-
+            "BACKOFF" => {
+                let num_pools = self.backendpools.len();
+                let mut lines = Vec::new();
+                for pool in self.backendpools.iter() {
+                    let start_backend_index = pool.first_backend_index - FIRST_SOCKET_INDEX - num_pools;
+                    for backend in self.backends[start_backend_index..start_backend_index + pool.num_backends].iter() {
+                        for line in backend.get_backoff_info(&self.cluster_backends) {
+                            lines.push(AdminReply::Bulk(format!("{} {}", pool.name, line)));
+                        }
+                    }
+                }
+                AdminReply::Array(lines)
+            }
+            "BACKEND" => {
+                // BACKEND DISABLE|ENABLE|DRAIN|RECONNECT <pool> <backend>. The backend is given as in STATS.
+                match (args.next(), args.next(), args.next()) {
+                    (Some(action), Some(pool_name), Some(backend_name)) => {
+                        self.change_backend(&action.to_uppercase(), pool_name, backend_name, completed_clients)
+                    }
+                    _ => AdminReply::Error("Usage: BACKEND DISABLE|ENABLE|DRAIN|RECONNECT <pool> <backend>".to_owned()),
                 }
-                Err(err) => {
-                    let mut response = String::new();
-                    response.push_str("-");
-                    response.push_str(&format!("{}", err));
-                    response.push_str("\r\n");
-                    self.admin.write_to_client(token, response);
+            }
+            "POOL" => {
+                // POOL ADDSERVER <pool> <host> <weight>, POOL REMOVESERVER <pool> <backend> or
+                // POOL SETWEIGHT <pool> <backend> <weight>. The backend is given as in STATS.
+                match (args.next(), args.next()) {
+                    (Some(action), Some(pool_name)) => {
+                        let backend_name = args.next();
+                        let weight = args.next();
+                        self.change_pool(&action.to_uppercase(), pool_name, backend_name, weight, completed_clients)
+                    }
+                    _ => AdminReply::Error(POOL_USAGE.to_owned()),
+                }
+            }
+            "CONFIGREWRITE" => {
+                // Writes the current config, with the changes of the POOL command, back to the file it was loaded from.
+                let text = match config_to_toml(&self.config) {
+                    Ok(text) => text,
+                    Err(err) => return AdminReply::Error(format!("Unable to serialize config: {}", err)),
+                };
+                match File::create(&self.config_path).and_then(|mut file| file.write_all(text.as_bytes())) {
+                    Ok(_) => AdminReply::Status("OK".to_owned()),
+                    Err(err) => AdminReply::Error(format!("Unable to write config to {}: {}", self.config_path, err)),
                 }
             }
+            "CLIENT" => {
+                match args.next().map(|subcommand| subcommand.to_uppercase()) {
+                    Some(ref subcommand) if subcommand == "LIST" => AdminReply::Bulk(self.get_client_list()),
+                    Some(ref subcommand) if subcommand == "KILL" => {
+                        let filters: Vec<&str> = args.collect();
+                        self.kill_clients(&filters)
+                    }
+                    _ => AdminReply::Error(CLIENT_USAGE.to_owned()),
+                }
+            }
+            "MONITOR" => {
+                // MONITOR [<pool>|*] [<key pattern>] [<sample percentage>]
+                let pool_index = match args.next() {
+                    None | Some("*") => None,
+                    Some(pool_name) => {
+                        match self.backendpools.iter().position(|pool| pool.name == pool_name) {
+                            Some(pool_index) => Some(pool_index),
+                            None => return AdminReply::Error("Unknown pool!".to_owned()),
+                        }
+                    }
+                };
+                let pattern = args.next().map(|pattern| pattern.as_bytes().to_vec());
+                let sample_percentage = match args.next().map(|percentage| percentage.parse::<usize>()) {
+                    None => 100,
+                    Some(Ok(percentage)) if percentage > 0 && percentage <= 100 => percentage,
+                    Some(_) => return AdminReply::Error("Sample percentage must be between 1 and 100!".to_owned()),
+                };
+                self.monitor = Some(Monitor::new(token.0, pool_index, pattern, sample_percentage));
+                AdminReply::Status("OK".to_owned())
+            }
+            "MIGRATION" => {
+                // MIGRATION <pool> [START|CUTOVER|FINISH]. Returns the state of the pool's migration.
+                let pool_name = args.next();
+                let action = args.next();
+                match self.backendpools.iter_mut().find(|pool| Some(pool.name.as_str()) == pool_name) {
+                    None => AdminReply::Error("Unknown pool!".to_owned()),
+                    Some(ref pool) if pool.migrate_from_pool_index.is_none() => {
+                        AdminReply::Error(format!("Pool {} has no 'migrate_from' pool!", pool.name))
+                    }
+                    Some(pool) => {
+                        match action {
+                            None => AdminReply::Status(pool.migration_state.name().to_owned()),
+                            Some(action) => {
+                                match pool.migration_state.advance(&action.to_uppercase()) {
+                                    Some(state) => {
+                                        info!("Migration of pool {} is now {}", pool.name, state.name());
+                                        pool.migration_state = state;
+                                        AdminReply::Status(state.name().to_owned())
+                                    }
+                                    None => AdminReply::Error(format!("Cannot {} a migration that is {}!", action, pool.migration_state.name())),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            unknown_command => {
+                debug!("Unknown command: {}", unknown_command);
+                AdminReply::Error(format!("Unknown command '{}'", unknown_command))
+            }
         }
     }
 
@@ -676,9 +1478,18 @@ impl RedFlareProxy {
         if *value == 1 {
             return SubType::AdminListener;
         }
-        if *value > 1 && *value < FIRST_SOCKET_INDEX {
+        if *value >= FIRST_ADMIN_CLIENT_INDEX && *value < FIRST_ADMIN_CLIENT_INDEX + MAX_ADMIN_CLIENTS {
             return SubType::AdminClient;
         }
+        if *value == METRICS_LISTENER.0 {
+            return SubType::MetricsListener;
+        }
+        if *value >= FIRST_METRICS_CLIENT_INDEX && *value < STATSD_TIMER.0 {
+            return SubType::MetricsClient;
+        }
+        if *value == STATSD_TIMER.0 {
+            return SubType::StatsdTimer;
+        }
         if *value >= FIRST_SOCKET_INDEX && *value < FIRST_SOCKET_INDEX + num_pools {
             return SubType::PoolListener;
         }
@@ -691,13 +1502,77 @@ impl RedFlareProxy {
         if *value >= FIRST_SOCKET_INDEX + num_pools + 2*num_backends && *value < FIRST_SOCKET_INDEX + num_pools + 3*num_backends {
             return SubType::RequestTimeout;
         }
+        if *value >= FIRST_SOCKET_INDEX + num_pools + 3*num_backends && *value < FIRST_SOCKET_INDEX + num_pools + 4*num_backends {
+            return SubType::HealthCheck;
+        }
+        if *value >= FIRST_LISTENER_INDEX {
+            return SubType::Listener;
+        }
         if *value >= FIRST_CLUSTER_BACKEND_INDEX {
-            return SubType::ClusterServer;
+            return match (*value - FIRST_CLUSTER_BACKEND_INDEX) / CLUSTER_TOKEN_RANGE {
+                0 => SubType::ClusterServer,
+                RETRY_TIMER => SubType::ClusterTimeout,
+                REQUEST_TIMER => SubType::ClusterRequestTimeout,
+                _ => SubType::ClusterHealthCheck,
+            };
         }
         return SubType::PoolClient;
     }
 }
 
+/*
+    Reads the memory usage of the process from /proc, which is only there on Linux. The sizes are in bytes, like in
+    redis' INFO.
+*/
+fn get_memory_info() -> String {
+    let mut status = String::new();
+    match File::open("/proc/self/status") {
+        Ok(mut file) => {
+            match file.read_to_string(&mut status) {
+                Ok(_) => {}
+                Err(_) => return "used_memory_rss:unknown\n".to_owned(),
+            }
+        }
+        Err(_) => return "used_memory_rss:unknown\n".to_owned(),
+    }
+    let mut info = String::new();
+    for line in status.lines() {
+        let name = if line.starts_with("VmRSS:") {
+            "used_memory_rss"
+        } else if line.starts_with("VmHWM:") {
+            "used_memory_peak_rss"
+        } else {
+            continue;
+        };
+        // The sizes are given in kB, as in "VmRSS:	    1234 kB".
+        match line.split_whitespace().nth(1).and_then(|size| size.parse::<usize>().ok()) {
+            Some(size) => info.push_str(&format!("{}:{}\n", name, size * 1024)),
+            None => {}
+        }
+    }
+    return info;
+}
+
+const CLIENT_USAGE: &'static str = "Usage: CLIENT LIST | CLIENT KILL <ip:port> | CLIENT KILL [ADDR <ip:port>] [POOL <pool>] [IDLE <seconds>]";
+const POOL_USAGE: &'static str = "Usage: POOL ADDSERVER <pool> <host> <weight> | REMOVESERVER <pool> <backend> | SETWEIGHT <pool> <backend> <weight>";
+
+// Finds a backend of the pool, given by its index in the pool, its host or its name. Returns its index in the pool.
+fn find_pool_backend(pool: &BackendPool, backend_name: &str) -> Option<usize> {
+    return pool.config.servers.iter().enumerate().position(|(index, backend_config)| {
+        backend_name == index.to_string() ||
+        backend_config.host.map(|host| host.to_string()) == Some(backend_name.to_owned()) ||
+        backend_config.name.as_ref().map(|name| name.as_str()) == Some(backend_name)
+    });
+}
+
+// The config as TOML, for the admin CONFIGINFO and STAGEDCONFIG commands.
+fn config_reply(config: &RedFlareProxyConfig) -> AdminReply {
+    match config_to_toml(config) {
+        Ok(text) => AdminReply::Bulk(text),
+        Err(err) => AdminReply::Error(format!("Unable to serialize config: {}", err)),
+    }
+}
+
 pub fn convert_token_to_pool_index(token_value: PoolTokenValue) -> PoolIndex {
     return token_value - FIRST_SOCKET_INDEX;
 }
@@ -710,9 +1585,23 @@ pub fn convert_token_to_timeout_index(token_value: TimeoutTokenValue, num_pools:
 pub fn convert_token_to_requesttimeout_index(token_value: RequestTimeoutTokenValue, num_pools: usize, num_backends: usize) -> usize {
     return token_value - FIRST_SOCKET_INDEX - num_pools - 2*num_backends;
 }
+pub fn convert_token_to_healthcheck_index(token_value: HealthCheckTokenValue, num_pools: usize, num_backends: usize) -> usize {
+    return token_value - FIRST_SOCKET_INDEX - num_pools - 3*num_backends;
+}
 pub fn convert_token_to_cluster_index(token_value: ClusterTokenValue) -> usize {
     return token_value - FIRST_CLUSTER_BACKEND_INDEX;
 }
+pub fn convert_token_to_cluster_timer_index(token_value: ClusterTokenValue, timer: usize) -> usize {
+    return token_value - FIRST_CLUSTER_BACKEND_INDEX - timer * CLUSTER_TOKEN_RANGE;
+}
+
+// Returns the token of the given kind of timer for a backend.
+pub fn get_timer_token(backend_token: BackendToken, num_backends: usize, timer: usize) -> Token {
+    if backend_token.0 >= FIRST_CLUSTER_BACKEND_INDEX {
+        return Token(backend_token.0 + timer * CLUSTER_TOKEN_RANGE);
+    }
+    return Token(backend_token.0 + timer * num_backends);
+}
 
 /*
     Handles a ready client.
@@ -720,28 +1609,30 @@ pub fn convert_token_to_cluster_index(token_value: ClusterTokenValue) -> usize {
 */
 fn handle_client(
     backendpools: &mut Vec<BackendPool>,
+    listeners: &Vec<Listener>,
     backends: &mut Vec<Backend>,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
     token: &mut Token,
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
+    monitor: &mut Option<Monitor>,
     remove_client_if_empty: bool,
 ) {
-    let num_pools = backendpools.len();
     match clients.get_mut(&token.0) {
         Some((client, pool_token_value)) => {
             if client.get_ref().pending_count > 0 {
                 return;
             }
-            let pool_index = *pool_token_value - FIRST_SOCKET_INDEX;
-            let start_backend_index = backendpools.get(pool_index).unwrap().first_backend_index - FIRST_SOCKET_INDEX - num_pools;
-            let last_index = start_backend_index + backendpools.get(pool_index).unwrap().num_backends;
-            let backends = match backends.get_mut(start_backend_index..last_index) {
-                Some(b) => b,
-                None => panic!("Unable to get full backends from {:?} to {:?}", start_backend_index, last_index),
+            // Clients of a pool's own listen socket send all keys to that pool.
+            let pool_router;
+            let router = if *pool_token_value >= FIRST_LISTENER_INDEX {
+                &listeners.get(*pool_token_value - FIRST_LISTENER_INDEX).unwrap().router
+            } else {
+                pool_router = Router::for_pool(*pool_token_value - FIRST_SOCKET_INDEX);
+                &pool_router
             };
-            if handle_client_readable(&mut backendpools.get_mut(pool_index).unwrap(), client, *token, backends, cluster_backends, completed_clients, stats) || !remove_client_if_empty {
+            if handle_client_readable(backendpools, router, client, *token, backends, cluster_backends, completed_clients, stats, monitor) || !remove_client_if_empty {
                 return;
             }
         }
@@ -808,11 +1699,20 @@ fn init_backend(
         &mut next_cluster_token_value,
         pool_config.timeout,
         pool_config.failure_limit,
-        pool_config.retry_timeout,
+        RetryBackoff::from_config(pool_config),
         pool_token_value,
         num_backends,
         cached_backend_shards,
     );
+    match HealthCheck::from_config(pool_config) {
+        Some(health_check) => backend.enable_health_check(health_check, cluster_backends),
+        None => {}
+    }
+    match CircuitBreaker::from_config(pool_config) {
+        Some(circuit_breaker) => backend.enable_circuit_breaker(circuit_breaker, cluster_backends),
+        None => {}
+    }
+    backend.set_read_balance(pool_config.read_balance);
     backend.init_connection(cluster_backends);
     return backend;
 }
@@ -17,6 +17,7 @@ pub enum WriteError {
     NoSocket,
     BufOutOfBounds,
     BackendNotReady,
+    CircuitOpen,
     WriteFailure(Option<SocketAddr>, std::io::Error),
 }
 impl fmt::Display for WriteError {
@@ -25,6 +26,7 @@ impl fmt::Display for WriteError {
             WriteError::NoSocket => write!(f, "No TcpStream for this backend"),
             WriteError::BufOutOfBounds => write!(f, "This should be impossible. Somehow send wrote more bytes than the buffer size"),
             WriteError::BackendNotReady => write!(f, "Backend is not available."),
+            WriteError::CircuitOpen => write!(f, "Circuit breaker for the backend is open."),
             WriteError::WriteFailure(ref s, ref e) => write!(f, "Failed to write to stream: {:?}. Received error: {}.", s, e),
         }
     }
@@ -35,6 +37,7 @@ impl error::Error for WriteError {
             WriteError::NoSocket => None,
             WriteError::BufOutOfBounds => None,
             WriteError::BackendNotReady => None,
+            WriteError::CircuitOpen => None,
             WriteError::WriteFailure(_, ref e) => Some(e),
         }
     }
@@ -72,6 +75,10 @@ pub enum KeyPos<'a> {
     Single(&'a [u8]),
     Multi(Vec<&'a [u8]>),
     MultiSet(Vec<(&'a [u8], &'a [u8])>),
+    // KEYS and SCAN, which work over all the keys of a backend.
+    Keyspace,
+    // CLIENT, which the proxy answers itself.
+    Proxy,
 }
 
 enum KeyPosition {
@@ -80,6 +87,8 @@ enum KeyPosition {
     MultiInterleaved,
     Unsupported,
     Eval,
+    Keyspace,
+    Proxy,
 }
 
 #[test]
@@ -97,6 +106,9 @@ fn test_parsing_redis() {
     let req = b"*5\r\n$4\r\nMSET\r\n$2\r\nab\r\n$2\r\ncd\r\n$4\r\nkey2\r\n$0\r\n\r\n";
     let res = extract_key(req);
     assert_eq!(res, Ok(KeyPos::MultiSet(vec!((b"ab", b"cd"), (b"key2", b"")))));
+    let req = b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\napp\r\n";
+    let res = extract_key(req);
+    assert_eq!(res, Ok(KeyPos::Proxy));
 }
 
 #[test]
@@ -271,6 +283,8 @@ pub fn extract_key(bytes: &[u8]) -> Result<KeyPos, RedisError> {
 
         match supported_keys(command) {
             KeyPosition::Unsupported => { return Err(RedisError::UnsupportedCommand); }
+            KeyPosition::Keyspace => { return Ok(KeyPos::Keyspace); }
+            KeyPosition::Proxy => { return Ok(KeyPos::Proxy); }
             KeyPosition::Next => {
                 index += num + 2;
 
@@ -425,6 +439,285 @@ pub fn extract_key(bytes: &[u8]) -> Result<KeyPos, RedisError> {
     }
 }
 
+// Splits a request into its arguments, the first one being the command.
+pub fn split_request(bytes: &[u8]) -> Result<Vec<&[u8]>, RedisError> {
+    if bytes.get(0) != Some(&b'*') {
+        return Err(RedisError::InvalidProtocol);
+    }
+    let mut index = 1;
+    let num_args = try!(interpret_num(bytes, &mut index));
+    index += 2;
+    let mut args = Vec::new();
+    for _ in 0..num_args {
+        if bytes.get(index) != Some(&b'$') {
+            return Err(RedisError::InvalidProtocol);
+        }
+        index += 1;
+        let len = try!(interpret_num(bytes, &mut index));
+        if len < 0 {
+            return Err(RedisError::InvalidProtocol);
+        }
+        index += 2;
+        match bytes.get(index..index + len as usize) {
+            Some(arg) => args.push(arg),
+            None => return Err(RedisError::IncompleteMessage),
+        }
+        index += len as usize + 2;
+    }
+    return Ok(args);
+}
+
+// Appends a bulk string holding the prefix followed by the value.
+fn push_bulk(request: &mut Vec<u8>, prefix: &[u8], value: &[u8]) {
+    request.push(b'$');
+    request.extend_from_slice((prefix.len() + value.len()).to_string().as_bytes());
+    request.extend_from_slice(b"\r\n");
+    request.extend_from_slice(prefix);
+    request.extend_from_slice(value);
+    request.extend_from_slice(b"\r\n");
+}
+
+// Escapes the characters of the prefix that are special in a KEYS or SCAN pattern.
+fn escape_glob(prefix: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(prefix.len());
+    for &c in prefix {
+        match c {
+            b'*' | b'?' | b'[' | b']' | b'\\' => escaped.push(b'\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    return escaped;
+}
+
+/*
+    Rewrites the request with the prefix added to each of its keys, for pools with a key_prefix. This covers the keys
+    found by extract_key, all the keys of an EVAL, and the pattern of KEYS and SCAN. A SCAN without a MATCH gets one, so
+    that it only returns the keys under the prefix.
+*/
+pub fn add_key_prefix(bytes: &[u8], prefix: &[u8]) -> Result<Vec<u8>, RedisError> {
+    let args = try!(split_request(bytes));
+    let command = match args.get(0) {
+        Some(command) => *command,
+        None => return Err(RedisError::InvalidProtocol),
+    };
+    // Keys are the arguments from first to last, every step arguments.
+    let mut pattern_index = None;
+    let mut add_match = false;
+    let (first, last, step) = match supported_keys(command) {
+        KeyPosition::Next => (1, 2, 1),
+        KeyPosition::Eval => {
+            let num_keys = match args.get(2).and_then(|n| std::str::from_utf8(n).ok()).and_then(|n| n.parse::<usize>().ok()) {
+                Some(num_keys) => num_keys,
+                None => return Err(RedisError::InvalidScript),
+            };
+            (3, 3 + num_keys, 1)
+        }
+        KeyPosition::Multi => (1, args.len(), 1),
+        KeyPosition::MultiInterleaved => (1, args.len(), 2),
+        KeyPosition::Keyspace => {
+            if command.eq_ignore_ascii_case(b"KEYS") {
+                pattern_index = Some(1);
+            } else {
+                pattern_index = args.iter().position(|arg| arg.eq_ignore_ascii_case(b"MATCH")).map(|index| index + 1);
+                add_match = pattern_index.is_none();
+            }
+            (0, 0, 1)
+        }
+        KeyPosition::Unsupported | KeyPosition::Proxy => return Err(RedisError::UnsupportedCommand),
+    };
+    if last > args.len() || pattern_index.map_or(false, |index| index >= args.len()) {
+        return Err(RedisError::InvalidProtocol);
+    }
+    let escaped_prefix = escape_glob(prefix);
+    let num_args = if add_match { args.len() + 2 } else { args.len() };
+    let mut request = Vec::with_capacity(bytes.len() + (args.len() + 1) * (prefix.len() + 2));
+    request.push(b'*');
+    request.extend_from_slice(num_args.to_string().as_bytes());
+    request.extend_from_slice(b"\r\n");
+    for (index, arg) in args.iter().enumerate() {
+        let arg_prefix: &[u8] = if index >= first && index < last && (index - first) % step == 0 {
+            prefix
+        } else if Some(index) == pattern_index {
+            &escaped_prefix
+        } else {
+            b""
+        };
+        push_bulk(&mut request, arg_prefix, arg);
+    }
+    if add_match {
+        push_bulk(&mut request, b"", b"MATCH");
+        push_bulk(&mut request, &escaped_prefix, b"*");
+    }
+    return Ok(request);
+}
+
+// Copies num bulk strings to the response, with the prefix stripped from those that start with it.
+fn strip_bulk_strings(bytes: &[u8], index: &mut usize, num: isize, prefix: &[u8], response: &mut Vec<u8>) -> Result<(), RedisError> {
+    for _ in 0..num {
+        if bytes.get(*index) != Some(&b'$') {
+            return Err(RedisError::InvalidProtocol);
+        }
+        *index += 1;
+        let len = try!(interpret_num(bytes, index));
+        if len < 0 {
+            return Err(RedisError::InvalidProtocol);
+        }
+        *index += 2;
+        let value = match bytes.get(*index..*index + len as usize) {
+            Some(value) => value,
+            None => return Err(RedisError::IncompleteMessage),
+        };
+        if value.starts_with(prefix) {
+            push_bulk(response, b"", &value[prefix.len()..]);
+        } else {
+            push_bulk(response, b"", value);
+        }
+        *index += len as usize + 2;
+    }
+    return Ok(());
+}
+
+/*
+    Strips the prefix from the keys in the reply to a KEYS or SCAN. A SCAN reply is the cursor followed by the array of
+    keys, and the cursor is left as is. Other replies, like errors, are returned unchanged.
+*/
+pub fn strip_key_prefix(bytes: &[u8], prefix: &[u8]) -> Result<Vec<u8>, RedisError> {
+    if bytes.get(0) != Some(&b'*') {
+        return Ok(bytes.to_vec());
+    }
+    let mut index = 1;
+    let num = try!(interpret_num(bytes, &mut index));
+    index += 2;
+    let mut response = Vec::with_capacity(bytes.len());
+    response.extend_from_slice(&bytes[0..index]);
+    let is_scan = num == 2 && bytes.get(index) == Some(&b'$') && {
+        let mut next_index = index;
+        parse_redis_request(bytes, &mut next_index).is_ok() && bytes.get(next_index) == Some(&b'*')
+    };
+    if is_scan {
+        let cursor_index = index;
+        try!(parse_redis_request(bytes, &mut index));
+        response.extend_from_slice(&bytes[cursor_index..index]);
+        let keys_index = index;
+        index += 1;
+        let num_keys = try!(interpret_num(bytes, &mut index));
+        index += 2;
+        response.extend_from_slice(&bytes[keys_index..index]);
+        try!(strip_bulk_strings(bytes, &mut index, num_keys, prefix, &mut response));
+    } else {
+        try!(strip_bulk_strings(bytes, &mut index, num, prefix, &mut response));
+    }
+    return Ok(response);
+}
+
+#[test]
+fn test_key_prefix() {
+    assert_eq!(add_key_prefix(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"t1:").unwrap(), b"*2\r\n$3\r\nGET\r\n$6\r\nt1:key\r\n".to_vec());
+    assert_eq!(
+        add_key_prefix(b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n", b"t1:").unwrap(),
+        b"*5\r\n$4\r\nMSET\r\n$4\r\nt1:a\r\n$1\r\n1\r\n$4\r\nt1:b\r\n$1\r\n2\r\n".to_vec()
+    );
+    assert_eq!(
+        add_key_prefix(b"*6\r\n$4\r\nEVAL\r\n$6\r\nreturn\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n", b"t1:").unwrap(),
+        b"*6\r\n$4\r\nEVAL\r\n$6\r\nreturn\r\n$1\r\n2\r\n$4\r\nt1:a\r\n$4\r\nt1:b\r\n$1\r\nc\r\n".to_vec()
+    );
+    assert_eq!(add_key_prefix(b"*2\r\n$4\r\nKEYS\r\n$1\r\n*\r\n", b"t*:").unwrap(), b"*2\r\n$4\r\nKEYS\r\n$5\r\nt\\*:*\r\n".to_vec());
+    assert_eq!(
+        add_key_prefix(b"*2\r\n$4\r\nSCAN\r\n$1\r\n0\r\n", b"t1:").unwrap(),
+        b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$4\r\nt1:*\r\n".to_vec()
+    );
+    assert_eq!(
+        add_key_prefix(b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$2\r\na*\r\n", b"t1:").unwrap(),
+        b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$5\r\nt1:a*\r\n".to_vec()
+    );
+    assert_eq!(add_key_prefix(b"*1\r\n$4\r\nKEYS\r\n", b"t1:"), Err(RedisError::InvalidProtocol));
+
+    assert_eq!(strip_key_prefix(b"*2\r\n$4\r\nt1:a\r\n$4\r\nt1:b\r\n", b"t1:").unwrap(), b"*2\r\n$1\r\na\r\n$1\r\nb\r\n".to_vec());
+    // The cursor of a SCAN reply is kept, even when it looks like a key under the prefix.
+    assert_eq!(
+        strip_key_prefix(b"*2\r\n$2\r\n17\r\n*1\r\n$3\r\n1ab\r\n", b"1").unwrap(),
+        b"*2\r\n$2\r\n17\r\n*1\r\n$2\r\nab\r\n".to_vec()
+    );
+    assert_eq!(strip_key_prefix(b"-ERR\r\n", b"t1:").unwrap(), b"-ERR\r\n".to_vec());
+}
+
+/*
+    Returns whether the request may modify data, and so must be sent to a master.
+    Only commands known to be read-only are considered reads. Anything else, including commands that can store their
+    result (SORT, GEORADIUS) and scripts, is considered a write.
+*/
+pub fn is_write_command(bytes: &[u8]) -> bool {
+    let command = match get_command_name(bytes) {
+        Ok(command) => command.to_ascii_uppercase(),
+        Err(_) => { return true; }
+    };
+    match command.as_slice() {
+        b"GET" | b"MGET" | b"TTL" | b"PTTL" | b"TYPE" | b"DUMP" | b"EXISTS" | b"STRLEN" | b"GETBIT" | b"BITPOS" |
+        b"BITCOUNT" | b"GETRANGE" | b"HGET" | b"HLEN" | b"HKEYS" | b"HMGET" | b"HSCAN" | b"HVALS" | b"HEXISTS" |
+        b"HGETALL" | b"HSTRLEN" | b"LLEN" | b"LINDEX" | b"LRANGE" | b"SCARD" | b"SSCAN" | b"SMEMBERS" | b"SISMEMBER" |
+        b"SRANDMEMBER" | b"ZCARD" | b"ZRANK" | b"ZSCAN" | b"ZCOUNT" | b"ZRANGE" | b"ZSCORE" | b"ZREVRANK" |
+        b"ZLEXCOUNT" | b"ZREVRANGE" | b"ZRANGEBYLEX" | b"ZRANGEBYSCORE" | b"ZREVRANGEBYLEX" | b"ZREVRANGEBYSCORE" |
+        b"PFCOUNT" | b"GEOPOS" | b"GEODIST" | b"GEOHASH" | b"GEORADIUSBYMEMBER_RO" | b"GEORADIUS_RO" => false,
+        _ => true,
+    }
+}
+
+// Returns whether the request is a GET, whose reply can be written back as the value of a SET.
+pub fn is_get_command(bytes: &[u8]) -> bool {
+    match get_command_name(bytes) {
+        Ok(command) => command.eq_ignore_ascii_case(b"GET"),
+        Err(_) => false,
+    }
+}
+
+// Returns whether the response is a nil reply, which reads of a missing key get.
+pub fn is_nil_response(bytes: &[u8]) -> bool {
+    return bytes == b"$-1\r\n" || bytes == b"*-1\r\n";
+}
+
+// Extracts the command name from a request, without validating the rest of the request.
+pub fn get_command_name(bytes: &[u8]) -> Result<&[u8], RedisError> {
+    if bytes.get(0) != Some(&b'*') {
+        return Err(RedisError::InvalidProtocol);
+    }
+    let mut index = 0;
+    try!(skip_past_eol(&bytes, &mut index));
+    if bytes.get(index) != Some(&b'$') {
+        return Err(RedisError::InvalidProtocol);
+    }
+    index += 1;
+    let num = try!(interpret_num(bytes, &mut index));
+    if num < 0 {
+        return Err(RedisError::InvalidProtocol);
+    }
+    index += 2;
+    match bytes.get(index..index + num as usize) {
+        Some(command) => Ok(command),
+        None => Err(RedisError::IncompleteMessage),
+    }
+}
+
+#[test]
+fn test_migration_helpers() {
+    assert!(is_get_command(b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n"));
+    assert!(!is_get_command(b"*2\r\n$4\r\nHGET\r\n$3\r\nkey\r\n"));
+    assert!(is_nil_response(b"$-1\r\n"));
+    assert!(is_nil_response(b"*-1\r\n"));
+    assert!(!is_nil_response(b"$0\r\n\r\n"));
+}
+
+#[test]
+fn test_is_write_command() {
+    assert!(!is_write_command(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n"));
+    assert!(!is_write_command(b"*2\r\n$7\r\nhgetall\r\n$3\r\nkey\r\n"));
+    assert!(!is_write_command(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n"));
+    assert!(is_write_command(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n"));
+    assert!(is_write_command(b"*2\r\n$4\r\nINCR\r\n$3\r\nkey\r\n"));
+    assert!(is_write_command(b"*2\r\n$4\r\nSORT\r\n$3\r\nkey\r\n"));
+    assert!(is_write_command(b"*2\r\n$3\r\nGE"));
+}
+
 fn supported_keys(command: &[u8]) -> KeyPosition {
     match command.len() {
         3 => {
@@ -438,6 +731,8 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str4compare(command, 'E', 'V', 'A', 'L') { return KeyPosition::Eval; }
             if str4compare(command, 'M', 'G', 'E', 'T') { return KeyPosition::Multi; }
             if str4compare(command, 'M', 'S', 'E', 'T') { return KeyPosition::MultiInterleaved; }
+            if str4compare(command, 'K', 'E', 'Y', 'S') { return KeyPosition::Keyspace; }
+            if str4compare(command, 'S', 'C', 'A', 'N') { return KeyPosition::Keyspace; }
             if str4compare(command, 'D', 'U', 'M', 'P') { return KeyPosition::Next; }
             if str4compare(command, 'P', 'T', 'T', 'L') { return KeyPosition::Next; }
             if str4compare(command, 'S', 'O', 'R', 'T') { return KeyPosition::Next; }
@@ -505,6 +800,7 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str6compare(command, 'Z', 'S', 'C', 'O', 'R', 'E') { return KeyPosition::Next; }
             if str6compare(command, 'G', 'E', 'O', 'A', 'D', 'D') { return KeyPosition::Next; }
             if str6compare(command, 'G', 'E', 'O', 'P', 'O', 'S') { return KeyPosition::Next; }
+            if str6compare(command, 'C', 'L', 'I', 'E', 'N', 'T') { return KeyPosition::Proxy; }
             return KeyPosition::Unsupported;
         }
         7 => {
@@ -1090,6 +1386,58 @@ pub fn handle_slotsmap(
     return Ok(());
 }
 
+/*
+    Parses a flat array response of bulk strings and integers, such as a pubsub message. A null array is parsed as an
+    empty Vec.
+*/
+pub fn parse_string_array(response: &[u8]) -> Result<Vec<String>, RedisError> {
+    let mut index = 0;
+    if response.get(index) != Some(&('*' as u8)) {
+        return Err(RedisError::InvalidProtocol);
+    }
+    index += 1;
+    let num = try!(interpret_num(response, &mut index));
+    index += 2;
+    let mut strings = Vec::with_capacity(if num > 0 { num as usize } else { 0 });
+    for _ in 0..num {
+        match response.get(index) {
+            Some(b'$') => {
+                index += 1;
+                let len = try!(interpret_num(response, &mut index));
+                index += 2;
+                if len < 0 {
+                    strings.push(String::new());
+                    continue;
+                }
+                let string = match response.get(index..index + len as usize) {
+                    Some(s) => String::from_utf8_lossy(s).into_owned(),
+                    None => { return Err(RedisError::IncompleteMessage); }
+                };
+                strings.push(string);
+                index += len as usize + 2;
+            }
+            Some(b':') => {
+                index += 1;
+                let num = try!(interpret_num(response, &mut index));
+                index += 2;
+                strings.push(num.to_string());
+            }
+            Some(_) => { return Err(RedisError::InvalidProtocol); }
+            None => { return Err(RedisError::IncompleteMessage); }
+        }
+    }
+    return Ok(strings);
+}
+
+#[test]
+fn test_parse_string_array() {
+    assert_eq!(parse_string_array(b"*2\r\n$9\r\n127.0.0.1\r\n$4\r\n6379\r\n"), Ok(vec!["127.0.0.1".to_owned(), "6379".to_owned()]));
+    assert_eq!(parse_string_array(b"*3\r\n$9\r\nsubscribe\r\n$14\r\n+switch-master\r\n:1\r\n"), Ok(vec!["subscribe".to_owned(), "+switch-master".to_owned(), "1".to_owned()]));
+    assert_eq!(parse_string_array(b"*-1\r\n"), Ok(Vec::new()));
+    assert_eq!(parse_string_array(b"+OK\r\n"), Err(RedisError::InvalidProtocol));
+    assert_eq!(parse_string_array(b"*1\r\n$4\r\nab"), Err(RedisError::IncompleteMessage));
+}
+
 fn expect_eol(bytes: &[u8], index: &mut usize) -> Result<(), RedisError> {
     debug!("Expecitng eol: {}", index);
     let mut next = bytes.get(*index).unwrap();
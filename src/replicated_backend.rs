@@ -0,0 +1,278 @@
+use client::BufferedClient;
+use stats::Stats;
+use redflareproxy::ClientTokenValue;
+use redisprotocol::WriteError;
+use redflareproxy::PoolTokenValue;
+use redflareproxy::convert_token_to_cluster_index;
+use redflareproxy::{BackendToken, ClientToken};
+use backend::SingleBackend;
+use backoff::RetryBackoff;
+use config::{BackendConfig, ReadBalance};
+use healthcheck::HealthCheck;
+use circuitbreaker::CircuitBreaker;
+use std::collections::{VecDeque};
+use hashbrown::HashMap;
+use mio::{Token, Poll};
+use std::time::Instant;
+use std::cell::{RefCell};
+use std::rc::Rc;
+use rand::thread_rng;
+use rand::Rng;
+use std;
+
+/*
+    A master with replicas.
+    Write commands are sent to the master, a SingleBackend that shares the token of this backend. Read commands are
+    spread across the available replicas, which are stored in cluster_backends. If no replica is available, reads go
+    to the master.
+*/
+pub struct ReplicatedBackend {
+    master: SingleBackend,
+    replicas: Vec<BackendToken>,
+    read_balance: ReadBalance,
+    // Index of the replica that the next read is sent to, for round robin.
+    next_replica: usize,
+    token: BackendToken,
+}
+impl ReplicatedBackend {
+    pub fn new(
+        config: BackendConfig,
+        host: std::net::SocketAddr,
+        token: BackendToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        poll_registry: &Rc<RefCell<Poll>>,
+        next_cluster_token_value: &mut usize,
+        timeout: usize,
+        failure_limit: usize,
+        retry_backoff: RetryBackoff,
+        pool_token: usize,
+        num_backends: usize,
+        cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
+    ) -> (ReplicatedBackend, Vec<BackendToken>) {
+        let mut all_backend_tokens = Vec::with_capacity(config.replicas.len());
+        for replica_host in &config.replicas {
+            let replica_token = Token(*next_cluster_token_value);
+            *next_cluster_token_value += 1;
+            let (replica, _) = SingleBackend::new(
+                config.clone(),
+                replica_host.clone(),
+                replica_token,
+                poll_registry,
+                timeout,
+                failure_limit,
+                retry_backoff.clone(),
+                pool_token,
+                num_backends,
+                cached_backend_shards,
+            );
+            cluster_backends.push((replica, token.0));
+            all_backend_tokens.push(replica_token);
+        }
+
+        let (master, _) = SingleBackend::new(
+            config,
+            host,
+            token,
+            poll_registry,
+            timeout,
+            failure_limit,
+            retry_backoff,
+            pool_token,
+            num_backends,
+            cached_backend_shards,
+        );
+        debug!("Initializing replicated backend for master {} with {} replicas", host, all_backend_tokens.len());
+        (ReplicatedBackend {
+            master: master,
+            replicas: all_backend_tokens.clone(),
+            read_balance: ReadBalance::RoundRobin,
+            next_replica: 0,
+            token: token,
+        }, all_backend_tokens)
+    }
+
+    pub fn set_read_balance(&mut self, read_balance: ReadBalance) {
+        self.read_balance = read_balance;
+    }
+
+    pub fn reregister_token(&mut self, new_token: BackendToken, cluster_backends: &mut Vec<(SingleBackend, usize)>, new_num_backends: usize) -> Result<(), std::io::Error> {
+        self.token = new_token;
+        for replica_token in self.replicas.iter() {
+            let cluster_index = convert_token_to_cluster_index(replica_token.0);
+            match cluster_backends.get_mut(cluster_index) {
+                Some((replica, _)) => {
+                    replica.num_backends = new_num_backends;
+                }
+                None => {
+                    panic!("ReplicatedBackend is referencing a replica that does not exist! Occurred during reregistering token.");
+                }
+            };
+        }
+        return self.master.reregister_token(new_token, new_num_backends);
+    }
+
+    pub fn change_pool_token(&mut self, new_token_value: PoolTokenValue) {
+        self.master.change_pool_token(new_token_value);
+    }
+
+    // Replicas can't take writes, so the backend is only available if the master is.
+    pub fn is_available(&self) -> bool {
+        return self.master.is_available();
+    }
+
+    pub fn outstanding_requests(&self) -> usize {
+        return self.master.queue.len();
+    }
+
+    pub fn get_master(&mut self) -> &mut SingleBackend {
+        return &mut self.master;
+    }
+
+    // Health checks and circuit breakers are run against the master and each replica separately.
+    pub fn enable_health_check(&mut self, health_check: HealthCheck, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for replica_token in self.replicas.iter() {
+            let cluster_index = convert_token_to_cluster_index(replica_token.0);
+            cluster_backends.get_mut(cluster_index).unwrap().0.enable_health_check(health_check.clone());
+        }
+        self.master.enable_health_check(health_check);
+    }
+
+    pub fn enable_circuit_breaker(&mut self, circuit_breaker: CircuitBreaker, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for replica_token in self.replicas.iter() {
+            let cluster_index = convert_token_to_cluster_index(replica_token.0);
+            cluster_backends.get_mut(cluster_index).unwrap().0.enable_circuit_breaker(circuit_breaker.clone());
+        }
+        self.master.enable_circuit_breaker(circuit_breaker);
+    }
+
+    // Describes the master and each replica connection, along with its role.
+    pub fn describe_hosts<'a, T>(&'a self, cluster_backends: &'a Vec<(SingleBackend, usize)>, describe: &dyn Fn(&str, &'a SingleBackend) -> T) -> Vec<T> {
+        let mut info = Vec::with_capacity(self.replicas.len() + 1);
+        info.push(describe("master", &self.master));
+        for replica_token in self.replicas.iter() {
+            let cluster_index = convert_token_to_cluster_index(replica_token.0);
+            match cluster_backends.get(cluster_index) {
+                Some((replica, _)) => info.push(describe("replica", replica)),
+                None => {
+                    panic!("ReplicatedBackend is referencing a replica that does not exist! Occurred when getting backoff info.");
+                }
+            };
+        }
+        return info;
+    }
+
+    pub fn init_connection(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for replica_token in self.replicas.iter() {
+            let cluster_index = convert_token_to_cluster_index(replica_token.0);
+            match cluster_backends.get_mut(cluster_index) {
+                Some((replica, _)) => replica.init_connection(),
+                None => {
+                    panic!("ReplicatedBackend is referencing a replica that does not exist! Occurred when initializing connections.");
+                }
+            };
+        }
+        self.master.init_connection();
+    }
+
+    pub fn handle_backend_response(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        let mut resp_handler = |_response: &[u8]| -> () {};
+        if token == self.token {
+            self.master.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
+        } else {
+            let cluster_index = convert_token_to_cluster_index(token.0);
+            cluster_backends.get_mut(cluster_index).unwrap().0.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
+        }
+    }
+
+    pub fn handle_backend_failure(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if token == self.token {
+            self.master.handle_backend_failure(clients, completed_clients, stats);
+        } else {
+            let cluster_index = convert_token_to_cluster_index(token.0);
+            cluster_backends.get_mut(cluster_index).unwrap().0.handle_backend_failure(clients, completed_clients, stats);
+        }
+    }
+
+    pub fn handle_timeout(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) -> bool {
+        if token == self.token {
+            return self.master.handle_timeout(clients, completed_clients, stats);
+        }
+        let cluster_index = convert_token_to_cluster_index(token.0);
+        return cluster_backends.get_mut(cluster_index).unwrap().0.handle_timeout(clients, completed_clients, stats);
+    }
+
+    pub fn write_message(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<(), WriteError> {
+        return self.master.write_message(message, client_token, request_id, stats);
+    }
+
+    /*
+        Sends a read command to a replica, picked by the read balance policy.
+        Falls back to the master if no replica is available, or the chosen replica could not be written to.
+    */
+    pub fn write_read_message(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<(), WriteError> {
+        match self.choose_replica(cluster_backends) {
+            Some(cluster_index) => {
+                let replica = &mut cluster_backends.get_mut(cluster_index).unwrap().0;
+                match replica.write_message(message, client_token, request_id, stats) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => debug!("Failed to write to replica. Sending the read to the master instead. Error: {}", err),
+                }
+            }
+            None => {}
+        }
+        return self.master.write_message(message, client_token, request_id, stats);
+    }
+
+    // Returns the cluster index of the replica that should receive the next read, if any replica is available.
+    fn choose_replica(&mut self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Option<usize> {
+        let available: Vec<usize> = self.replicas.iter()
+            .map(|replica_token| convert_token_to_cluster_index(replica_token.0))
+            .filter(|cluster_index| cluster_backends.get(*cluster_index).unwrap().0.is_available())
+            .collect();
+        if available.len() == 0 {
+            return None;
+        }
+        let chosen = match self.read_balance {
+            ReadBalance::RoundRobin => {
+                self.next_replica = self.next_replica.wrapping_add(1);
+                available[self.next_replica % available.len()]
+            }
+            ReadBalance::Random => available[thread_rng().gen_range(0, available.len())],
+        };
+        return Some(chosen);
+    }
+}
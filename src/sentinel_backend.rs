@@ -0,0 +1,356 @@
+use client::BufferedClient;
+use stats::Stats;
+use redflareproxy::ClientTokenValue;
+use redisprotocol::WriteError;
+use redisprotocol::parse_string_array;
+use std::net::SocketAddr;
+use redflareproxy::PoolTokenValue;
+use redflareproxy::convert_token_to_cluster_index;
+use redflareproxy::{BackendToken, ClientToken, NULL_TOKEN};
+use backend::SingleBackend;
+use backoff::RetryBackoff;
+use config::BackendConfig;
+use std::collections::{VecDeque};
+use hashbrown::HashMap;
+use mio::{Token, Poll};
+use std::time::Instant;
+use std::cell::{RefCell};
+use std::rc::Rc;
+use std;
+
+/*
+    A master/replica set managed by Redis Sentinel.
+    Requests are sent to the master, a SingleBackend that shares the token of this backend. The connections to the
+    sentinels are stored in cluster_backends. Each sentinel is asked for the master's address once it is ready, and is
+    subscribed to +switch-master, so that the master connection follows failovers.
+*/
+pub struct SentinelBackend {
+    master: SingleBackend,
+    // Whether the master address has been resolved by a sentinel yet.
+    master_resolved: bool,
+    master_name: String,
+    sentinels: Vec<BackendToken>,
+    token: BackendToken,
+}
+impl SentinelBackend {
+    pub fn new(
+        config: BackendConfig,
+        token: BackendToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        poll_registry: &Rc<RefCell<Poll>>,
+        next_cluster_token_value: &mut usize,
+        timeout: usize,
+        failure_limit: usize,
+        retry_backoff: RetryBackoff,
+        pool_token: usize,
+        num_backends: usize,
+        cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
+    ) -> (SentinelBackend, Vec<BackendToken>) {
+        // Sentinels don't have databases, and don't share the password of the master.
+        let mut sentinel_config = config.clone();
+        sentinel_config.db = 0;
+        sentinel_config.auth = String::new();
+
+        let mut all_backend_tokens = Vec::with_capacity(config.sentinel_hosts.len());
+        for host in &config.sentinel_hosts {
+            let sentinel_token = Token(*next_cluster_token_value);
+            *next_cluster_token_value += 1;
+            let (sentinel, _) = SingleBackend::new(
+                sentinel_config.clone(),
+                host.clone(),
+                sentinel_token,
+                poll_registry,
+                timeout,
+                failure_limit,
+                retry_backoff.clone(),
+                pool_token,
+                num_backends,
+                cached_backend_shards,
+            );
+            cluster_backends.push((sentinel, token.0));
+            all_backend_tokens.push(sentinel_token);
+        }
+
+        // The master's address is unknown until a sentinel responds. It is not connected to until then.
+        let master_name = config.sentinel_master_name.clone().unwrap();
+        let (master, _) = SingleBackend::new(
+            config,
+            "0.0.0.0:0".parse().unwrap(),
+            token,
+            poll_registry,
+            timeout,
+            failure_limit,
+            retry_backoff,
+            pool_token,
+            num_backends,
+            cached_backend_shards,
+        );
+        debug!("Initializing sentinel backend for master {}", master_name);
+        (SentinelBackend {
+            master: master,
+            master_resolved: false,
+            master_name: master_name,
+            sentinels: all_backend_tokens.clone(),
+            token: token,
+        }, all_backend_tokens)
+    }
+
+    pub fn get_master(&mut self) -> &mut SingleBackend {
+        return &mut self.master;
+    }
+
+    pub fn reregister_token(&mut self, new_token: BackendToken, cluster_backends: &mut Vec<(SingleBackend, usize)>, new_num_backends: usize) -> Result<(), std::io::Error> {
+        self.token = new_token;
+        for sentinel_token in self.sentinels.iter() {
+            let cluster_index = convert_token_to_cluster_index(sentinel_token.0);
+            match cluster_backends.get_mut(cluster_index) {
+                Some((sentinel, _)) => {
+                    sentinel.num_backends = new_num_backends;
+                }
+                None => {
+                    panic!("SentinelBackend is referencing a sentinel that does not exist! Occurred during reregistering token.");
+                }
+            };
+        }
+        return self.master.reregister_token(new_token, new_num_backends);
+    }
+
+    pub fn change_pool_token(&mut self, new_token_value: PoolTokenValue) {
+        self.master.change_pool_token(new_token_value);
+    }
+
+    pub fn is_available(&self) -> bool {
+        return self.master.is_available();
+    }
+
+    pub fn outstanding_requests(&self) -> usize {
+        return self.master.queue.len();
+    }
+
+    // Describes the master and each sentinel connection, along with its role.
+    pub fn describe_hosts<'a, T>(&'a self, cluster_backends: &'a Vec<(SingleBackend, usize)>, describe: &dyn Fn(&str, &'a SingleBackend) -> T) -> Vec<T> {
+        let mut info = Vec::with_capacity(self.sentinels.len() + 1);
+        info.push(describe("master", &self.master));
+        for sentinel_token in self.sentinels.iter() {
+            let cluster_index = convert_token_to_cluster_index(sentinel_token.0);
+            match cluster_backends.get(cluster_index) {
+                Some((sentinel, _)) => info.push(describe("sentinel", sentinel)),
+                None => {
+                    panic!("SentinelBackend is referencing a sentinel that does not exist! Occurred when getting backoff info.");
+                }
+            };
+        }
+        return info;
+    }
+
+    pub fn init_connection(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for sentinel_token in self.sentinels.iter() {
+            let cluster_index = convert_token_to_cluster_index(sentinel_token.0);
+            match cluster_backends.get_mut(cluster_index) {
+                Some((sentinel, _)) => sentinel.init_connection(),
+                None => {
+                    panic!("SentinelBackend is referencing a sentinel that does not exist! Occurred when initializing connections.");
+                }
+            };
+        }
+        if self.master_resolved {
+            self.master.init_connection();
+        }
+    }
+
+    pub fn handle_retry_timeout(&mut self, stats: &mut Stats) {
+        if self.master_resolved {
+            self.master.handle_retry_timeout(stats);
+        }
+    }
+
+    // Reconnects to the master, once the sentinels have told which host it is.
+    pub fn reconnect(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if self.master_resolved {
+            self.master.reconnect(clients, completed_clients, stats);
+        }
+    }
+
+    pub fn handle_backend_response(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if token == self.token {
+            let mut resp_handler = |_response: &[u8]| -> () {};
+            self.master.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
+            return;
+        }
+
+        let cluster_index = convert_token_to_cluster_index(token.0);
+        let mut new_master = None;
+        {
+            let master_name = &self.master_name;
+            let mut resp_handler = |response: &[u8]| -> () {
+                match get_master_from_sentinel_response(response, master_name) {
+                    Some(addr) => new_master = Some(addr),
+                    None => {}
+                }
+            };
+            let sentinel = match cluster_backends.get_mut(cluster_index) {
+                Some((sentinel, _)) => sentinel,
+                None => {
+                    panic!("SentinelBackend is referencing a sentinel that does not exist! Occurred when handling backend response.");
+                }
+            };
+            let was_available = sentinel.is_available();
+            sentinel.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
+
+            // Once a sentinel is ready, ask it for the master, and follow any failovers.
+            if !was_available && sentinel.is_available() {
+                if initialize_sentinel(sentinel, master_name, stats).is_err() {
+                    error!("Failed to query sentinel {:?} for master {}", token, master_name);
+                }
+            }
+        }
+
+        match new_master {
+            Some(addr) => self.switch_master(addr, clients, completed_clients, stats),
+            None => {}
+        }
+    }
+
+    // Points the master connection to a new address. Clients stay connected, but any pending requests to the old
+    // master receive an error.
+    fn switch_master(
+        &mut self,
+        addr: SocketAddr,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if self.master_resolved && self.master.get_host() == addr {
+            return;
+        }
+        info!("Sentinel reported {} as the master for {}", addr, self.master_name);
+        self.master_resolved = true;
+        self.master.change_host(addr, clients, completed_clients, stats);
+    }
+
+    pub fn handle_backend_failure(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if token == self.token {
+            self.master.handle_backend_failure(clients, completed_clients, stats);
+        } else {
+            let cluster_index = convert_token_to_cluster_index(token.0);
+            cluster_backends.get_mut(cluster_index).unwrap().0.handle_backend_failure(clients, completed_clients, stats);
+        }
+    }
+
+    pub fn handle_timeout(
+        &mut self,
+        token: BackendToken,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) -> bool {
+        if token == self.token {
+            return self.master.handle_timeout(clients, completed_clients, stats);
+        }
+        let cluster_index = convert_token_to_cluster_index(token.0);
+        return cluster_backends.get_mut(cluster_index).unwrap().0.handle_timeout(clients, completed_clients, stats);
+    }
+
+    pub fn write_message(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<(), WriteError> {
+        return self.master.write_message(message, client_token, request_id, stats);
+    }
+}
+
+/*
+    Asks the sentinel for the master's address, and subscribes to failover notifications.
+    After subscribing, the connection only receives pubsub messages.
+*/
+fn initialize_sentinel(sentinel: &mut SingleBackend, master_name: &String, stats: &mut Stats) -> Result<(), WriteError> {
+    let mut request = String::new();
+    request.push_str("*3\r\n$8\r\nSENTINEL\r\n$23\r\nget-master-addr-by-name\r\n$");
+    request.push_str(&master_name.len().to_string());
+    request.push_str("\r\n");
+    request.push_str(master_name);
+    request.push_str("\r\n");
+    try!(sentinel.write_message(request.as_bytes(), NULL_TOKEN, (Instant::now(), 0), stats));
+    try!(sentinel.write_message(b"*2\r\n$9\r\nSUBSCRIBE\r\n$14\r\n+switch-master\r\n", NULL_TOKEN, (Instant::now(), 0), stats));
+    sentinel.set_subscribed(true);
+    return Ok(());
+}
+
+/*
+    Extracts the master address from either a SENTINEL get-master-addr-by-name response, or a +switch-master message.
+    Returns None for any other response, such as the SUBSCRIBE confirmation.
+*/
+fn get_master_from_sentinel_response(response: &[u8], master_name: &String) -> Option<SocketAddr> {
+    let strings = match parse_string_array(response) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Received unexpected response from sentinel: {:?}. Error: {:?}", std::str::from_utf8(response), err);
+            return None;
+        }
+    };
+    let host = if strings.len() == 2 {
+        // Response to SENTINEL get-master-addr-by-name: ip, port.
+        format!("{}:{}", strings[0], strings[1])
+    } else if strings.len() == 3 && strings[0] == "message" && strings[1] == "+switch-master" {
+        // Message format: <master name> <old ip> <old port> <new ip> <new port>
+        let fields: Vec<&str> = strings[2].split(' ').collect();
+        if fields.len() != 5 || fields[0] != master_name {
+            return None;
+        }
+        format!("{}:{}", fields[3], fields[4])
+    } else {
+        if strings.len() == 0 {
+            error!("Sentinel does not know of master {}", master_name);
+        }
+        return None;
+    };
+    match host.parse() {
+        Ok(addr) => Some(addr),
+        Err(err) => {
+            error!("Unable to parse master host from sentinel: {}. Received error: {}", host, err);
+            None
+        }
+    }
+}
+
+#[test]
+fn test_get_master_from_sentinel_response() {
+    let master_name = "mymaster".to_owned();
+    assert_eq!(
+        get_master_from_sentinel_response(b"*2\r\n$9\r\n127.0.0.1\r\n$4\r\n6379\r\n", &master_name),
+        Some("127.0.0.1:6379".parse().unwrap())
+    );
+    assert_eq!(
+        get_master_from_sentinel_response(b"*3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n$38\r\nmymaster 127.0.0.1 6379 127.0.0.1 6380\r\n", &master_name),
+        Some("127.0.0.1:6380".parse().unwrap())
+    );
+    assert_eq!(
+        get_master_from_sentinel_response(b"*3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n$35\r\nother 127.0.0.1 6379 127.0.0.1 6380\r\n", &master_name),
+        None
+    );
+    assert_eq!(get_master_from_sentinel_response(b"*3\r\n$9\r\nsubscribe\r\n$14\r\n+switch-master\r\n:1\r\n", &master_name), None);
+    assert_eq!(get_master_from_sentinel_response(b"*-1\r\n", &master_name), None);
+}
@@ -1,5 +1,12 @@
+use histogram::LatencyHistogram;
+use std::collections::{BTreeMap, HashMap};
+
+// Commands past this many distinct names share the OTHER latency histogram.
+const MAX_LATENCY_COMMANDS: usize = 256;
+
 pub struct Stats {
     pub accepted_clients: usize,
+    pub rejected_clients: usize,
     pub client_connections: usize,
     pub requests: usize,
     pub responses: usize,
@@ -7,12 +14,25 @@ pub struct Stats {
     pub recv_client_bytes: usize,
     pub send_backend_bytes: usize,
     pub recv_backend_bytes: usize,
+    pub circuit_breaker_opened: usize,
+    pub circuit_breaker_half_opened: usize,
+    pub circuit_breaker_closed: usize,
+    pub circuit_breaker_rejected: usize,
+    pub mirrored_requests: usize,
+    pub mirror_failures: usize,
+    pub migration_fallbacks: usize,
+    pub migration_backfills: usize,
+    pub pool_fallbacks: usize,
+    // Latency of each command by name, indexed by the command index of the backend queues.
+    pub command_latency: Vec<(String, LatencyHistogram)>,
+    command_indexes: HashMap<Vec<u8>, usize>,
 }
 
 impl Stats {
     pub fn new() -> Stats {
         Stats {
             accepted_clients: 0,
+            rejected_clients: 0,
             client_connections: 0,
             requests: 0,
             responses: 0,
@@ -20,11 +40,23 @@ impl Stats {
             recv_client_bytes: 0,
             send_backend_bytes: 0,
             recv_backend_bytes: 0,
+            circuit_breaker_opened: 0,
+            circuit_breaker_half_opened: 0,
+            circuit_breaker_closed: 0,
+            circuit_breaker_rejected: 0,
+            mirrored_requests: 0,
+            mirror_failures: 0,
+            migration_fallbacks: 0,
+            migration_backfills: 0,
+            pool_fallbacks: 0,
+            command_latency: Vec::new(),
+            command_indexes: HashMap::new(),
         }
     }
 
     pub fn reset(&mut self) {
         self.accepted_clients = 0;
+        self.rejected_clients = 0;
         self.client_connections = 0;
         self.requests = 0;
         self.responses = 0;
@@ -32,18 +64,183 @@ impl Stats {
         self.recv_client_bytes = 0;
         self.send_backend_bytes = 0;
         self.recv_backend_bytes = 0;
+        self.circuit_breaker_opened = 0;
+        self.circuit_breaker_half_opened = 0;
+        self.circuit_breaker_closed = 0;
+        self.circuit_breaker_rejected = 0;
+        self.mirrored_requests = 0;
+        self.mirror_failures = 0;
+        self.migration_fallbacks = 0;
+        self.migration_backfills = 0;
+        self.pool_fallbacks = 0;
+        self.reset_latency();
+    }
+
+    // The counters by name, in the order shown by STATS.
+    pub fn counters(&self) -> Vec<(&'static str, usize)> {
+        return vec![
+            ("accepted_clients", self.accepted_clients),
+            ("rejected_clients", self.rejected_clients),
+            ("client_connections", self.client_connections),
+            ("requests", self.requests),
+            ("responses", self.responses),
+            ("send_client_bytes", self.send_client_bytes),
+            ("recv_client_bytes", self.recv_client_bytes),
+            ("send_backend_bytes", self.send_backend_bytes),
+            ("recv_backend_bytes", self.recv_backend_bytes),
+            ("circuit_breaker_opened", self.circuit_breaker_opened),
+            ("circuit_breaker_half_opened", self.circuit_breaker_half_opened),
+            ("circuit_breaker_closed", self.circuit_breaker_closed),
+            ("circuit_breaker_rejected", self.circuit_breaker_rejected),
+            ("mirrored_requests", self.mirrored_requests),
+            ("mirror_failures", self.mirror_failures),
+            ("migration_fallbacks", self.migration_fallbacks),
+            ("migration_backfills", self.migration_backfills),
+            ("pool_fallbacks", self.pool_fallbacks),
+        ];
+    }
+
+    // Histograms are reset in place, since requests in the backend queues still point to their command index.
+    pub fn reset_latency(&mut self) {
+        for &mut (_, ref mut histogram) in self.command_latency.iter_mut() {
+            histogram.reset();
+        }
+    }
+
+    /*
+        Returns the index in command_latency of the command name. Names are looked up as sent first, so that the name
+        only gets uppercased the first time a client sends it that way.
+    */
+    pub fn command_index(&mut self, name: &[u8]) -> usize {
+        match self.command_indexes.get(name) {
+            Some(index) => return *index,
+            None => {}
+        }
+        let uppercase = String::from_utf8_lossy(name).to_ascii_uppercase();
+        let index = match self.command_latency.iter().position(|&(ref command, _)| command == &uppercase) {
+            Some(index) => index,
+            None if self.command_latency.len() < MAX_LATENCY_COMMANDS => {
+                self.command_latency.push((uppercase, LatencyHistogram::default()));
+                self.command_latency.len() - 1
+            }
+            None => {
+                match self.command_latency.iter().position(|&(ref command, _)| command == "OTHER") {
+                    Some(index) => index,
+                    None => {
+                        self.command_latency.push(("OTHER".to_owned(), LatencyHistogram::default()));
+                        self.command_latency.len() - 1
+                    }
+                }
+            }
+        };
+        if self.command_indexes.len() < MAX_LATENCY_COMMANDS * 4 {
+            self.command_indexes.insert(name.to_vec(), index);
+        }
+        return index;
     }
 }
 impl std::fmt::Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        try!(write!(f, "Stats:\n"));
-        try!(write!(f, "accepted_clients: {}\n", self.accepted_clients));
-        try!(write!(f, "client_connections: {}\n", self.client_connections));
+        let counters: Vec<String> = self.counters().iter().map(|&(name, value)| format!("{}: {}", name, value)).collect();
+        write!(f, "Stats:\n{}", counters.join("\n"))
+    }
+}
+/*
+    Counters of a single backend connection, shown by STATS <pool> <backend>. The stats of a pool add up the counters of
+    its backends.
+*/
+#[derive(Clone, Default)]
+pub struct BackendStats {
+    pub requests: usize,
+    pub responses: usize,
+    // Error replies, by their type, such as ERR or WRONGTYPE.
+    pub errors: BTreeMap<String, usize>,
+    pub timeouts: usize,
+    // Times the backend was marked down.
+    pub ejections: usize,
+    pub reconnects: usize,
+    pub send_bytes: usize,
+    pub recv_bytes: usize,
+    // Latency of the responses to clients, in microseconds. Shown by LATENCY rather than STATS.
+    pub latency: LatencyHistogram,
+}
+impl BackendStats {
+    pub fn record_response(&mut self, response: &[u8]) {
+        self.responses += 1;
+        if response.len() == 0 || response[0] != b'-' {
+            return;
+        }
+        let error_type = match response[1..].iter().position(|&c| c == b' ' || c == b'\r') {
+            Some(end) => String::from_utf8_lossy(&response[1..end + 1]).into_owned(),
+            None => String::from_utf8_lossy(&response[1..]).into_owned(),
+        };
+        *self.errors.entry(error_type).or_insert(0) += 1;
+    }
+
+    pub fn add(&mut self, other: &BackendStats) {
+        self.requests += other.requests;
+        self.responses += other.responses;
+        for (error_type, count) in other.errors.iter() {
+            *self.errors.entry(error_type.clone()).or_insert(0) += *count;
+        }
+        self.timeouts += other.timeouts;
+        self.ejections += other.ejections;
+        self.reconnects += other.reconnects;
+        self.send_bytes += other.send_bytes;
+        self.recv_bytes += other.recv_bytes;
+        self.latency.add(&other.latency);
+    }
+}
+impl std::fmt::Display for BackendStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         try!(write!(f, "requests: {}\n", self.requests));
         try!(write!(f, "responses: {}\n", self.responses));
-        try!(write!(f, "send_client_bytes: {}\n", self.send_client_bytes));
-        try!(write!(f, "recv_client_bytes: {}\n", self.recv_client_bytes));
-        try!(write!(f, "send_backend_bytes: {}\n", self.send_backend_bytes));
-        write!(f, "recv_backend_bytes: {}", self.recv_backend_bytes)
+        try!(write!(f, "errors: {}\n", self.errors.values().sum::<usize>()));
+        for (error_type, count) in self.errors.iter() {
+            try!(write!(f, "errors_{}: {}\n", error_type, count));
+        }
+        try!(write!(f, "timeouts: {}\n", self.timeouts));
+        try!(write!(f, "ejections: {}\n", self.ejections));
+        try!(write!(f, "reconnects: {}\n", self.reconnects));
+        try!(write!(f, "send_bytes: {}\n", self.send_bytes));
+        write!(f, "recv_bytes: {}", self.recv_bytes)
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_backend_stats() {
+    let mut stats = BackendStats::default();
+    stats.record_response(b"+OK\r\n");
+    stats.record_response(b"-ERR unknown command 'FOO'\r\n");
+    stats.record_response(b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    stats.record_response(b"-ERR syntax error\r\n");
+    stats.record_response(b"-LOADING\r\n");
+    assert_eq!(stats.responses, 5);
+    assert_eq!(stats.errors.get("ERR"), Some(&2));
+    assert_eq!(stats.errors.get("WRONGTYPE"), Some(&1));
+    assert_eq!(stats.errors.get("LOADING"), Some(&1));
+
+    let mut total = BackendStats::default();
+    total.add(&stats);
+    total.add(&stats);
+    assert_eq!(total.responses, 10);
+    assert_eq!(total.errors.get("ERR"), Some(&4));
+    assert!(format!("{}", total).contains("errors: 8\nerrors_ERR: 4\nerrors_LOADING: 2\nerrors_WRONGTYPE: 2\n"));
+}
+
+#[test]
+fn test_command_index() {
+    let mut stats = Stats::new();
+    assert_eq!(stats.command_index(b"get"), 0);
+    assert_eq!(stats.command_index(b"SET"), 1);
+    assert_eq!(stats.command_index(b"GET"), 0);
+    assert_eq!(stats.command_index(b"Get"), 0);
+    assert_eq!(stats.command_latency[0].0, "GET");
+
+    for i in 0..MAX_LATENCY_COMMANDS {
+        stats.command_index(format!("CMD{}", i).as_bytes());
+    }
+    assert_eq!(stats.command_latency.len(), MAX_LATENCY_COMMANDS + 1);
+    assert_eq!(stats.command_latency[MAX_LATENCY_COMMANDS].0, "OTHER");
+    assert_eq!(stats.command_index(b"ANOTHER"), MAX_LATENCY_COMMANDS);
+}
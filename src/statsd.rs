@@ -0,0 +1,228 @@
+use backend::create_timer;
+use config::StatsdConfig;
+use histogram::LatencyHistogram;
+use metrics::{Metric, MetricValue};
+use redflareproxy::STATSD_TIMER;
+
+use mio::*;
+use mio::net::UdpSocket;
+use mio_more::timer::Timer;
+use hashbrown::HashMap;
+use std::time::{Duration, Instant};
+
+// Keeps each packet within the MTU of most networks, like the StatsD clients do.
+const MAX_PACKET_SIZE: usize = 1432;
+
+/*
+    Pushes the metrics of the proxy to a StatsD server over UDP, every interval. Counters are sent as the increase since
+    the last push, gauges as they are, and latency histograms as timings in milliseconds. Each non-empty bucket of a
+    histogram is sent once, with a sample rate standing for the number of requests in it, so that a push stays small no
+    matter the traffic.
+    With dogstatsd, the pool, backend and command are sent as tags. Otherwise they are added to the metric name, as in
+    redflareproxy.backend_requests.pool1.0.master.127_0_0_1_6381.
+*/
+pub struct StatsdExporter {
+    pub config: StatsdConfig,
+    socket: UdpSocket,
+    timer: Timer<Instant>,
+    // The last pushed value of each counter and latency histogram, by metric name.
+    last_counters: HashMap<String, usize>,
+    last_latencies: HashMap<String, LatencyHistogram>,
+}
+
+impl StatsdExporter {
+    pub fn new(config: StatsdConfig, poll: &Poll) -> StatsdExporter {
+        let bind_addr = if config.address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(&bind_addr.parse().unwrap()) {
+            Ok(socket) => socket,
+            Err(error) => {
+                panic!("Unable to bind the statsd socket. Reason: {:?}", error);
+            }
+        };
+        let timer = create_timer();
+        match poll.register(&timer, STATSD_TIMER, Ready::readable(), PollOpt::edge()) {
+            Ok(_) => {}
+            Err(error) => {
+                panic!("Failed to register statsd timer to poll. Reason: {:?}", error);
+            }
+        };
+        let mut exporter = StatsdExporter {
+            config: config,
+            socket: socket,
+            timer: timer,
+            last_counters: HashMap::new(),
+            last_latencies: HashMap::new(),
+        };
+        exporter.set_timer();
+        return exporter;
+    }
+
+    // Returns whether it is time to push, and sets the timer for the next push if so.
+    pub fn handle_timeout(&mut self) -> bool {
+        let mut expired = false;
+        while self.timer.poll().is_some() {
+            expired = true;
+        }
+        if expired {
+            self.set_timer();
+        }
+        return expired;
+    }
+
+    fn set_timer(&mut self) {
+        let interval = Duration::from_millis(self.config.interval as u64);
+        match self.timer.set_timeout(interval, Instant::now() + interval) {
+            Ok(_) => {}
+            Err(err) => {
+                // Expected to occur only in cases of usize integer overflow.
+                panic!("Failure setting timer timeout: {}.", err);
+            }
+        }
+    }
+
+    /*
+        Sends the metrics. Packets that the socket can't take right away are dropped, as StatsD is lossy anyway, rather
+        than holding up the event loop.
+    */
+    pub fn push(&mut self, metrics: &[Metric]) {
+        let lines = format_metrics(metrics, &self.config, &mut self.last_counters, &mut self.last_latencies);
+        let mut packet = String::new();
+        for line in lines.iter() {
+            if packet.len() > 0 && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                self.send(&packet);
+                packet.clear();
+            }
+            if packet.len() > 0 {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        if packet.len() > 0 {
+            self.send(&packet);
+        }
+    }
+
+    fn send(&self, packet: &str) {
+        match self.socket.send_to(packet.as_bytes(), &self.config.address) {
+            Ok(_) => {}
+            Err(error) => debug!("Unable to send statsd packet to {}. Received error: {}", self.config.address, error),
+        }
+    }
+}
+
+/*
+    Characters that StatsD uses as separators are replaced in names and tags. Tag values may have colons, since only the
+    first one separates the tag name from its value.
+*/
+fn sanitize(value: &str, in_name: bool) -> String {
+    return value.chars().map(|c| match c {
+        '|' | '@' | ',' | '#' | '\n' | ' ' => '_',
+        '.' | ':' if in_name => '_',
+        c => c,
+    }).collect();
+}
+
+/*
+    Formats the metrics as StatsD lines, updating the last pushed values. Counters that went down, such as after
+    RESETSTATS, are sent as their whole value.
+*/
+fn format_metrics(
+    metrics: &[Metric],
+    config: &StatsdConfig,
+    last_counters: &mut HashMap<String, usize>,
+    last_latencies: &mut HashMap<String, LatencyHistogram>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    for metric in metrics.iter() {
+        let (name, tags) = if config.dogstatsd {
+            let tags: Vec<String> = metric.labels.iter().map(|&(label, ref value)| format!("{}:{}", label, sanitize(value, false))).collect();
+            let tags = if tags.len() > 0 { format!("|#{}", tags.join(",")) } else { String::new() };
+            (format!("{}.{}", config.prefix, metric.name), tags)
+        } else {
+            let mut name = format!("{}.{}", config.prefix, metric.name);
+            for &(_, ref value) in metric.labels.iter() {
+                name.push('.');
+                name.push_str(&sanitize(value, true));
+            }
+            (name, String::new())
+        };
+        // Tags are part of the key, since the same name is used for each pool and backend with dogstatsd.
+        let key = format!("{}{}", name, tags);
+        match metric.value {
+            MetricValue::Counter(value) => {
+                let last = last_counters.insert(key, value).unwrap_or(0);
+                let delta = if value >= last { value - last } else { value };
+                if delta > 0 {
+                    lines.push(format!("{}:{}|c{}", name, delta, tags));
+                }
+            }
+            MetricValue::Gauge(value) => {
+                lines.push(format!("{}:{}|g{}", name, value, tags));
+            }
+            MetricValue::Latency(ref histogram) => {
+                let delta = match last_latencies.get(&key) {
+                    Some(last) if last.count() <= histogram.count() => histogram.subtract(last),
+                    _ => histogram.clone(),
+                };
+                for (value, count) in delta.buckets() {
+                    let milliseconds = value as f64 / 1000.0;
+                    if count == 1 {
+                        lines.push(format!("{}:{}|ms{}", name, milliseconds, tags));
+                    } else {
+                        lines.push(format!("{}:{}|ms|@{}{}", name, milliseconds, 1.0 / count as f64, tags));
+                    }
+                }
+                last_latencies.insert(key, histogram.clone());
+            }
+        }
+    }
+    return lines;
+}
+
+#[test]
+fn test_format_metrics() {
+    let mut config = StatsdConfig {
+        address: "127.0.0.1:8125".parse().unwrap(),
+        interval: 1000,
+        prefix: "redflareproxy".to_owned(),
+        dogstatsd: false,
+    };
+    let labels = vec![("pool", "pool1".to_owned()), ("host", "127.0.0.1:6381".to_owned())];
+    let mut histogram = LatencyHistogram::default();
+    histogram.record(100);
+    histogram.record(100);
+    histogram.record(250);
+    let mut metrics = vec![
+        Metric { name: "requests".to_owned(), labels: Vec::new(), value: MetricValue::Counter(5) },
+        Metric { name: "backend_queue".to_owned(), labels: labels.clone(), value: MetricValue::Gauge(2) },
+        Metric { name: "backend_latency".to_owned(), labels: labels.clone(), value: MetricValue::Latency(histogram.clone()) },
+    ];
+    let mut last_counters = HashMap::new();
+    let mut last_latencies = HashMap::new();
+    assert_eq!(format_metrics(&metrics, &config, &mut last_counters, &mut last_latencies), vec![
+        "redflareproxy.requests:5|c",
+        "redflareproxy.backend_queue.pool1.127_0_0_1_6381:2|g",
+        "redflareproxy.backend_latency.pool1.127_0_0_1_6381:0.1|ms|@0.5",
+        "redflareproxy.backend_latency.pool1.127_0_0_1_6381:0.25|ms",
+    ]);
+
+    // Only what changed since the last push is sent for counters and latencies.
+    histogram.record(100);
+    metrics[0].value = MetricValue::Counter(7);
+    metrics[2].value = MetricValue::Latency(histogram);
+    assert_eq!(format_metrics(&metrics, &config, &mut last_counters, &mut last_latencies), vec![
+        "redflareproxy.requests:2|c",
+        "redflareproxy.backend_queue.pool1.127_0_0_1_6381:2|g",
+        "redflareproxy.backend_latency.pool1.127_0_0_1_6381:0.1|ms",
+    ]);
+
+    // Counters that went down were reset, and are sent whole. Unchanged counters are left out.
+    metrics[0].value = MetricValue::Counter(3);
+    assert_eq!(format_metrics(&metrics[0..1], &config, &mut last_counters, &mut last_latencies), vec!["redflareproxy.requests:3|c"]);
+    assert_eq!(format_metrics(&metrics[0..1], &config, &mut last_counters, &mut last_latencies).len(), 0);
+
+    config.dogstatsd = true;
+    assert_eq!(format_metrics(&metrics[1..2], &config, &mut HashMap::new(), &mut HashMap::new()), vec![
+        "redflareproxy.backend_queue:2|g|#pool:pool1,host:127.0.0.1:6381",
+    ]);
+}
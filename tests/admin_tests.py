@@ -1,5 +1,9 @@
 #!/usr/bin/env python
+import re
 import redis
+import shutil
+import socket
+import time
 from test_util import TestUtil
 
 class AdminTests(TestUtil):
@@ -9,4 +13,257 @@ class AdminTests(TestUtil):
 
         r = redis.Redis(port=1530, decode_responses=True)
         response = r.execute_command("INFO")
-        self.assertEqual(response.get('__raw__'), ["DERP"]);
\ No newline at end of file
+        self.assertTrue(response["config_file"].endswith("tests/conf/timeout1.toml"))
+        self.assertEqual(response["connected_clients"], 0)
+        self.assertEqual(response["pool0"]["name"], "pool1")
+        self.assertEqual(response["pool0"]["distribution"], "Modula")
+        self.assertEqual(response["backend0"]["host"], "127.0.0.1:6380")
+        self.assertTrue("used_memory_rss" in response)
+
+        # A section filters out the others.
+        response = r.execute_command("INFO", "clients")
+        self.assertEqual(response, {"connected_clients": 0, "accepted_clients": 0, "rejected_clients": 0})
+
+    def test_resp_replies(self):
+        self.start_proxy("tests/conf/timeout1.toml")
+
+        r = redis.Redis(port=1530, decode_responses=True)
+        self.assertTrue(r.ping())
+        self.assertEqual(r.execute_command("STAGEDCONFIG"), None)
+        self.assertEqual(r.execute_command("resetstats"), "OK")
+        try:
+            r.execute_command("NOSUCHCOMMAND")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Unknown command 'NOSUCHCOMMAND'")
+        try:
+            r.execute_command("LOADCONFIG", "tests/conf/missing.toml")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            pass
+
+        # Inline commands, as typed into telnet, work too, and requests split across writes wait for the rest.
+        s = socket.create_connection(("localhost", 1530))
+        s.sendall("PING\r\n*1\r\n$4\r\nPI")
+        time.sleep(0.1)
+        s.sendall("NG\r\n")
+        time.sleep(0.1)
+        self.assertEqual(s.recv(100), "+PONG\r\n+PONG\r\n")
+        s.close()
+    def test_backoff(self):
+        # No redis server is running, so every reconnect attempt fails and backs off further.
+        self.start_proxy("tests/conf/backoff1.toml")
+        time.sleep(1.5)
+
+        r = redis.Redis(port=1530, decode_responses=True)
+        response = r.execute_command("BACKOFF")
+        self.assertEqual(len(response), 1)
+        self.assertTrue(response[0].startswith("pool1 127.0.0.1:6380 status=DISCONNECTED"))
+        self.assertTrue("last_retry_timeout=400 next_retry_timeout=400" in response[0])
+
+        # Once the server is up, the backoff is reset.
+        self.start_redis_server(6380)
+        time.sleep(1)
+        response = r.execute_command("BACKOFF")
+        self.assertEqual(response, ["pool1 127.0.0.1:6380 status=READY attempts=0 last_retry_timeout=0 next_retry_timeout=100"])
+
+    def test_backend_commands(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        first = redis.Redis(port=6381)
+        second = redis.Redis(port=6382)
+        client = redis.Redis(port=1531, socket_timeout=1)
+        r = redis.Redis(port=1530, socket_timeout=1, decode_responses=True)
+
+        # A disabled backend is disconnected, and its keys go to the other backend.
+        self.assertEqual(r.execute_command("BACKEND", "DISABLE", "pool1", "second"), "OK")
+        self.assertEqual(r.execute_command("INFO", "backends")["backend1"]["status"], "DISCONNECTED")
+        for i in range(10):
+            self.assertTrue(client.set("key%d" % i, "value"))
+        self.assertEqual(first.dbsize(), 10)
+        self.assertEqual(second.dbsize(), 0)
+
+        # Once enabled again, it takes back its keys.
+        self.assertEqual(r.execute_command("BACKEND", "ENABLE", "pool1", "second"), "OK")
+        time.sleep(0.2)
+        self.assertEqual(r.execute_command("INFO", "backends")["backend1"]["status"], "READY")
+        for i in range(10):
+            self.assertTrue(client.set("other%d" % i, "value"))
+        self.assertTrue(second.dbsize() > 0)
+
+        # A draining backend stays connected, but takes no new requests.
+        TestUtil.flush_keys([6381, 6382])
+        self.assertEqual(r.execute_command("BACKEND", "DRAIN", "pool1", "0"), "OK")
+        for i in range(10):
+            self.assertTrue(client.set("key%d" % i, "value"))
+        self.assertEqual(first.dbsize(), 0)
+        self.assertEqual(r.execute_command("INFO", "backends")["backend0"]["mode"], "DRAINING")
+        self.assertEqual(r.execute_command("INFO", "backends")["backend0"]["status"], "READY")
+
+        self.assertEqual(r.execute_command("BACKEND", "RECONNECT", "pool1", "127.0.0.1:6382"), "OK")
+        try:
+            r.execute_command("BACKEND", "ENABLE", "pool1", "2")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Unknown backend in pool pool1!")
+
+    def test_pool_commands(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_redis_server(6383)
+        # CONFIGREWRITE writes to the config file, so the proxy runs from a copy.
+        config_path = "tests/log/test_pool_commands.toml"
+        shutil.copyfile("tests/conf/poolstats1.toml", config_path)
+        self.start_proxy(config_path)
+
+        third = redis.Redis(port=6383)
+        client = redis.Redis(port=1531, socket_timeout=1)
+        r = redis.Redis(port=1530, socket_timeout=1, decode_responses=True)
+
+        # An added server takes its share of the keys.
+        self.assertEqual(r.execute_command("POOL", "ADDSERVER", "pool1", "127.0.0.1:6383", "2"), "OK")
+        time.sleep(0.2)
+        self.assertEqual(r.execute_command("INFO", "backends")["backend2"]["status"], "READY")
+        for i in range(20):
+            self.assertTrue(client.set("key%d" % i, "value"))
+        self.assertTrue(third.dbsize() > 0)
+        try:
+            r.execute_command("POOL", "ADDSERVER", "pool1", "127.0.0.1:6383", "1")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Pool pool1 already has server 127.0.0.1:6383!")
+
+        # Without weight, it gets no keys.
+        TestUtil.flush_keys([6381, 6382, 6383])
+        self.assertEqual(r.execute_command("POOL", "SETWEIGHT", "pool1", "127.0.0.1:6383", "0"), "OK")
+        for i in range(20):
+            self.assertTrue(client.set("key%d" % i, "value"))
+        self.assertEqual(third.dbsize(), 0)
+
+        # Removing a server moves the servers after it, which keep working.
+        self.assertEqual(r.execute_command("POOL", "REMOVESERVER", "pool1", "0"), "OK")
+        self.assertEqual(r.execute_command("INFO", "backends")["backend0"]["host"], "127.0.0.1:6382")
+        self.assertTrue(client.set("key", "value"))
+        self.assertEqual(client.get("key"), "value")
+
+        # The changes can be written back to the config file.
+        self.assertEqual(r.execute_command("CONFIGREWRITE"), "OK")
+        self.assertEqual(r.execute_command("LOADCONFIG", config_path), "OK")
+        self.assertEqual(r.execute_command("STAGEDCONFIG"), r.execute_command("CONFIGINFO"))
+
+    def test_client_commands(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        other = redis.Redis(port=1531, socket_timeout=1)
+        r = redis.Redis(port=1530, socket_timeout=1, decode_responses=True)
+
+        # Clients can name their connection, which the admin port shows with the rest of the connection.
+        self.assertTrue(client.client_setname("app1"))
+        self.assertEqual(client.client_getname(), "app1")
+        self.assertTrue(client.set("key", "value"))
+        self.assertEqual(other.client_getname(), None)
+        clients = r.client_list()
+        self.assertEqual(len(clients), 2)
+        self.assertEqual(clients[0]["name"], "app1")
+        self.assertEqual(clients[0]["pool"], "pool1")
+        self.assertEqual(clients[0]["cmd"], "set")
+        self.assertEqual(clients[0]["multi-pending"], "0")
+        self.assertTrue(int(clients[0]["net-in"]) > 0)
+        self.assertTrue(int(clients[0]["net-out"]) > 0)
+
+        # Killed clients are disconnected.
+        self.assertEqual(r.execute_command("CLIENT", "KILL", "ADDR", clients[1]["addr"]), 1)
+        self.assertEqual(r.execute_command("CLIENT", "KILL", "POOL", "pool2"), 0)
+        self.assertEqual([c["name"] for c in r.client_list()], ["app1"])
+        time.sleep(1)
+        self.assertEqual(r.execute_command("CLIENT", "KILL", "IDLE", "1"), 1)
+        self.assertEqual(r.client_list(), [])
+
+    def test_monitor(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        s = socket.create_connection(("localhost", 1530))
+        s.settimeout(1)
+
+        # Each request shows up with its pool, client and backend.
+        s.sendall("MONITOR pool1 user:*\r\n")
+        self.assertEqual(s.recv(100), "+OK\r\n")
+        self.assertTrue(client.set("user:1", "a value"))
+        self.assertTrue(client.set("other", "value"))
+        self.assertEqual(client.get("user:1"), "a value")
+        time.sleep(0.1)
+        lines = s.recv(1000).splitlines()
+        self.assertEqual(len(lines), 2)
+        self.assertTrue(re.match('^\\+\\d+\\.\\d{6} \\[pool1 127\\.0\\.0\\.1:\\d+ 127\\.0\\.0\\.1:6382\\] "SET" "user:1" "a value"$', lines[0]))
+        self.assertTrue(re.match('^\\+\\d+\\.\\d{6} \\[pool1 127\\.0\\.0\\.1:\\d+ 127\\.0\\.0\\.1:6382\\] "GET" "user:1"$', lines[1]))
+
+        # Another command ends the monitoring.
+        s.sendall("PING\r\n")
+        self.assertEqual(s.recv(100), "+PONG\r\n")
+        self.assertEqual(client.get("user:1"), "a value")
+        time.sleep(0.1)
+        s.settimeout(0.2)
+        try:
+            s.recv(100)
+            self.fail("Expected no more monitor output")
+        except socket.timeout:
+            pass
+
+        s.settimeout(1)
+        s.sendall("MONITOR nopool\r\n")
+        self.assertEqual(s.recv(100), "-ERR Unknown pool!\r\n")
+        s.close()
+
+    def test_monitor_with_other_admin_clients(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        s = socket.create_connection(("localhost", 1530))
+        s.settimeout(1)
+        s.sendall("MONITOR pool1\r\n")
+        self.assertEqual(s.recv(100), "+OK\r\n")
+
+        # Another admin client neither ends the monitoring nor gets its output.
+        other = socket.create_connection(("localhost", 1530))
+        other.settimeout(1)
+        other.sendall("PING\r\n")
+        self.assertEqual(other.recv(100), "+PONG\r\n")
+        self.assertTrue(client.set("user:1", "a value"))
+        time.sleep(0.1)
+        lines = s.recv(1000).splitlines()
+        self.assertEqual(len(lines), 1)
+        self.assertTrue(lines[0].endswith('"SET" "user:1" "a value"'))
+        other.settimeout(0.2)
+        try:
+            other.recv(100)
+            self.fail("Expected no monitor output on the other admin client")
+        except socket.timeout:
+            pass
+
+        # Once the monitoring client is gone, a new admin client doesn't get its output either.
+        s.close()
+        time.sleep(0.1)
+        other.close()
+        s = socket.create_connection(("localhost", 1530))
+        s.settimeout(1)
+        s.sendall("PING\r\n")
+        self.assertEqual(s.recv(100), "+PONG\r\n")
+        self.assertEqual(client.get("user:1"), "a value")
+        time.sleep(0.1)
+        s.settimeout(0.2)
+        try:
+            s.recv(100)
+            self.fail("Expected no monitor output on a new admin client")
+        except socket.timeout:
+            pass
+        s.close()
@@ -0,0 +1,38 @@
+#!/usr/bin/env python
+import time
+import socket
+import redis
+from test_util import TestUtil
+
+class CircuitBreakerTests(TestUtil):
+
+    def test_circuit_breaker(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 1, 6382)
+        self.start_proxy("tests/conf/circuitbreaker1.toml")
+
+        TestUtil.verify_redis_connection(1531)
+
+        # Slow down the backend, so that every response is over the latency threshold.
+        conn_to_delayer = socket.socket(socket.AF_INET)
+        conn_to_delayer.connect(("0.0.0.0", 6382))
+        conn_to_delayer.sendall("SETDELAY 60")
+        time.sleep(0.1)
+        for _ in range(4):
+            TestUtil.verify_redis_connection(1531)
+
+        # The circuit is open, so requests fail fast.
+        TestUtil.verify_redis_error(1531, "ERROR: Circuit open")
+
+        # After the open timeout, a successful probe request closes the circuit.
+        conn_to_delayer.sendall("SETDELAY 1")
+        time.sleep(0.7)
+        TestUtil.verify_redis_connection(1531)
+        TestUtil.verify_redis_connection(1531)
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS")
+        self.assertTrue("circuit_breaker_opened: 1\n" in response)
+        self.assertTrue("circuit_breaker_half_opened: 1\n" in response)
+        self.assertTrue("circuit_breaker_closed: 1\n" in response)
+        self.assertTrue("circuit_breaker_rejected: 1" in response)
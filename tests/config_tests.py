@@ -30,6 +30,18 @@ class ConfigTests(TestUtil):
         proxy_proc = self.start_proxy("tests/conf/configclusterwithhost.toml")
         self.assertEquals(proxy_proc.poll(), 1)
 
+        # Verify that a backoff multiplier of 0 errors.
+        proxy_proc = self.start_proxy("tests/conf/configbackoffmultiplier.toml")
+        self.assertEquals(proxy_proc.poll(), 1)
+
+        # Verify that a max retry timeout lower than the retry timeout errors.
+        proxy_proc = self.start_proxy("tests/conf/configbackoffmaxtimeout.toml")
+        self.assertEquals(proxy_proc.poll(), 1)
+
+        # Verify that a hash function named in the wrong case errors, rather than picking another hash function.
+        proxy_proc = self.start_proxy("tests/conf/confighashfunctioncase.toml")
+        self.assertEquals(proxy_proc.poll(), 1)
+
 
     def test_switch_config(self):
         self.start_redis_server(6380)
@@ -0,0 +1,41 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class FallbackTests(TestUtil):
+
+    def test_fallback_pool(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/fallback1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(client.set("key1", "value"))
+        self.assert_redis_key(6381, "key1")
+        self.assertFalse(TestUtil.expect_redis_key(6382, "key1"))
+
+        # Once the only backend of pool1 is down, its requests go to pool2.
+        TestUtil.kill_redis_server(6381)
+        time.sleep(0.1)
+        try:
+            client.set("key2", "value")
+        except:
+            pass
+        self.assertTrue(client.set("key2", "value"))
+        self.assertEquals(client.get("key2"), "value")
+        self.assert_redis_key(6382, "key2")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        self.assertTrue("pool_fallbacks: 0" not in r.execute_command("STATS"))
+
+    def test_no_fallback_while_available(self):
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/fallback1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(client.set("key1", "value"))
+        self.assertEquals(client.get("key1"), "value")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        self.assertTrue("pool_fallbacks: 0" in r.execute_command("STATS"))
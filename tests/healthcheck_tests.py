@@ -0,0 +1,57 @@
+#!/usr/bin/env python
+import time
+import socket
+from test_util import TestUtil
+
+class HealthCheckTests(TestUtil):
+
+    def test_idle_backend_ejected(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 1, 6382)
+        self.start_proxy("tests/conf/healthcheck1.toml")
+
+        TestUtil.verify_redis_connection(1531)
+
+        # Set a long delay, without sending any requests. The health checks should mark the backend down on their own.
+        conn_to_delayer = socket.socket(socket.AF_INET)
+        conn_to_delayer.connect(("0.0.0.0", 6382))
+        conn_to_delayer.sendall("SETDELAY 400")
+        time.sleep(1)
+
+        TestUtil.verify_redis_error(1531, "ERROR: Not connected")
+
+        # Once the backend is responsive again, it should pass the health checks and be used again.
+        conn_to_delayer.sendall("SETDELAY 1")
+        time.sleep(2)
+
+        TestUtil.verify_redis_connection(1531)
+
+    def test_health_check_timeout_ignores_failure_limit(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 1, 6382)
+        self.start_proxy("tests/conf/healthcheck3.toml")
+
+        TestUtil.verify_redis_connection(1531)
+
+        TestUtil.populate_redis_key(6381, "key1", "value1")
+        TestUtil.populate_redis_key(6381, "key2", "value2")
+
+        # A single health check timing out is below the unhealthy threshold, so failure_limit must not eject the backend.
+        conn_to_delayer = socket.socket(socket.AF_INET)
+        conn_to_delayer.connect(("0.0.0.0", 6382))
+        conn_to_delayer.sendall("SETDELAY 150")
+        time.sleep(0.3)
+        conn_to_delayer.sendall("SETDELAY 1")
+        time.sleep(0.5)
+
+        # The late reply to the timed out check must not be taken as the reply to the next requests.
+        self.assert_redis_key(1531, "key1", "value1")
+        self.assert_redis_key(1531, "key2", "value2")
+
+    def test_unexpected_health_check_reply(self):
+        # The backend is a master, so the health check expecting a slave should mark it down.
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/healthcheck2.toml")
+        time.sleep(0.5)
+
+        TestUtil.verify_redis_error(1531, "ERROR: Not connected")
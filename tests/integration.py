@@ -15,6 +15,18 @@ from config_tests import ConfigTests
 from sharding_tests import ShardingTests
 from command_tests import CommandTests
 from stats_tests import StatsTests
+from healthcheck_tests import HealthCheckTests
+from circuitbreaker_tests import CircuitBreakerTests
+from sentinel_tests import SentinelTests
+from replication_tests import ReplicationTests
+from routing_tests import RoutingTests
+from mirror_tests import MirrorTests
+from migration_tests import MigrationTests
+from keyprefix_tests import KeyPrefixTests
+from replicas_tests import ReplicaTests
+from fallback_tests import FallbackTests
+from metrics_tests import MetricsTests
+from statsd_tests import StatsdTests
 
 class TestRedFlareProxy(TestUtil):
 
@@ -0,0 +1,32 @@
+#!/usr/bin/env python
+import redis
+from test_util import TestUtil
+
+class KeyPrefixTests(TestUtil):
+
+    def test_key_prefix(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/keyprefix1.toml")
+        backend = redis.Redis(port=6380, socket_timeout=1)
+
+        tenant1 = redis.Redis(port=1531, socket_timeout=1)
+        tenant2 = redis.Redis(port=1532, socket_timeout=1)
+        self.assertTrue(tenant1.set("key1", "value1"))
+        self.assertTrue(tenant2.set("key1", "value2"))
+        self.assertEquals(backend.get("tenant1:key1"), "value1")
+        self.assertEquals(backend.get("tenant2:key1"), "value2")
+        self.assertEquals(tenant1.get("key1"), "value1")
+        self.assertEquals(tenant2.get("key1"), "value2")
+
+        # Multi-key commands and scripts.
+        self.assertTrue(tenant1.mset({"key2": "a", "key3": "b"}))
+        self.assertEquals(tenant1.mget("key2", "key3"), ["a", "b"])
+        self.assertEquals(backend.get("tenant1:key3"), "b")
+        self.assertEquals(tenant1.eval("return redis.call('get', KEYS[1])", 1, "key2"), "a")
+
+        # Only the tenant's own keys are listed, without the prefix.
+        self.assertEquals(sorted(tenant1.keys("*")), ["key1", "key2", "key3"])
+        self.assertEquals(tenant2.keys("key*"), ["key1"])
+        cursor, keys = tenant1.scan(0, count=100)
+        self.assertEquals(cursor, 0)
+        self.assertEquals(sorted(keys), ["key1", "key2", "key3"])
@@ -0,0 +1,37 @@
+#!/usr/bin/env python
+import redis
+import urllib2
+from test_util import TestUtil
+
+class MetricsTests(TestUtil):
+
+    def test_prometheus_metrics(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/metrics1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        for i in range(10):
+            self.assertTrue(client.set("key%d" % i, "value"))
+        try:
+            client.hget("key1", "field")
+        except redis.exceptions.ResponseError:
+            pass
+
+        response = urllib2.urlopen("http://127.0.0.1:1540/metrics", timeout=1)
+        self.assertTrue(response.info().getheader("Content-Type").startswith("text/plain; version=0.0.4"))
+        metrics = response.read()
+        self.assertTrue("# TYPE redflareproxy_requests_total counter\nredflareproxy_requests_total 11\n" in metrics)
+        self.assertTrue('redflareproxy_pool_backends_available{pool="pool1"} 2\n' in metrics)
+        self.assertTrue('redflareproxy_backend_up{pool="pool1",backend="1",role="master",host="127.0.0.1:6382"} 1\n' in metrics)
+        self.assertTrue('redflareproxy_backend_errors_total{pool="pool1",' in metrics)
+        self.assertTrue('redflareproxy_command_latency_seconds_count{command="SET"} 10\n' in metrics)
+        requests = [int(line.split(" ")[1]) for line in metrics.split("\n") if line.startswith("redflareproxy_backend_requests_total{")]
+        self.assertEqual(sum(requests), 11)
+
+        # Only GET /metrics is served.
+        try:
+            urllib2.urlopen("http://127.0.0.1:1540/", timeout=1)
+            self.fail("Expected a 404")
+        except urllib2.HTTPError as error:
+            self.assertEqual(error.code, 404)
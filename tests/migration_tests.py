@@ -0,0 +1,56 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class MigrationTests(TestUtil):
+
+    def test_migration(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        old = redis.Redis(port=6380, socket_timeout=1)
+        new = redis.Redis(port=6381, socket_timeout=1)
+        self.start_proxy("tests/conf/migration1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        r = redis.Redis(port=1530, socket_timeout=1)
+        self.assertEquals(r.execute_command("MIGRATION new"), "off")
+        try:
+            r.execute_command("MIGRATION new CUTOVER")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Cannot CUTOVER a migration that is off!")
+
+        # Before the migration starts, everything goes to the old pool.
+        self.assertTrue(client.set("key1", "value1"))
+        self.assertEquals(old.get("key1"), "value1")
+        self.assertEquals(new.get("key1"), None)
+
+        # Writes go to both pools, and reads that miss on the new pool are back-filled from the old pool.
+        self.assertEquals(r.execute_command("MIGRATION new START"), "dual_write")
+        self.assertTrue(client.set("key2", "value2"))
+        time.sleep(0.1)
+        self.assertEquals(old.get("key2"), "value2")
+        self.assertEquals(new.get("key2"), "value2")
+        self.assertEquals(client.get("key1"), "value1")
+        self.assertEquals(client.get("missing"), None)
+        time.sleep(0.1)
+        self.assertEquals(new.get("key1"), "value1")
+        stats = r.execute_command("STATS")
+        self.assertTrue("migration_fallbacks: 2\n" in stats)
+        self.assertTrue("migration_backfills: 1" in stats)
+
+        # After the cut over, reads only go to the new pool.
+        self.assertEquals(r.execute_command("MIGRATION new CUTOVER"), "cut_over")
+        old.set("key3", "value3")
+        self.assertEquals(client.get("key3"), None)
+        self.assertTrue(client.set("key4", "value4"))
+        time.sleep(0.1)
+        self.assertEquals(old.get("key4"), "value4")
+
+        # Once finished, the old pool is left alone.
+        self.assertEquals(r.execute_command("MIGRATION new FINISH"), "finished")
+        self.assertTrue(client.set("key5", "value5"))
+        time.sleep(0.1)
+        self.assertEquals(new.get("key5"), "value5")
+        self.assertEquals(old.get("key5"), None)
@@ -0,0 +1,41 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class MirrorTests(TestUtil):
+
+    def test_mirror_writes(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/mirror1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(client.set("key1", "value"))
+        self.assertEquals(client.get("key1"), "value")
+        time.sleep(0.1)
+        self.assert_redis_key(6380, "key1")
+        self.assert_redis_key(6381, "key1")
+
+        # Only writes are mirrored.
+        r = redis.Redis(port=1530, socket_timeout=1)
+        self.assertTrue("mirrored_requests: 1\n" in r.execute_command("STATS"))
+
+        # The mirrored copy isn't counted as a request, nor timed as one.
+        self.assertTrue("\nrequests: 0\n" in r.execute_command("STATS", "shadow"))
+        lines = r.execute_command("LATENCY").split("\n")
+        self.assertTrue(any(line.startswith("pool shadow: count=0 ") for line in lines))
+        self.assertTrue(any(line.startswith("command SET: count=1 ") for line in lines))
+
+    def test_mirror_failure(self):
+        # The mirror pool being down doesn't affect the primary pool.
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/mirror1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(client.set("key1", "value"))
+        self.assertEquals(client.get("key1"), "value")
+        self.assert_redis_key(6380, "key1")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        self.assertTrue("mirror_failures: 1" in r.execute_command("STATS"))
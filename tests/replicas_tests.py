@@ -0,0 +1,51 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class ReplicaTests(TestUtil):
+
+    def test_replicated_writes(self):
+        ports = [6381, 6382, 6383]
+        for port in ports:
+            self.start_redis_server(port)
+        self.start_proxy("tests/conf/replicas1.toml")
+        proxy = redis.Redis(port=1531, socket_timeout=1)
+        backends = dict((port, redis.Redis(port=port, socket_timeout=1)) for port in ports)
+
+        # Every key is written to exactly two backends.
+        keys = ["key%d" % i for i in range(20)]
+        placement = {}
+        for key in keys:
+            self.assertTrue(proxy.set(key, "value"))
+            placement[key] = [port for port in ports if backends[port].get(key) == "value"]
+            self.assertEquals(len(placement[key]), 2)
+            self.assertEquals(proxy.get(key), "value")
+        self.assertTrue(proxy.mset({"mkey1": "a", "mkey2": "b"}))
+        for key in ["mkey1", "mkey2"]:
+            self.assertEquals(len([port for port in ports if backends[port].get(key) is not None]), 2)
+
+        # Once a backend is ejected, its keys are still read from their other copy.
+        TestUtil.kill_redis_server(6382)
+        time.sleep(0.1)
+        try:
+            proxy.get(keys[0])
+        except:
+            pass
+        for key in keys:
+            self.assertEquals(proxy.get(key), "value")
+
+    def test_replicated_mset_waits_for_all_copies(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/replicas2.toml")
+        proxy = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(proxy.mset({"mkey1": "a", "mkey2": "b"}))
+
+        # With replica_ack = "All", each split MSET write fails once one of its copies can't be written.
+        TestUtil.kill_redis_server(6382)
+        time.sleep(0.1)
+        response = proxy.execute_command("MSET", "mkey1", "c", "mkey2", "d")
+        self.assertEquals(len(response), 2)
+        for reply in response:
+            self.assertTrue(isinstance(reply, redis.ResponseError))
@@ -0,0 +1,45 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class ReplicationTests(TestUtil):
+
+    def test_read_write_splitting(self):
+        # The servers don't actually replicate, so that the values show which server handled a request.
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        TestUtil.populate_redis_key(6380, "key1", "master")
+        TestUtil.populate_redis_key(6381, "key1", "replica")
+        self.start_proxy("tests/conf/replication1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(client.get("key1"), "replica")
+
+        # Writes go to the master, and pin the client's reads to the master for the read_your_writes window.
+        self.assertTrue(client.set("key2", "value"))
+        self.assert_redis_key(6380, "key2")
+        self.assertEquals(client.get("key1"), "master")
+        time.sleep(0.6)
+        self.assertEquals(client.get("key1"), "replica")
+
+        # Other clients are not pinned.
+        self.assertTrue(client.set("key2", "value"))
+        other_client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(other_client.get("key1"), "replica")
+
+    def test_replica_fallback(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        TestUtil.populate_redis_key(6380, "key1", "master")
+        self.start_proxy("tests/conf/replication1.toml")
+
+        TestUtil.kill_redis_server(6381)
+        time.sleep(0.1)
+        client = redis.Redis(port=1531, socket_timeout=1)
+        try:
+            # The proxy may not notice that the replica is down until this read times out.
+            client.get("key1")
+        except redis.exceptions.ResponseError:
+            pass
+        self.assertEquals(client.get("key1"), "master")
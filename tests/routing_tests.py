@@ -0,0 +1,28 @@
+#!/usr/bin/env python
+import redis
+from test_util import TestUtil
+
+class RoutingTests(TestUtil):
+
+    def test_key_routing(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/routing1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(client.set("session:1", "a"))
+        self.assertTrue(client.set("cache:1", "b"))
+        self.assertTrue(client.set("user:42", "c"))
+        self.assertTrue(client.set("other", "d"))
+        self.assert_redis_key(6380, "session:1", "a")
+        self.assert_redis_key(6381, "cache:1", "b")
+        self.assert_redis_key(6380, "user:42", "c")
+        self.assert_redis_key(6382, "other", "d")
+        self.assertEquals(client.get("session:1"), "a")
+        self.assertEquals(client.get("other"), "d")
+
+        # The pool's own listener still sends every key to its backends.
+        pool_client = redis.Redis(port=1532, socket_timeout=1)
+        self.assertTrue(pool_client.set("session:2", "e"))
+        self.assert_redis_key(6382, "session:2", "e")
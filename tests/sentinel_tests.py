@@ -0,0 +1,30 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class SentinelTests(TestUtil):
+
+    def test_sentinel_failover(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        replica = redis.Redis(port=6381)
+        replica.execute_command("SLAVEOF 127.0.0.1 6380")
+        time.sleep(1)
+        self.start_redis_sentinel(26379, 6380)
+        self.start_proxy("tests/conf/sentinel1.toml")
+        time.sleep(0.5)
+
+        # The proxy should have resolved the master through the sentinel.
+        TestUtil.populate_redis_key(1531, "key1")
+        self.assert_redis_key(6380, "key1")
+
+        # After a failover, the proxy should follow the new master without reconnecting the client.
+        client = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(client.get("key1"), "value")
+        sentinel = redis.Redis(port=26379)
+        sentinel.execute_command("SENTINEL FAILOVER mymaster")
+        time.sleep(3)
+
+        self.assertTrue(client.set("key2", "value"))
+        self.assert_redis_key(6381, "key2")
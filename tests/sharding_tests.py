@@ -1,4 +1,5 @@
 #!/usr/bin/env python
+import time
 from test_util import TestUtil
 
 class ShardingTests(TestUtil):
@@ -11,4 +12,52 @@ class ShardingTests(TestUtil):
         # 3. Verify the same happens when another backend is ejected. Verify it's the same as twemproxy.
         # 4. Verify recover of 2nd
         # 5. Verify recovery of first.
-        # TODO: Set up a test while a constant stream of redis requests occurs. We want to make sure the switch is clean.
\ No newline at end of file
+        # TODO: Set up a test while a constant stream of redis requests occurs. We want to make sure the switch is clean.
+
+    def test_ketama_auto_eject(self):
+        ports = [6381, 6382, 6383]
+        for port in ports:
+            self.start_redis_server(port)
+        self.start_proxy("tests/conf/ketama1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        keys = ["key{}".format(i) for i in range(20)]
+        placement = {}
+        for key in keys:
+            TestUtil.populate_redis_key(1531, key)
+            placement[key] = [port for port in ports if TestUtil.expect_redis_key(port, key)][0]
+        TestUtil.flush_keys(ports)
+
+        # Once a backend is ejected, only its keys should move to other backends.
+        TestUtil.kill_redis_server(6382)
+        time.sleep(0.1)
+        moved_key = [key for key in keys if placement[key] == 6382][0]
+        try:
+            TestUtil.populate_redis_key(1531, moved_key)
+        except:
+            pass
+        for key in keys:
+            TestUtil.populate_redis_key(1531, key)
+            if placement[key] == 6382:
+                self.assertTrue(TestUtil.expect_redis_key(6381, key) or TestUtil.expect_redis_key(6383, key))
+            else:
+                self.assert_redis_key(placement[key], key)
+
+    def test_load_based_distributions(self):
+        ports = [6381, 6382, 6383]
+        for port in ports:
+            self.start_redis_server(port)
+        self.start_proxy("tests/conf/loadbalance1.toml")
+
+        # Idle backends are picked at random, so requests end up on both backends.
+        for proxy_port in [1531, 1532]:
+            TestUtil.flush_keys(ports)
+            keys = ["key%d" % i for i in range(40)]
+            for key in keys:
+                TestUtil.populate_redis_key(proxy_port, key)
+            for port in [6381, 6382]:
+                self.assertTrue(any(TestUtil.expect_redis_key(port, key) for key in keys))
+
+        # Random used to panic with a total weight of 1.
+        TestUtil.populate_redis_key(1533, "key1")
+        self.assert_redis_key(6383, "key1")
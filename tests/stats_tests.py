@@ -18,13 +18,23 @@ class StatsTests(TestUtil):
             response,
             """Stats:
 accepted_clients: 1
+rejected_clients: 0
 client_connections: 0
 requests: 1
 responses: 1
 send_client_bytes: 5
 recv_client_bytes: 27
 send_backend_bytes: 33
-recv_backend_bytes: 12"""
+recv_backend_bytes: 12
+circuit_breaker_opened: 0
+circuit_breaker_half_opened: 0
+circuit_breaker_closed: 0
+circuit_breaker_rejected: 0
+mirrored_requests: 0
+mirror_failures: 0
+migration_fallbacks: 0
+migration_backfills: 0
+pool_fallbacks: 0"""
         );
 
 
@@ -34,12 +44,92 @@ recv_backend_bytes: 12"""
             response,
             """Stats:
 accepted_clients: 2
+rejected_clients: 0
 client_connections: 0
 requests: 2
 responses: 2
 send_client_bytes: 10
 recv_client_bytes: 61
 send_backend_bytes: 67
-recv_backend_bytes: 17"""
+recv_backend_bytes: 17
+circuit_breaker_opened: 0
+circuit_breaker_half_opened: 0
+circuit_breaker_closed: 0
+circuit_breaker_rejected: 0
+mirrored_requests: 0
+mirror_failures: 0
+migration_fallbacks: 0
+migration_backfills: 0
+pool_fallbacks: 0"""
         );
 
+
+    def test_pool_stats(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        keys = ["key%d" % i for i in range(10)]
+        for key in keys:
+            self.assertTrue(client.set(key, "value"))
+        try:
+            client.hget("key1", "field")
+        except redis.exceptions.ResponseError:
+            pass
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS", "pool1")
+        self.assertTrue(response.startswith("Stats for pool pool1:\nbackends: 2\nbackends_available: 2\nqueue: 0\nrequests: 11\nresponses: 11\nerrors: 1\nerrors_WRONGTYPE: 1\n"))
+
+        # Backends are given by their index, host or name.
+        first = r.execute_command("STATS", "pool1", "0")
+        self.assertTrue(first.startswith("Stats for master backend 0 of pool pool1:\nhost: 127.0.0.1:6381\nstatus: READY\n"))
+        second = r.execute_command("STATS", "pool1", "127.0.0.1:6382")
+        self.assertEqual(second.split("\n")[1:], r.execute_command("STATS", "pool1", "second").split("\n")[1:])
+        requests = [int(line.split(": ")[1]) for line in (first + "\n" + second).split("\n") if line.startswith("requests: ")]
+        self.assertEqual(sum(requests), 11)
+        try:
+            r.execute_command("STATS", "pool1", "2")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Unknown backend in pool pool1!")
+        try:
+            r.execute_command("STATS", "pool2")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Unknown pool!")
+
+        r.execute_command("RESETSTATS")
+        self.assertTrue("\nrequests: 0\n" in r.execute_command("STATS", "pool1"))
+
+    def test_latency(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/poolstats1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        for i in range(10):
+            self.assertTrue(client.set("key%d" % i, "value"))
+            self.assertEqual(client.get("key%d" % i), "value")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        lines = r.execute_command("LATENCY").split("\n")
+        self.assertEqual(lines[0], "Latency in microseconds:")
+        self.assertTrue(lines[1].startswith("pool pool1: count=20 p50="))
+        self.assertTrue(lines[2].startswith("command GET: count=10 p50="))
+        self.assertTrue(lines[3].startswith("command SET: count=10 p50="))
+
+        # Given a pool, shows each of its backends.
+        lines = r.execute_command("LATENCY", "pool1").split("\n")
+        self.assertTrue(lines[1].startswith("pool pool1: count=20 "))
+        self.assertTrue(lines[2].startswith("backend 0 master 127.0.0.1:6381: count="))
+        self.assertTrue(lines[3].startswith("backend 1 master 127.0.0.1:6382: count="))
+        try:
+            r.execute_command("LATENCY", "pool2")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "Unknown pool!")
+
+        self.assertEqual(r.execute_command("LATENCY", "RESET"), "OK")
+        self.assertEqual(r.execute_command("LATENCY").split("\n")[1:], ["pool pool1: count=0 p50=0 p90=0 p99=0 p999=0 max=0"])
@@ -0,0 +1,36 @@
+#!/usr/bin/env python
+import redis
+import socket
+import time
+from test_util import TestUtil
+
+class StatsdTests(TestUtil):
+
+    def test_statsd_push(self):
+        listener = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        listener.bind(("127.0.0.1", 8125))
+        listener.settimeout(0.1)
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_proxy("tests/conf/statsd1.toml")
+
+        client = redis.Redis(port=1531, socket_timeout=1)
+        for i in range(10):
+            self.assertTrue(client.set("key%d" % i, "value"))
+
+        # Collect a few pushes, which come every 200ms.
+        lines = []
+        end = time.time() + 1
+        while time.time() < end:
+            try:
+                lines.extend(listener.recv(65536).split("\n"))
+            except socket.timeout:
+                pass
+        listener.close()
+
+        # Counters are sent as the increase since the last push, so they add up to the total.
+        requests = [int(line.split(":")[1].split("|")[0]) for line in lines if line.startswith("redflareproxy.requests:")]
+        self.assertEqual(sum(requests), 10)
+        self.assertTrue("redflareproxy.pool_backends_available:2|g|#pool:pool1" in lines)
+        self.assertTrue("redflareproxy.backend_up:1|g|#pool:pool1,backend:1,role:master,host:127.0.0.1:6382" in lines)
+        self.assertTrue(any(line.startswith("redflareproxy.command_latency:") and line.endswith("|#command:SET") for line in lines))
@@ -169,6 +169,30 @@ class TestUtil(unittest.TestCase):
                 time.sleep(0.1)
         raise AssertionError('Redis server did not start at port: {}'.format(port))
 
+    def start_redis_sentinel(self, port, master_port, master_name="mymaster"):
+        # Sentinel rewrites its config file, so it is generated in tests/tmp for every test.
+        config_path = "tests/tmp/sentinel{}.conf".format(port)
+        with open(config_path, 'w') as f:
+            f.write("port {}\n".format(port))
+            f.write("sentinel monitor {} 127.0.0.1 {} 1\n".format(master_name, master_port))
+            f.write("sentinel down-after-milliseconds {} 1000\n".format(master_name))
+            f.write("sentinel failover-timeout {} 2000\n".format(master_name))
+        FNULL = open(os.devnull, 'w')
+        process = subprocess.Popen(["redis-server", config_path, "--sentinel"], stdout=FNULL, stderr=subprocess.STDOUT)
+        self.subprocesses.append(process)
+
+        r = redis.Redis(port=port)
+        attempts_remaining = 4
+        while attempts_remaining:
+            try:
+                if not r.ping():
+                    raise AssertionError('Redis sentinel is unavailable at port: {}. Stopping test.'.format(port))
+                return process
+            except redis.ConnectionError:
+                attempts_remaining = attempts_remaining - 1
+                time.sleep(0.1)
+        raise AssertionError('Redis sentinel did not start at port: {}'.format(port))
+
     def start_redis_cluster_server(self, port):
         FNULL = open(os.devnull, 'w')
         process = subprocess.Popen(["redis-server", "tests/conf/redis-cluster{}.conf".format(port)], stdout=FNULL, stderr=subprocess.STDOUT)